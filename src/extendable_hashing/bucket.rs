@@ -1,35 +1,69 @@
-use crate::extendable_hashing::{K_MASK, K_STASH_BUCKET};
-use crate::hash::ValueT;
-use crate::utils::pair::{Key, Pair};
-use crate::utils::var_compare;
-use std::error::Error;
+use crate::extendable_hashing::K_MASK;
+use crate::utils::pair::Pair;
+use crate::utils::simd::{match_fingerprints, K_NUM_FINGERPRINTS};
 use std::fmt::Debug;
-use std::ops::BitAnd;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic;
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering::{Acquire, Release, SeqCst};
-use std::sync::{atomic, Arc};
 use thiserror::Error;
 
 pub const K_NUM_PAIR_PER_BUCKET: u32 = 14;
 const COUNT_MASK: u32 = (1 << 4) - 1;
 const OVERFLOW_BITMAP_MASK: u8 = (1 << 4) - 1;
 const OVERFLOW_SET: u8 = 1 << 4;
-const STASH_BUCKET: u8 = 2;
-const STASH_MASK: usize = (1 << STASH_BUCKET.ilog2()) - 1;
+// An overflow indicator keeps the index of its stash bucket in two bits, so a segment has at most 4 stash buckets
+const STASH_MASK: usize = (1 << 2) - 1;
 const ALLOC_MASK: usize = (1 << K_NUM_PAIR_PER_BUCKET) - 1;
 const LOCK_SET: u32 = 1 << 31;
 const LOCK_MASK: u32 = (1 << 31) - 1;
-#[derive(Debug, Clone)]
-pub struct Bucket<T: PartialEq> {
-    pub pairs: Vec<Option<Pair<T>>>,
-    pub unused: [u8; 2],
-    pub overflow_count: u8,
-    pub overflow_member: u8,
+/**
+The slots and the lock are stored inline, so a bucket holds no pointer and can be placed in a persistent memory pool.
+The layout is the one of Dash: the lock, the bitmap and the fingerprints fill the first 32 bytes of the cache line
+a probe starts with, followed by the key/value slots. With 8-byte keys and values a bucket is 256 bytes,
+four cache lines.
+*/
+#[derive(Debug)]
+#[repr(C, align(64))]
+pub struct Bucket<K: PartialEq + Clone, V: Clone> {
+    pub version_lock: AtomicU32,
+    pub bitmap: u32, // allocation bitmap + pointer bitmap + counter
+    pub finger_array: [u8; K_NUM_FINGERPRINTS], /*0-13 for the slots, 14-17 for overflowed, compared at once by `match_fingers`*/
+    pub overflow_bitmap: u8,
     pub overflow_index: u8,
-    pub overflow_bitmap: u8, // Overflow member is used to identify if any items stored in this stash bucket from the target bucket
-    pub finger_array: [u8; 18], /*only use the first 14 bytes, can be accelerated by SSE instruction,0-13 for finger, 14-17 for overflowed*/
-    pub bitmap: u32,            // allocation bitmap + pointer bitmap + counter
-    pub version_lock: Arc<AtomicU32>,
+    pub overflow_member: u8, // Overflow member is used to identify if any items stored in this stash bucket from the target bucket
+    pub overflow_count: u8,
+    pub unused: [u8; 2],
+    // A slot is initialized iff its bit is set in the allocation bitmap, see `pair`
+    pairs: [MaybeUninit<Pair<K, V>>; K_NUM_PAIR_PER_BUCKET as usize],
+}
+impl<K: Debug + Clone + PartialEq, V: Clone> Default for Bucket<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<K: PartialEq + Clone, V: Clone> Drop for Bucket<K, V> {
+    fn drop(&mut self) {
+        let mask = get_bitmap(self.bitmap);
+        for (i, pair) in self.pairs.iter_mut().enumerate() {
+            if check_bit_32(mask, i as u32) {
+                // SAFETY: An allocated slot is initialized, and the bucket is not used anymore
+                unsafe { ptr::drop_in_place(pair.as_mut_ptr()) };
+            }
+        }
+    }
+}
+/**
+The lock of a bucket taken by `Bucket::lock`, released when the guard is dropped. A panic in a closure
+or in a `Clone` of the keys or values while the lock is held unwinds through the guard, so it never leaves
+the bucket locked.
+*/
+pub(crate) struct BucketGuard<'a, K: Debug + Clone + PartialEq, V: Clone>(&'a Bucket<K, V>);
+impl<K: Debug + Clone + PartialEq, V: Clone> Drop for BucketGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.0.release_lock();
+    }
 }
 /**
 for Bitmap: 32 bits
@@ -39,23 +73,45 @@ Next 4 is for stash buckets
 Next 10 is for pointers
 The last 4 bits determines the number of slots filled in the bucket,
 In the above example 5 slots are filled
-
 */
-
-impl<T: Debug + Clone + PartialEq> Bucket<T> {
+impl<K: Debug + Clone + PartialEq, V: Clone> Bucket<K, V> {
     pub fn new() -> Self {
         Bucket {
-            pairs: vec![None; K_NUM_PAIR_PER_BUCKET as usize],
-            unused: [0, 0],
-            overflow_count: 0,
-            overflow_member: 0,
-            overflow_index: 0,
-            overflow_bitmap: 0,
-            finger_array: [0; 18],
+            version_lock: AtomicU32::new(0),
             bitmap: 0,
-            version_lock: Arc::new(AtomicU32::new(0)),
+            finger_array: [0; K_NUM_FINGERPRINTS],
+            overflow_bitmap: 0,
+            overflow_index: 0,
+            overflow_member: 0,
+            overflow_count: 0,
+            unused: [0, 0],
+            pairs: [const { MaybeUninit::uninit() }; K_NUM_PAIR_PER_BUCKET as usize],
         }
     }
+    /**
+    Returns the pair stored in `slot`, if the slot is allocated.
+    */
+    #[inline]
+    pub fn pair(&self, slot: u32) -> Option<&Pair<K, V>> {
+        // SAFETY: An allocated slot is initialized
+        check_bit_32(get_bitmap(self.bitmap), slot)
+            .then(|| unsafe { self.pairs[slot as usize].assume_init_ref() })
+    }
+    /**
+    Frees `slot`, returning the pair it stored.
+    */
+    pub(crate) fn take(&mut self, slot: u32) -> Pair<K, V> {
+        assert!(check_bit_32(get_bitmap(self.bitmap), slot));
+        self.unset_hash(slot);
+        // SAFETY: The slot was allocated, and is no longer once its bit is cleared
+        unsafe { self.pairs[slot as usize].assume_init_read() }
+    }
+    /**
+    Frees `slot` and drops the pair it stored.
+    */
+    pub(crate) fn remove(&mut self, slot: u32) {
+        drop(self.take(slot));
+    }
     /**
         It will wait till the executor is able to get the lock.
         Ensure there are no threads which are acquired but not released.
@@ -69,7 +125,6 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
                 old_value = self.version_lock.load(atomic::Ordering::Acquire);
                 if old_value & LOCK_SET == 0 {
                     old_value &= LOCK_MASK;
-                    let hello = old_value;
                     break;
                 }
             }
@@ -87,6 +142,20 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
             }
         }
     }
+    /**
+    Locks the bucket like `get_lock`, the guard releases the lock when dropped.
+    */
+    pub(crate) fn lock(&self) -> BucketGuard<'_, K, V> {
+        self.get_lock();
+        BucketGuard(self)
+    }
+    /**
+    Locks the bucket like `try_get_lock`, the guard releases the lock when dropped.
+    */
+    pub(crate) fn try_lock(&self) -> Option<BucketGuard<'_, K, V>> {
+        // Built lazily, a guard dropped without the lock would release the lock of another thread
+        self.try_get_lock().then(|| BucketGuard(self))
+    }
     pub fn release_lock(&self) {
         let version_lock = self.version_lock.load(atomic::Ordering::Acquire);
         self.version_lock
@@ -109,6 +178,35 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
             .compare_exchange(old_value, new_value, Acquire, Acquire)
             .is_ok()
     }
+    /**
+        Returns the current version for an optimistic read, or `None` while a writer holds the lock.
+        The version is bumped on every `release_lock`, see `version_changed`.
+    */
+    pub fn read_version(&self) -> Option<u32> {
+        let version = self.version_lock.load(Acquire);
+        if version & LOCK_SET != 0 {
+            return None;
+        }
+        Some(version)
+    }
+    /**
+        Returns true if the bucket was locked or modified since `version` was read with `read_version`.
+    */
+    pub fn version_changed(&self, version: u32) -> bool {
+        // Order the reads of the bucket content before the validation
+        atomic::fence(Acquire);
+        self.version_lock.load(atomic::Ordering::Relaxed) != version
+    }
+    /**
+        Copies the bucket with a volatile read for a reader holding no lock. The copy may be torn by a writer,
+        so it is only interpreted with `assume_init_ref` once `version_changed` validated the version read before
+        it. It shares the keys and values of the bucket and is never dropped, the writers retire the pairs they
+        unlink instead of dropping them, see `Table::search`.
+    */
+    pub(crate) fn snapshot(&self) -> MaybeUninit<Self> {
+        // SAFETY: The bucket is valid for reads, and a `MaybeUninit` holds any bytes the writers left
+        unsafe { ptr::read_volatile(self as *const Self as *const MaybeUninit<Self>) }
+    }
     pub fn find_empty_slot(&self) -> i32 {
         if get_count(self.bitmap) == K_NUM_PAIR_PER_BUCKET {
             return -1;
@@ -117,7 +215,7 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
         mask.trailing_zeros() as i32
     }
     pub fn is_lock(&self) -> bool {
-        self.version_lock.load(Acquire) != 0
+        self.version_lock.load(Acquire) & LOCK_SET != 0
     }
 
     /*true indicates overflow, needs extra check in the stash*/
@@ -130,7 +228,7 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
     }
 
     pub fn clear_stash_check(&mut self) {
-        self.overflow_bitmap = self.overflow_bitmap & !OVERFLOW_SET
+        self.overflow_bitmap &= !OVERFLOW_SET
     }
 
     /**
@@ -138,7 +236,7 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
     If there is an available slot then uses it or else it does the exact search in the probing (neighbor) bucket
     If there are no free slots in stash allotted slots i.e. 4 to add the reference in the target or probing bucket then increased the overflow count.
     */
-    pub fn set_indicator(&mut self, meta_hash: u8, neighbor: &mut Bucket<T>, pos: u8) {
+    pub fn set_indicator(&mut self, meta_hash: u8, neighbor: &mut Bucket<K, V>, pos: u8) {
         let mut mask: u8 = self.overflow_bitmap & OVERFLOW_BITMAP_MASK;
         mask = !mask;
         let mut index: u8 = mask.trailing_zeros() as u8;
@@ -146,7 +244,7 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
         if index < 4 {
             // Means a slot is free in the probing bucket
             self.finger_array[(K_NUM_PAIR_PER_BUCKET + index as u32) as usize] = meta_hash;
-            self.overflow_bitmap = (1 << index) | self.overflow_bitmap;
+            self.overflow_bitmap |= 1 << index;
             self.overflow_index =
                 self.overflow_index & (!(3 << (index * 2))) | (pos << (index * 2));
         } else {
@@ -156,36 +254,32 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
             index = mask.trailing_zeros() as u8;
             if index < 4 {
                 neighbor.finger_array[(K_NUM_PAIR_PER_BUCKET + index as u32) as usize] = meta_hash;
-                neighbor.overflow_bitmap = (1 << index) | neighbor.overflow_bitmap;
+                neighbor.overflow_bitmap |= 1 << index;
                 neighbor.overflow_index =
                     neighbor.overflow_index & (!(3 << (index * 2))) | (pos << (index * 2));
                 // Overflow member is only used to track that if there are some overflowed members in neighboring buckets
-                neighbor.overflow_member = (1 << index) | neighbor.overflow_member;
+                neighbor.overflow_member |= 1 << index;
             } else {
                 self.overflow_count += 1;
             }
         }
+        self.overflow_bitmap |= OVERFLOW_SET;
     }
 
-    pub fn unset_indicator(
-        &mut self,
-        meta_hash: u8,
-        neighbor: &mut Bucket<T>,
-        // key: Key<T>,
-        pos: u64,
-    ) {
+    pub fn unset_indicator(&mut self, meta_hash: u8, neighbor: &mut Bucket<K, V>, pos: u64) {
         // TODO: Verify it it is u64 or u8
         let mut clear_success = false;
         let mask1 = self.overflow_bitmap & OVERFLOW_BITMAP_MASK;
+        let matched = self.match_fingers(meta_hash) >> K_NUM_PAIR_PER_BUCKET;
         for i in 0..4 {
             // First looking for the match in the probing bucket
             if check_bit(mask1, i)
-                && (self.finger_array[(14 + i) as usize] == meta_hash)
+                && check_bit_32(matched, i)
                 && ((1 << i) & self.overflow_member == 0)
                 && (((self.overflow_index >> (2 * i)) as usize & STASH_MASK) == pos as usize)
             {
-                self.overflow_bitmap = self.overflow_bitmap & !(1 << i);
-                self.overflow_index = self.overflow_index & (!(3 << (i * 2)));
+                self.overflow_bitmap &= !(1 << i);
+                self.overflow_index &= !(3 << (i * 2));
                 assert_eq!((self.overflow_index >> (i * 2)) as usize & STASH_MASK, 0);
                 clear_success = true;
                 break;
@@ -195,16 +289,17 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
         let mask2 = neighbor.overflow_bitmap & OVERFLOW_BITMAP_MASK;
         if !clear_success {
             // If the match is not found then we look for neighboring bucket
+            let matched = neighbor.match_fingers(meta_hash) >> K_NUM_PAIR_PER_BUCKET;
             for i in 0..4 {
                 if check_bit(mask2, i)
-                    && (neighbor.finger_array[(14 + i) as usize] == meta_hash)
-                    && ((1 << i) & neighbor.overflow_member == 0)
+                    && check_bit_32(matched, i)
+                    && ((1 << i) & neighbor.overflow_member != 0)
                     && (((neighbor.overflow_index >> (2 * i)) as usize & STASH_MASK)
                         == pos as usize)
                 {
-                    neighbor.overflow_bitmap = neighbor.overflow_bitmap & !(1 << i);
-                    neighbor.overflow_index = neighbor.overflow_index & (!(3 << (i * 2)));
-                    neighbor.overflow_member = neighbor.overflow_member & !(1 << i);
+                    neighbor.overflow_bitmap &= !(1 << i);
+                    neighbor.overflow_index &= !(3 << (i * 2));
+                    neighbor.overflow_member &= !(1 << i);
                     assert_eq!(
                         (neighbor.overflow_index >> (i * 2)) as usize & STASH_MASK,
                         0
@@ -235,7 +330,7 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
         let mut new_bitmap = self.bitmap | (1 << (index + 18));
         if probe {
             // Meaning the value is being hosted but not owned by the bucket
-            new_bitmap = new_bitmap | (1 << (index + 4));
+            new_bitmap |= 1 << (index + 4);
         }
         new_bitmap += 1; // Increasing the count of occupied slots i.e. last 4 bits
         self.bitmap = new_bitmap;
@@ -249,8 +344,8 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
     }
     pub fn insert(
         &mut self,
-        key: Key<T>,
-        value: ValueT,
+        key: K,
+        value: V,
         meta_hash: u8,
         probe: bool,
     ) -> Result<i32, BucketError> {
@@ -260,25 +355,28 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
             // println!("Cannot find the empty slot, for key {:?}", key);
             return Err(BucketError::BucketFull);
         }
-        self.pairs[slot as usize] = Some(Pair::new(key, value));
+        self.pairs[slot as usize].write(Pair::new(key, value));
         self.set_hash(slot, meta_hash, probe);
         Ok(slot)
     }
-    pub fn check_and_get(
-        &self,
-        meta_hash: u8,
-        key: &Key<T>,
-        probe: bool,
-        value: &mut ValueT,
-    ) -> bool {
-        let mut mask: u32 = 0;
-        // TODO: We can replace this loop with SIMD instruction
-        for (i, &finger) in self.finger_array.iter().enumerate() {
-            if finger == meta_hash {
-                // Setting the corresponding bit which matched with the hash;
-                mask |= 1 << i;
-            }
-        }
+    /**
+    Returns the bitmask of the fingerprints equal to `meta_hash`, the slots in the 14 low bits
+    and the overflow indicators in the 4 bits above, see `match_fingerprints`.
+    */
+    #[inline]
+    pub fn match_fingers(&self, meta_hash: u8) -> u32 {
+        match_fingerprints(&self.finger_array, meta_hash)
+    }
+    pub fn check_and_get(&self, meta_hash: u8, key: &K, probe: bool) -> Option<&V> {
+        self.find(meta_hash, key, probe)
+            .and_then(|slot| self.pair(slot))
+            .map(|pair| &pair.value)
+    }
+    /**
+    Returns the slot of the key, among the slots the bucket owns or, with `probe`, hosts for its previous bucket.
+    */
+    pub(crate) fn find(&self, meta_hash: u8, key: &K, probe: bool) -> Option<u32> {
+        let mut mask = self.match_fingers(meta_hash);
         if probe {
             // Meaning We are looking the key in the probing (neighbor) bucket
             mask = mask & get_bitmap(self.bitmap) & get_member(self.bitmap);
@@ -288,100 +386,79 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
 
         if mask == 0 {
             // No match found
-            return false;
-        }
-        if key.is_pointer {
-            // Variable length key
-            for i in 0..14 {
-                if check_bit_32(mask, i as u32) {
-                    let ex_key = &self.pairs[i].clone().unwrap().key;
-                    if var_compare(
-                        &key.pointed_key,
-                        key.length,
-                        &ex_key.pointed_key,
-                        ex_key.length,
-                    ) {
-                        *value = self.pairs[i].clone().unwrap().value;
-                        return true;
-                    }
-                }
-            }
-        } else {
-            // Fixed length keys
-            for i in 0..14 {
-                let iu = i as usize;
-                if check_bit_32(mask, i) && self.pairs[iu].clone().unwrap().key.key == key.key {
-                    *value = self.pairs[iu].clone().unwrap().value;
-                    return true;
-                }
-            }
+            return None;
         }
-        false
+        (0..K_NUM_PAIR_PER_BUCKET)
+            .filter(|i| check_bit_32(mask, *i))
+            .find(|i| self.pair(*i).is_some_and(|pair| pair.key == *key))
+    }
+    /**
+    Replaces the value stored in `slot` and returns the old one.
+    */
+    pub(crate) fn replace_value(&mut self, slot: u32, value: V) -> V {
+        assert!(check_bit_32(get_bitmap(self.bitmap), slot));
+        // SAFETY: An allocated slot is initialized
+        let pair = unsafe { self.pairs[slot as usize].assume_init_mut() };
+        std::mem::replace(&mut pair.value, value)
+    }
+    /**
+    Writes the key of `slot` with `value` to a free slot, which stays unallocated until `commit_replace`.
+    Gives the value back when the bucket has no free slot.
+    */
+    pub(crate) fn stage_replace(&mut self, slot: u32, value: V) -> Result<u32, V> {
+        let staged = self.find_empty_slot();
+        if staged == -1 {
+            return Err(value);
+        }
+        let key = self.pair(slot).unwrap().key.clone();
+        self.pairs[staged as usize].write(Pair::new(key, value));
+        self.finger_array[staged as usize] = self.finger_array[slot as usize];
+        Ok(staged as u32)
+    }
+    /**
+    Moves the allocation of `slot` to the slot written by `stage_replace` with a single store to the bitmap,
+    so a crash leaves either the old or the new pair allocated, and returns the old pair.
+    */
+    pub(crate) fn commit_replace(&mut self, slot: u32, staged: u32) -> Pair<K, V> {
+        let mut new_bitmap = self.bitmap & !(1 << (slot + 18)) & !(1 << (slot + 4));
+        new_bitmap |= 1 << (staged + 18);
+        if check_bit_32(self.bitmap, slot + 4) {
+            // The pair is hosted for the previous bucket
+            new_bitmap |= 1 << (staged + 4);
+        }
+        // SAFETY: The slot is allocated until the bitmap is replaced, and read only once
+        let old = unsafe { self.pairs[slot as usize].assume_init_read() };
+        self.bitmap = new_bitmap;
+        old
     }
     pub(crate) fn insert_displace(
         &mut self,
-        key: Key<T>,
-        value: ValueT,
+        key: K,
+        value: V,
         meta_hash: u8,
         slot: i32,
         probe: bool,
     ) {
-        self.pairs[slot as usize] = Some(Pair::new(key, value));
+        self.pairs[slot as usize].write(Pair::new(key, value));
         self.set_hash(slot, meta_hash, probe);
     }
-    pub fn delete(&mut self, key: &Key<T>, meta_hash: u8, probe: bool) -> Result<(), BucketError> {
-        /*do the simd and check the key, then do the delete operation*/
-        let mut mask: u32 = 0;
-        // TODO: Can be replaced by a simd operation
-        for (i, &finger) in self.finger_array.iter().enumerate() {
-            if finger == meta_hash {
-                // Setting the corresponding bit which matched with the hash;
-                mask |= 1 << i;
-            }
-        }
-        if probe {
-            mask = mask & get_bitmap(self.bitmap) & get_member(self.bitmap);
-        } else {
-            mask = mask & get_bitmap(self.bitmap) & !get_member(self.bitmap);
-        }
-        if key.is_pointer {
-            if mask != 0 {
-                for i in 0..14 {
-                    let iu = i as usize;
-                    if check_bit_32(mask, i)
-                        && self.pairs[iu].clone().unwrap().key.pointed_key == key.pointed_key
-                    {
-                        self.unset_hash(i);
-                        self.pairs[iu] = None;
-                        return Ok(());
-                    }
-                }
-            }
-        } else {
-            if mask != 0 {
-                for i in 0..14 {
-                    let iu = i as usize;
-                    if check_bit_32(mask, i) && self.pairs[iu].clone().unwrap().key.key == key.key {
-                        self.unset_hash(i);
-                        self.pairs[iu] = None;
-                        return Ok(());
-                    }
-                }
-            }
+    pub fn delete(&mut self, key: &K, meta_hash: u8, probe: bool) -> Result<(), BucketError> {
+        if let Some(slot) = self.find(meta_hash, key, probe) {
+            self.remove(slot);
+            return Ok(());
         }
         Err(BucketError::KeyDoesNotExist)
     }
     pub fn unique_check(
         &self,
         meta_hash: u8,
-        key: &Key<T>,
-        neighbor: &Bucket<T>,
-        stash: &[Bucket<T>],
+        key: &K,
+        neighbor: &Bucket<K, V>,
+        stash: &[&Bucket<K, V>],
     ) -> bool {
-        let mut value: ValueT = vec![];
         // We are only looking for the neighboring buckets
-        if self.check_and_get(meta_hash, &key, false, &mut value)
-            || neighbor.check_and_get(meta_hash, &key, true, &mut value)
+        if self.check_and_get(meta_hash, key, false).is_some()
+            || neighbor.check_and_get(meta_hash, key, true).is_some()
         {
             return false;
         }
@@ -394,9 +471,10 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
                 // Check in the overflow buckets and decide if we have to check in the stash buckets
                 let mask = self.overflow_bitmap & OVERFLOW_BITMAP_MASK;
                 if mask != 0 {
+                    let matched = self.match_fingers(meta_hash) >> K_NUM_PAIR_PER_BUCKET;
                     for i in 0..4usize {
                         if check_bit(mask, i as u32)
-                            && self.finger_array[14 + i] == meta_hash
+                            && check_bit_32(matched, i as u32)
                             && ((1 << i) & self.overflow_member) == 0
                         {
                             test_stash = true;
@@ -407,10 +485,11 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
                 if !test_stash {
                     let mask = neighbor.overflow_bitmap & OVERFLOW_BITMAP_MASK;
                     if mask != 0 {
+                        let matched = neighbor.match_fingers(meta_hash) >> K_NUM_PAIR_PER_BUCKET;
                         for i in 0..4usize {
                             if check_bit(mask, i as u32)
-                                && neighbor.finger_array[14 + i] == meta_hash
-                                && ((1 << i) & neighbor.overflow_member) == 0
+                                && check_bit_32(matched, i as u32)
+                                && ((1 << i) & neighbor.overflow_member) != 0
                             {
                                 test_stash = true;
                                 break;
@@ -420,9 +499,8 @@ impl<T: Debug + Clone + PartialEq> Bucket<T> {
                 }
             }
             if test_stash {
-                for i in 0..K_STASH_BUCKET {
-                    let curr_bucket = &stash[i];
-                    if curr_bucket.check_and_get(meta_hash, &key, false, &mut value) {
+                for curr_bucket in stash {
+                    if curr_bucket.check_and_get(meta_hash, key, false).is_some() {
                         return false;
                     }
                 }
@@ -498,35 +576,28 @@ pub fn get_inverse_member(var: u32) -> u32 {
 pub fn meta_hash(var: usize) -> u8 {
     (var & K_MASK) as u8
 }
-pub fn stash_insert<T: Debug + Clone + PartialEq>(
-    stash_buckets: Vec<&mut Bucket<T>>,
-    target: &mut Bucket<T>,
-    neighbor: &mut Bucket<T>,
-    key: Key<T>,
-    value: ValueT,
+/**
+Inserts the pair in the first stash bucket with a free slot and records the overflow indicator in the target bucket.
+The caller is expected to hold the stash lock (the lock of the first stash bucket) or own the table exclusively.
+*/
+pub fn stash_insert<K: Debug + Clone + PartialEq, V: Clone>(
+    stash_buckets: Vec<&mut Bucket<K, V>>,
+    target: &mut Bucket<K, V>,
+    neighbor: &mut Bucket<K, V>,
+    key: &K,
+    value: &V,
     meta_hash: u8,
 ) -> bool {
-    let mut index = 0;
-    for stash_bucket in stash_buckets {
+    for (index, stash_bucket) in stash_buckets.into_iter().enumerate() {
         if get_count(stash_bucket.bitmap) < K_NUM_PAIR_PER_BUCKET {
-            stash_bucket.get_lock();
-            return match stash_bucket.insert(key, value, meta_hash, false) {
+            return match stash_bucket.insert(key.clone(), value.clone(), meta_hash, false) {
                 Ok(_) => {
-                    target.set_indicator(meta_hash, neighbor, index);
-                    stash_bucket.release_lock();
+                    target.set_indicator(meta_hash, neighbor, index as u8);
                     true
                 }
-                Err(e) => {
-                    stash_bucket.release_lock();
-                    println!(
-                        "Some error occurred while inserting element to stash buckets {:?}",
-                        e
-                    );
-                    false
-                }
+                Err(_) => false,
             };
         }
-        index += 1;
     }
     false
 }
@@ -534,19 +605,45 @@ pub fn stash_insert<T: Debug + Clone + PartialEq>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hash::ValueT;
     use crate::utils::hashing::calculate_hash;
+    use std::mem::{align_of, offset_of, size_of};
     use std::ops::AddAssign;
+    use std::sync::Arc;
     use std::thread;
     use std::time::{Duration, Instant};
 
+    #[test]
+    fn test_layout() {
+        // The Dash bucket: 32 bytes of metadata and 14 slots of 16 bytes
+        assert_eq!(size_of::<Bucket<u64, u64>>(), 256);
+        assert_eq!(align_of::<Bucket<u64, u64>>(), 64);
+        assert_eq!(offset_of!(Bucket<u64, u64>, pairs), 32);
+        // Larger slots still start every bucket on its own cache line
+        assert_eq!(size_of::<Bucket<u64, ValueT>>() % 64, 0);
+    }
+
+    #[test]
+    fn test_drop_frees_the_stored_values() {
+        let value = Arc::new(0);
+        let mut bucket: Bucket<u64, Arc<i32>> = Bucket::new();
+        for i in 0..3 {
+            bucket.insert(i, value.clone(), i as u8, false).unwrap();
+        }
+        bucket.delete(&1, 1, false).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop(bucket);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
     #[test]
     fn test_locking_with_multiple_thread() {
-        let bucket: Arc<Bucket<i32>> = Arc::new(Bucket::new());
+        let bucket: Arc<Bucket<i32, ValueT>> = Arc::new(Bucket::new());
         let mut handles = vec![];
         let num_of_threads = 1000;
         let total_dur = Instant::now();
-        for i in 0..num_of_threads {
-            let mut cloned = Arc::clone(&bucket);
+        for _i in 0..num_of_threads {
+            let cloned = Arc::clone(&bucket);
             let handle = thread::spawn(move || {
                 let start = Instant::now();
                 cloned.get_lock();
@@ -574,19 +671,17 @@ mod tests {
     }
     #[test]
     fn test_bucket_insertion_with_fixed_keys() {
-        let mut bucket: Bucket<i32> = Bucket::new();
-        let mut success = 0;
+        let mut bucket: Bucket<i32, ValueT> = Bucket::new();
         let mut ans = vec![];
         for i in 10000..10020 {
-            let mut string = String::from(format!("let hash = calculate_hash(&key) {}", i));
+            let string = format!("let hash = calculate_hash(&key) {}", i);
             let value: ValueT = string.clone().into_bytes();
 
             let hash = calculate_hash(&i);
-            let key = Key::new(&i);
+            let key = i;
             let response = bucket.insert(key, value, meta_hash(hash), true);
             match response {
                 Ok(_) => {
-                    success += 1;
                     ans.push(i);
                 }
                 Err(err) => {
@@ -596,13 +691,12 @@ mod tests {
         }
         ans.push(500);
         for key_str in &ans {
-            let mut vector = vec![];
-            let cloned_key = key_str.clone();
+            let cloned_key = *key_str;
             let hash = calculate_hash(key_str);
-            let key = Key::new(key_str);
+            let key = *key_str;
             let start = Instant::now();
             // Calculate the elapsed time
-            if bucket.check_and_get(hash as u8, &key, false, &mut vector) {
+            if let Some(vector) = bucket.check_and_get(hash as u8, &key, false) {
                 println!("found the key {:?}", vector);
             } else {
                 println!("Didn't found the key {}", cloned_key);
@@ -611,17 +705,16 @@ mod tests {
             println!("it took so time {:?}", duration);
         }
         let hash = calculate_hash(&ans[5]);
-        let key: Key<i32> = Key::new(&ans[5]);
+        let key = ans[5];
         let delete = bucket.delete(&key, hash as u8, false);
         match delete {
             Ok(_) => {
                 println!("found the key to delete {:?}", ans);
-                let mut vector = vec![];
-                let key = Key::new(&ans[5]);
+                let key = ans[5];
                 let start = Instant::now();
                 // Calculate the elapsed time
 
-                if bucket.check_and_get(meta_hash(hash), &key, false, &mut vector) {
+                if let Some(vector) = bucket.check_and_get(meta_hash(hash), &key, false) {
                     println!("found the key {:?}", vector);
                 } else {
                     println!("Didn't found the key {}", ans[5]);
@@ -633,7 +726,7 @@ mod tests {
                 println!("{:?}", err);
             }
         }
-        let key: Key<i32> = Key::new(&ans[5]);
+        let key = ans[5];
         let delete = bucket.delete(&key, meta_hash(hash), false);
         match delete {
             Ok(_) => {
@@ -646,90 +739,105 @@ mod tests {
     }
     #[test]
     fn test_bucket_insertion_with_variable_keys() {
-        let mut bucket: Bucket<String> = Bucket::new();
-        let mut success = 0;
+        let mut bucket: Bucket<String, ValueT> = Bucket::new();
         let mut ans: Vec<String> = vec![];
         for i in 10000..10015 {
-            let key = String::from(format!("let hash = calculate_hash(&key) {}", i));
-            let mut string = String::from(format!("let hash = calculate_hash(&key) {}", i));
+            let key = format!("let hash = calculate_hash(&key) {}", i);
+            let string = format!("let hash = calculate_hash(&key) {}", i);
             let value: ValueT = string.clone().into_bytes();
 
             let hash = calculate_hash(&key);
-            let key = Key::new(&key);
             let response = bucket.insert(key, value, meta_hash(hash), false);
-            match response {
-                Ok(slot) => {
-                    let key = String::from(format!("let hash = calculate_hash(&key) {}", i));
-                    println!("The key {} is inserted at {}", key, slot);
-                    success += 1;
-                    ans.push(key);
-                }
-                Err(_) => {}
+            if let Ok(slot) = response {
+                let key = format!("let hash = calculate_hash(&key) {}", i);
+                println!("The key {} is inserted at {}", key, slot);
+                ans.push(key);
             }
         }
-        ans.push(String::from(format!(
-            "let hash = calculate_hash(&key) {}",
-            500
-        )));
+        ans.push(format!("let hash = calculate_hash(&key) {}", 500));
         for (idx, key_str) in ans.iter().enumerate() {
-            let mut vector = vec![];
-            let cloned_key = key_str.clone();
+            let _cloned_key = key_str.clone();
             let hash = calculate_hash(&key_str);
-            let key = Key::new(key_str);
-            let start = Instant::now();
+            let key = key_str.clone();
+            let _start = Instant::now();
             // Calculate the elapsed time
             if idx == ans.len() - 1 {
                 // Asserting the negative case
-                assert_eq!(
-                    bucket.check_and_get(meta_hash(hash), &key, false, &mut vector),
-                    false
-                );
+                assert!(bucket.check_and_get(meta_hash(hash), &key, false).is_none());
             } else {
                 // Asserting positive case
-                assert_eq!(
-                    bucket.check_and_get(meta_hash(hash), &key, false, &mut vector),
-                    true
-                );
+                assert!(bucket.check_and_get(meta_hash(hash), &key, false).is_some());
             }
         }
     }
 
     #[test]
     fn test_bucket_deletion_with_fixed_keys() {
-        let mut bucket: Bucket<String> = Bucket::new();
-        let mut success = 0;
+        let mut bucket: Bucket<String, ValueT> = Bucket::new();
         let mut ans: Vec<String> = vec![];
         for i in 10000..10020 {
-            let key = String::from(format!("let hash = calculate_hash(&key) {}", i));
-            let mut string = String::from(format!("let hash = calculate_hash(&key) {}", i));
+            let key = format!("let hash = calculate_hash(&key) {}", i);
+            let string = format!("let hash = calculate_hash(&key) {}", i);
             let value: ValueT = string.clone().into_bytes();
 
             let hash = calculate_hash(&key);
-            let key = Key::new(&key);
             let response = bucket.insert(key, value, meta_hash(hash), false);
-            match response {
-                Ok(slot) => {
-                    let key = String::from(format!("let hash = calculate_hash(&key) {}", i));
-                    println!("The key {} is inserted at {}", key, slot);
-                    success += 1;
-                    ans.push(key);
-                }
-                Err(_) => {}
+            if let Ok(slot) = response {
+                let key = format!("let hash = calculate_hash(&key) {}", i);
+                println!("The key {} is inserted at {}", key, slot);
+                ans.push(key);
             }
         }
         for key in ans {
-            let mut vector = vec![];
             let hash = calculate_hash(&key);
-            let key = Key::new(&key);
-            assert_eq!(
-                bucket.check_and_get(meta_hash(hash), &key, false, &mut vector),
-                true
-            );
-            assert_eq!(bucket.delete(&key, meta_hash(hash), false).is_ok(), true);
+            assert!(bucket.check_and_get(meta_hash(hash), &key, false).is_some());
+            assert!(bucket.delete(&key, meta_hash(hash), false).is_ok());
+            assert!(bucket.check_and_get(meta_hash(hash), &key, false).is_none());
+        }
+    }
+
+    #[test]
+    fn test_bucket_with_non_byte_values() {
+        let mut bucket: Bucket<u64, u64> = Bucket::new();
+        for i in 0..10u64 {
+            let hash = calculate_hash(&i);
+            assert!(bucket.insert(i, i * 2, meta_hash(hash), false).is_ok());
+        }
+        for i in 0..10u64 {
+            let hash = calculate_hash(&i);
+            let key = i;
             assert_eq!(
-                bucket.check_and_get(meta_hash(hash), &key, false, &mut vector),
-                false
+                bucket.check_and_get(meta_hash(hash), &key, false),
+                Some(&(i * 2))
             );
         }
     }
+
+    #[test]
+    fn test_replace_moves_the_pair_to_a_free_slot() {
+        let mut bucket: Bucket<u64, u64> = Bucket::new();
+        let hash = calculate_hash(&7u64);
+        let slot = bucket.insert(7, 1, meta_hash(hash), true).unwrap() as u32;
+        assert!(bucket
+            .insert(8, 2, meta_hash(calculate_hash(&8u64)), false)
+            .is_ok());
+        let staged = bucket.stage_replace(slot, 3).unwrap();
+        // Only allocated by the commit
+        assert_eq!(bucket.find(meta_hash(hash), &7, true), Some(slot));
+        assert!(bucket.pair(staged).is_none());
+        bucket.commit_replace(slot, staged);
+        assert_eq!(get_count(bucket.bitmap), 2);
+        assert!(bucket.pair(slot).is_none());
+        // The pair stays hosted for the previous bucket
+        assert_eq!(bucket.find(meta_hash(hash), &7, true), Some(staged));
+        assert_eq!(bucket.check_and_get(meta_hash(hash), &7, true), Some(&3));
+        // A full bucket has no slot to stage in
+        for i in 100..112u64 {
+            assert!(bucket
+                .insert(i, i, meta_hash(calculate_hash(&i)), false)
+                .is_ok());
+        }
+        assert_eq!(bucket.stage_replace(staged, 4), Err(4));
+        assert_eq!(bucket.check_and_get(meta_hash(hash), &7, true), Some(&3));
+    }
 }
@@ -1,25 +1,123 @@
+use crate::extendable_hashing::storage::Storage;
 use crate::extendable_hashing::table::Table;
-use std::collections::HashMap;
+use crate::hash::MapError;
+use crate::pm::PmPool;
 use std::fmt::Debug;
+use std::mem::size_of;
 
-pub struct Directory<T: PartialEq + Debug + Clone> {
-    pub segments: Vec<Table<T>>,
+/**
+The directory maps the `global_depth` most significant bits of a key hash to a segment.
+A segment with `local_depth < global_depth` is referenced by `2^(global_depth - local_depth)`
+consecutive entries, so the entries are raw pointers owned by `ExtendableHashing`.
+*/
+pub struct Directory<
+    K: PartialEq + Debug + Clone,
+    V: Clone,
+    const BUCKETS: usize,
+    const STASH: usize,
+> {
+    pub segments: Vec<*mut Table<K, V, BUCKETS, STASH>>,
     pub global_depth: usize,
     pub version: usize,
-    pub depth_count: usize,
+    pub depth_count: usize, // Number of segments whose local depth is equal to the global depth
+}
+/**
+The copy of a directory kept in a persistent memory pool, followed by the pool offsets of its `2^global_depth` segments.
+The pool maps the file at a different address on every open, so the segments are referenced by offset.
+*/
+#[repr(C)]
+struct PmDirectory {
+    global_depth: u64,
+    version: u64,
+    depth_count: u64,
 }
 
-impl<T: PartialEq + Debug + Clone> Directory<T> {
-    pub fn new(capacity: usize, version: usize) -> Self {
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Directory<K, V, BUCKETS, STASH>
+{
+    /**
+    Creates a directory with one fresh segment per entry.
+    The capacity is rounded up to the next power of two, as every entry has to be addressable by the hash prefix.
+    */
+    pub fn new(capacity: usize, version: usize, storage: &Storage) -> Result<Self, MapError> {
+        let capacity = capacity.next_power_of_two();
+        let global_depth = capacity.ilog2() as usize;
         let mut segments = Vec::with_capacity(capacity);
         for i in 0..capacity {
-            segments.push(Table::new(i));
+            segments.push(storage.new_table(i, global_depth)?);
         }
-        Directory {
+        Ok(Directory {
             segments,
-            global_depth: capacity.ilog2() as usize,
+            global_depth,
             version,
             depth_count: capacity,
+        })
+    }
+    /**
+    Returns the local depth and the pattern of the segment at `index`, as given by the entries referencing it.
+    Used by the recovery, where the depth stored in a segment may belong to an unpublished split or merge.
+    */
+    pub fn segment_depth(&self, index: usize) -> (usize, usize) {
+        let segment = self.segments[index];
+        let mut local_depth = self.global_depth;
+        // The entries of a segment form an aligned chunk, grow the chunk while it only references the segment
+        while local_depth > 0 {
+            let chunk_size = 1 << (self.global_depth - local_depth + 1);
+            let chunk_start = index & !(chunk_size - 1);
+            if self.segments[chunk_start..chunk_start + chunk_size]
+                .iter()
+                .any(|entry| *entry != segment)
+            {
+                break;
+            }
+            local_depth -= 1;
+        }
+        (local_depth, index >> (self.global_depth - local_depth))
+    }
+    /**
+    Writes the persistent copy of the directory at `offset` and persists it.
+    # Safety
+    `offset` must be allocated from the pool with at least `persisted_size` bytes, and every segment must live in the pool.
+    */
+    pub unsafe fn write_to(&self, pool: &PmPool, offset: u64) {
+        let header = pool.at::<PmDirectory>(offset);
+        header.write(PmDirectory {
+            global_depth: self.global_depth as u64,
+            version: self.version as u64,
+            depth_count: self.depth_count as u64,
+        });
+        let entries = header.add(1) as *mut u64;
+        for (i, segment) in self.segments.iter().enumerate() {
+            entries.add(i).write(pool.offset_of(*segment));
         }
+        pool.persist(header as *const u8, persisted_size(self.segments.len()));
     }
+    /**
+    Rebuilds the directory from its persistent copy at `offset`, translating the segment offsets to addresses.
+    # Safety
+    `offset` must point to a directory written by `write_to` in a pool with the same layout.
+    */
+    pub unsafe fn read_from(pool: &PmPool, offset: u64) -> Self {
+        let header = &*pool.at::<PmDirectory>(offset);
+        let entries = (header as *const PmDirectory).add(1) as *const u64;
+        let segments = (0..1usize << header.global_depth)
+            .map(|i| pool.at::<Table<K, V, BUCKETS, STASH>>(entries.add(i).read()))
+            .collect();
+        Directory {
+            segments,
+            global_depth: header.global_depth as usize,
+            version: header.version as usize,
+            depth_count: header.depth_count as usize,
+        }
+    }
+}
+/**
+Returns the number of bytes taken by the persistent copy of a directory with `len` entries.
+*/
+pub fn persisted_size(len: usize) -> usize {
+    size_of::<PmDirectory>() + len * size_of::<u64>()
 }
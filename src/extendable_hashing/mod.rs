@@ -1,124 +1,2408 @@
 pub mod bucket;
 mod directory;
-mod table;
+mod storage;
+pub mod table;
 
+use crate::extendable_hashing::bucket::meta_hash;
 use crate::extendable_hashing::directory::Directory;
-use crate::extendable_hashing::table::{Table, TableError};
-use crate::hash::{Hash, ValueT};
-use crate::utils::hashing::calculate_hash;
-use crate::utils::pair::Key;
-use std::sync::atomic::AtomicI32;
+use crate::extendable_hashing::storage::{pool_layout, Storage};
+use crate::extendable_hashing::table::{segment_pattern, Table, TableError};
+use crate::hash::{ConcurrentMap, MapConfig, MapError, ValueT};
+use crate::pm::{LayoutTag, PmError, PmPool, Pod, SlabUsage};
+use crate::utils::epoch::{Collector, Guard};
+use crate::utils::hashing::DashBuildHasher;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::hint::spin_loop;
+use std::path::Path;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{AtomicI32, AtomicPtr, AtomicUsize};
 
+// The default geometry of a segment, see the `BUCKETS` and `STASH` parameters of `ExtendableHashing`
 pub const K_NUM_BUCKET: usize = 64;
 pub const K_STASH_BUCKET: usize = 2;
 pub const K_FINGER_BITS: usize = 8;
 pub const K_MASK: usize = (1 << K_FINGER_BITS) - 1;
-// We use log2 to determine the number of bits to wrap the bucket index under K_NUM_BUCKET range
-// Ex: for 64 the bucket mask would be 63, We had to use log instead of -1 to cover the possibility of K_NUM_BUCKET of not being power of 2;
-pub const BUCKET_MASK: usize = (1 << K_NUM_BUCKET.ilog2()) - 1;
-pub const STASH_MASK: usize = (1 << K_STASH_BUCKET.ilog2()) - 1;
 pub const TAIL_MASK: u64 = (1 << 56) - 1;
 pub const HEADER_MASK: u64 = ((1 << 8) - 1) << 56;
-const DEFAULT_CAPACITY: usize = 10;
-pub struct ExtendableHashing<T> {
-    clean: bool,
-    crash_version: u64,
-    lock_and_counter: AtomicI32, // the MSB is the lock bit; remaining bits are used as the counter
-    dir: Directory<T>,           // Yet to be implemented
-}
-impl<T> Hash<T> for ExtendableHashing<T> {
-    fn new() -> Self {
+pub(crate) const DEFAULT_CAPACITY: usize = 10;
+const DIRECTORY_LOCK_SET: i32 = i32::MIN;
+const DIRECTORY_COUNTER_MASK: i32 = i32::MAX;
+/**
+The Dash extendible hash table, generic over the key, the stored value and the `BuildHasher` of the keys.
+The value type defaults to the byte vector `ValueT`, the hasher to the stable `DashBuildHasher`.
+A map kept in a pool must hash the same way in every process, so seeded hashers like `RandomState`
+only fit the maps in DRAM.
+
+Variable-length keys like strings or UUID blobs use `Key`, which keeps the short keys inline in the slots
+and the longer ones out of line. They are not `Pod`, so they only fit the maps in DRAM as well.
+
+`BUCKETS` and `STASH` set the number of normal and stash buckets of a segment, a power of two between 4 and 65536
+and between 1 and 4, checked at compile time. Small segments split faster, which bounds the latency of the insert
+triggering a split, while large ones waste less space on the directory and fill up more before splitting.
+The initial number of segments is `MapConfig::capacity`.
+
+The map is `Send` and `Sync` when the keys and values are, so it can be shared through an `Arc`
+and every operation but `clear` takes `&self`. Inserts and deletes lock the buckets they touch.
+Lookups and iterations are lock-free: they copy the buckets and validate their versions before cloning.
+The pairs removed or replaced by the writers are retired to the epoch collector, so a value is never freed
+while a lookup clones it.
+
+Splits and merges take the directory lock and publish a new directory instead of changing the current one,
+so operations never block on the directory. The directories and segments they replace are retired
+to the epoch collector, and only freed once every operation that could have loaded them is done.
+The segments are allocated from a slab, on the heap or in the pool, which reuses the blocks of the freed segments
+for the next splits, see `memory_usage`.
+
+A map created with `create` keeps its segments and a copy of its directory in a persistent memory pool,
+emulated by a memory-mapped file, and can be reopened with `open` after a restart.
+When the previous process did not call `shut_down`, `open` bumps the crash version of the pool
+and every segment is recovered the first time an operation touches it, see `Table::recover`.
+*/
+pub struct ExtendableHashing<
+    K: PartialEq + Debug + Clone + std::hash::Hash,
+    V: Clone = ValueT,
+    S = DashBuildHasher,
+    const BUCKETS: usize = K_NUM_BUCKET,
+    const STASH: usize = K_STASH_BUCKET,
+> {
+    #[allow(dead_code)]
+    clean: bool, // Set by `shut_down`, the flag read by `open` is the one persisted in the pool
+    crash_version: u64, // Segments created or recovered in an older crash version need a recovery
+    lock_and_counter: AtomicI32, // the MSB is the lock bit; remaining bits count the directory lock releases
+    dir: AtomicPtr<Directory<K, V, BUCKETS, STASH>>, // Replaced under the directory lock, see `publish`
+    collector: Collector, // Reclaims the unpublished directories and segments
+    storage: Storage,
+    config: MapConfig,
+    len: AtomicUsize,
+    hash_builder: S, // Routes the keys, picks their bucket and fingerprint, and rehashes them in splits and merges
+}
+/**
+Keeps the calling thread pinned in the map's epoch collector for as long as it is alive.
+Retired directories and segments are not freed while a guard pinned before their retirement exists,
+so the references handed out by `dir` stay valid for the guard's lifetime.
+*/
+struct DirectoryGuard<
+    'a,
+    K: PartialEq + Debug + Clone + std::hash::Hash,
+    V: Clone,
+    S,
+    const BUCKETS: usize,
+    const STASH: usize,
+> {
+    map: &'a ExtendableHashing<K, V, S, BUCKETS, STASH>,
+    guard: Guard<'a>,
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > DirectoryGuard<'_, K, V, S, BUCKETS, STASH>
+{
+    /**
+    Loads the currently published directory. Calling it again after a retry observes the directories
+    published in the meantime, their `version` tells whether the directory was doubled or halved.
+    */
+    fn dir(&self) -> &Directory<K, V, BUCKETS, STASH> {
+        // SAFETY: The published directory is only freed once it is retired and every guard pinned before is dropped
+        unsafe { &*self.map.dir.load(Acquire) }
+    }
+}
+
+// The updates chained by `Entry::and_modify`
+type Modify<'a, V> = Box<dyn FnOnce(&mut V) + 'a>;
+/**
+A key of a map with the update to apply if it is present, see `ExtendableHashing::entry`.
+A concurrent map cannot lend its slots, so nothing is looked up until one of the `or_insert` methods runs
+the whole operation under the locks of the buckets owning the key, and returns a copy of the resulting value.
+*/
+#[must_use = "an entry does nothing until one of its `or_insert` methods is called"]
+pub struct Entry<
+    'a,
+    K: PartialEq + Debug + Clone + std::hash::Hash,
+    V: Clone,
+    S,
+    const BUCKETS: usize,
+    const STASH: usize,
+> {
+    map: &'a ExtendableHashing<K, V, S, BUCKETS, STASH>,
+    key: K,
+    modify: Option<Modify<'a, V>>,
+}
+impl<
+        'a,
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S: BuildHasher,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Entry<'a, K, V, S, BUCKETS, STASH>
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+    /**
+    Applies `f` to the value if the key is present, after the updates of the previous calls.
+    */
+    pub fn and_modify(mut self, f: impl FnOnce(&mut V) + 'a) -> Self {
+        self.modify = Some(match self.modify.take() {
+            Some(previous) => Box::new(move |value: &mut V| {
+                previous(value);
+                f(value)
+            }),
+            None => Box::new(f),
+        });
+        self
+    }
+    /**
+    Inserts the key with `default` if it is absent, and returns a copy of its value.
+    */
+    pub fn or_insert(self, default: V) -> Result<V, MapError> {
+        self.or_insert_with(|| default)
+    }
+    /**
+    Inserts the key with the value returned by `default` if it is absent, and returns a copy of its value.
+    `default` runs without any lock, when another thread inserts the key first its value is dropped.
+    */
+    pub fn or_insert_with(self, default: impl FnOnce() -> V) -> Result<V, MapError> {
+        let Entry {
+            map,
+            key,
+            mut modify,
+        } = self;
+        let mut apply = |value: &mut V| {
+            if let Some(modify) = modify.take() {
+                modify(value);
+            }
+            value.clone()
+        };
+        match map.update(&key, &mut apply) {
+            Err(MapError::KeyNotFound) => {}
+            result => return result,
+        }
+        let value = default();
+        map.write(&key, |target, key_hash, meta_hash, guard| {
+            match target.upsert(
+                &key,
+                &value,
+                key_hash,
+                meta_hash,
+                &map.storage,
+                guard,
+                &mut apply,
+            )? {
+                Some(current) => Ok((current, false)),
+                None => Ok((value.clone(), true)),
+            }
+        })
+    }
+}
+/**
+An iterator over copies of the entries of a map, see `ExtendableHashing::iter`.
+*/
+pub struct Iter<
+    'a,
+    K: PartialEq + Debug + Clone + std::hash::Hash,
+    V: Clone,
+    S,
+    const BUCKETS: usize,
+    const STASH: usize,
+> {
+    map: &'a ExtendableHashing<K, V, S, BUCKETS, STASH>,
+    position: Option<usize>, // The first hash of the segments not read yet, `None` once the last one was read
+    entries: std::vec::IntoIter<(K, V)>,
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S: BuildHasher,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Iterator for Iter<'_, K, V, S, BUCKETS, STASH>
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(entry);
+            }
+            let (entries, next) = self.map.read_segment(self.position?);
+            self.entries = entries.into_iter();
+            self.position = next;
+        }
+    }
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S: BuildHasher + Default,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > ExtendableHashing<K, V, S, BUCKETS, STASH>
+{
+    pub fn new() -> Self {
+        Self::with_config(MapConfig::default()).unwrap()
+    }
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S: BuildHasher + Default,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Default for ExtendableHashing<K, V, S, BUCKETS, STASH>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S: BuildHasher + Default,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > ConcurrentMap<K, V> for ExtendableHashing<K, V, S, BUCKETS, STASH>
+{
+    fn with_config(config: MapConfig) -> Result<Self, MapError> {
+        Self::with_hasher(config, S::default())
+    }
+
+    /**
+    Inserts the key in the segment addressed by the most significant bits of its hash.
+    When the segment is full it is split, the directory is updated (or doubled when the segment
+    was already at the global depth) and the insert is retried against the new layout.
+    */
+    fn insert(&self, key: K, value: V) -> Result<(), MapError> {
+        self.write(&key, |target, key_hash, meta_hash, _| {
+            target
+                .insert(&key, &value, key_hash, meta_hash, &self.storage)
+                .map(|_| ((), true))
+        })
+    }
+
+    /**
+    Removes the key from the segment addressed by the most significant bits of its hash.
+    */
+    fn remove(&self, key: &K) -> Result<(), MapError> {
+        self.remove_with(key, |target, key_hash, meta_hash, guard| {
+            target
+                .delete(key, key_hash, meta_hash, &self.storage, guard)
+                .map(|_| true)
+        })
+        .map(|_| ())
+    }
+
+    /**
+    Looks the key up in the segment addressed by the most significant bits of its hash.
+    The lookup is retried when the segment lost the hash range to a split, or the directory was
+    doubled or halved while we were reading the buckets.
+    */
+    fn get(&self, key: &K) -> Option<V> {
+        let key_hash = self.hash(key);
+        let meta_hash = meta_hash(key_hash);
+        let guard = self.enter();
+        loop {
+            let dir = guard.dir();
+            let dir_index = segment_pattern(key_hash, dir.global_depth);
+            let target_ptr = dir.segments[dir_index];
+            // SAFETY: Segments are only freed once retired and every guard pinned before is dropped
+            let target = unsafe { &*target_ptr };
+            self.recover(dir, dir_index, target);
+            if !target.owns(key_hash) {
+                continue;
+            }
+            let value = target.search(key, key_hash, meta_hash, &guard.guard);
+            // The key may have been moved out by a split while we were reading the buckets
+            if !target.owns(key_hash) || guard.dir().version != dir.version {
+                continue;
+            }
+            return value;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Relaxed)
+    }
+
+    fn clear(&mut self) -> Result<(), MapError> {
+        self.free_segments();
+        // Dropping the collector frees the retired segments, giving their blocks back before the slab is reset
+        self.collector = Collector::new();
+        // Only a pool can fail to be reset or to allocate the directory. Its segments stay mapped, so the map keeps
+        // the old directory until the new one is published
+        self.storage.reset()?;
+        // SAFETY: `&mut self` excludes any operation
+        let version = unsafe { (**self.dir.get_mut()).version };
+        let new_dir = Directory::new(self.config.capacity, version + 1, &self.storage)
+            .and_then(|dir| self.storage.publish_directory(&dir).map(|_| dir))?;
+        let old_dir = std::mem::replace(self.dir.get_mut(), Box::into_raw(Box::new(new_dir)));
+        // SAFETY: The directory was allocated with `Box::into_raw` and is no longer published
+        drop(unsafe { Box::from_raw(old_dir) });
+        self.len.store(0, Relaxed);
+        Ok(())
+    }
+}
+impl<K, V, S, const BUCKETS: usize, const STASH: usize> ExtendableHashing<K, V, S, BUCKETS, STASH>
+where
+    K: PartialEq + Debug + Clone + std::hash::Hash + Pod,
+    V: Clone + Pod,
+    S: BuildHasher + Default + LayoutTag,
+{
+    /**
+    Creates a map in a new persistent memory pool of `pool_size` bytes at `path`, replacing any existing file.
+    Every segment and directory is allocated from the pool, so its size bounds the number of entries.
+    */
+    pub fn create(
+        path: impl AsRef<Path>,
+        pool_size: usize,
+        config: MapConfig,
+    ) -> Result<Self, MapError> {
+        config.validate()?;
+        let pool = PmPool::create(path, pool_size, pool_layout::<K, V, S, BUCKETS, STASH>())?;
+        Self::create_in(pool, config)
+    }
+    /**
+    Creates a map in `pool`, which has to be created with `pool_layout::<K, V, S, BUCKETS, STASH>()`.
+    */
+    fn create_in(pool: PmPool, config: MapConfig) -> Result<Self, MapError> {
+        pool.set_clean(false);
+        let storage = Storage::create(pool)?;
+        let dir = Directory::new(config.capacity, 0, &storage)?;
+        storage.publish_directory(&dir)?;
+        Ok(Self::with_storage(storage, dir, config, 0, S::default()))
+    }
+    /**
+    Reopens a map written by `create`, with the entries persisted before the previous process stopped.
+    The pool has to be created with the same key and value types. `config` is used when the map is cleared.
+    Opening reads the directory and the headers of the slab chunks, not the segments:
+    after a crash the segments are recovered lazily.
+    */
+    pub fn open(path: impl AsRef<Path>, config: MapConfig) -> Result<Self, MapError> {
+        config.validate()?;
+        let pool = PmPool::open(path, pool_layout::<K, V, S, BUCKETS, STASH>())?;
+        // Opening the slab completes the directory write a crash interrupted
+        let storage = Storage::open(pool)?;
+        let pool = storage.pool().unwrap();
+        let root = pool.root();
+        if root == 0 {
+            return Err(PmError::InvalidPool("the pool has no directory".to_string()).into());
+        }
+        // SAFETY: The root is only set to directories written by `write_directory`, in a pool of the same layout.
+        // The slots only hold `K` and `V`, which are `Pod`, so the persisted pairs are valid in this process
+        let dir = unsafe { Directory::<K, V, BUCKETS, STASH>::read_from(pool, root) };
+        if !pool.clean() {
+            // Every segment created or recovered before the crash now needs a recovery
+            pool.set_crash_version(pool.crash_version() + 1);
+        }
+        pool.set_clean(false);
+        // The entries a recovery drops are subtracted once it ran
+        let mut len = 0;
+        let mut i = 0;
+        while i < dir.segments.len() {
+            // SAFETY: Every entry points to a table in the pool
+            let table = unsafe { &*dir.segments[i] };
+            len += table.count_entries();
+            i += 1 << (dir.global_depth - dir.segment_depth(i).0);
+        }
+        Ok(Self::with_storage(storage, dir, config, len, S::default()))
+    }
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S: BuildHasher,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > ExtendableHashing<K, V, S, BUCKETS, STASH>
+{
+    /**
+    Creates an empty map routing the keys with `hash_builder`, which has to hash equal keys to the same value
+    for the whole life of the map.
+    */
+    pub fn with_hasher(config: MapConfig, hash_builder: S) -> Result<Self, MapError> {
+        config.validate()?;
+        let storage = Storage::dram();
+        let dir = Directory::new(config.capacity, 0, &storage)?;
+        Ok(Self::with_storage(storage, dir, config, 0, hash_builder))
+    }
+    fn with_storage(
+        storage: Storage,
+        dir: Directory<K, V, BUCKETS, STASH>,
+        config: MapConfig,
+        len: usize,
+        hash_builder: S,
+    ) -> Self {
+        let crash_version = storage.pool().map_or(0, PmPool::crash_version);
         Self {
-            clean: true,
-            crash_version: 0,
+            clean: false,
+            crash_version,
             lock_and_counter: Default::default(),
-            dir: Directory::new(DEFAULT_CAPACITY, 0),
-        }
-    }
-
-    fn insert(&mut self, key: T, value: ValueT) {
-        let key_hash = calculate_hash(&key);
-        let meta_hash = (key_hash & K_MASK) as u8;
-        'RETRY: loop {
-            let dir = &self.dir;
-            let dir_index = key_hash >> (8 * size_of::<usize>() - dir.global_depth);
-            let target_table: &mut Table<T> = &mut dir.segments[dir_index as u64 & TAIL_MASK];
-            let key = Key::new(&key);
-            // TODO: Complete the recovery part
-            let response = target_table.insert(key, value.clone(), key_hash, meta_hash);
-            match response {
-                Ok(_success_response) => {}
+            dir: AtomicPtr::new(Box::into_raw(Box::new(dir))),
+            collector: Collector::new(),
+            storage,
+            config,
+            len: AtomicUsize::new(len),
+            hash_builder,
+        }
+    }
+    fn hash(&self, key: &K) -> usize {
+        self.hash_builder.hash_one(key) as usize
+    }
+    /**
+    Pins the calling thread for the duration of an operation, see `DirectoryGuard`.
+    */
+    fn enter(&self) -> DirectoryGuard<'_, K, V, S, BUCKETS, STASH> {
+        DirectoryGuard {
+            map: self,
+            guard: self.collector.pin(),
+        }
+    }
+    /**
+    Runs `op` on the segment owning the key, with the hash and the fingerprint of the key, and the guard
+    the pairs it unlinks are retired with.
+    `op` returns its result and whether it inserted the key. Like `insert`, it is retried when the segment
+    lost the hash range of the key, and the segment is split when it is full.
+    */
+    fn write<R>(
+        &self,
+        key: &K,
+        mut op: impl FnMut(
+            &Table<K, V, BUCKETS, STASH>,
+            usize,
+            u8,
+            &Guard<'_>,
+        ) -> Result<(R, bool), TableError>,
+    ) -> Result<R, MapError> {
+        let key_hash = self.hash(key);
+        let meta_hash = meta_hash(key_hash);
+        let guard = self.enter();
+        loop {
+            let dir = guard.dir();
+            let dir_index = segment_pattern(key_hash, dir.global_depth);
+            let target_ptr = dir.segments[dir_index];
+            // SAFETY: Segments are only freed once retired and every guard pinned before is dropped
+            let target = unsafe { &*target_ptr };
+            self.recover(dir, dir_index, target);
+            match op(target, key_hash, meta_hash, &guard.guard) {
+                Ok((result, inserted)) => {
+                    if inserted {
+                        self.len.fetch_add(1, Relaxed);
+                    }
+                    return Ok(result);
+                }
+                // The segment was split (and the directory possibly doubled) since we loaded it, retry with the new one
+                Err(TableError::UnableToAcquireLock(_)) | Err(TableError::KeyMoved) => continue,
+                Err(TableError::TableFull) => self.split(&guard, key_hash, target_ptr)?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    /**
+    Runs `op` on the segment owning the key, like `write`, for the operations removing it.
+    `op` returns whether it removed the key, and a segment left at the low water mark is merged with its buddy.
+    */
+    fn remove_with(
+        &self,
+        key: &K,
+        mut op: impl FnMut(
+            &Table<K, V, BUCKETS, STASH>,
+            usize,
+            u8,
+            &Guard<'_>,
+        ) -> Result<bool, TableError>,
+    ) -> Result<bool, MapError> {
+        let key_hash = self.hash(key);
+        let meta_hash = meta_hash(key_hash);
+        let guard = self.enter();
+        loop {
+            let dir = guard.dir();
+            let dir_index = segment_pattern(key_hash, dir.global_depth);
+            let target_ptr = dir.segments[dir_index];
+            // SAFETY: Segments are only freed once retired and every guard pinned before is dropped
+            let target = unsafe { &*target_ptr };
+            self.recover(dir, dir_index, target);
+            match op(target, key_hash, meta_hash, &guard.guard) {
+                Ok(removed) => {
+                    if removed {
+                        self.len.fetch_sub(1, Relaxed);
+                        if target.len() <= Table::<K, V, BUCKETS, STASH>::MERGE_LOW_WATER_MARK {
+                            self.try_merge(&guard, key_hash);
+                        }
+                    }
+                    return Ok(removed);
+                }
+                Err(TableError::UnableToAcquireLock(_)) | Err(TableError::KeyMoved) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    /**
+    Inserts the key, or replaces its value, and returns the previous value.
+    */
+    pub fn upsert(&self, key: K, value: V) -> Result<Option<V>, MapError> {
+        self.write(&key, |target, key_hash, meta_hash, guard| {
+            let previous = target.upsert(
+                &key,
+                &value,
+                key_hash,
+                meta_hash,
+                &self.storage,
+                guard,
+                |old| std::mem::replace(old, value.clone()),
+            )?;
+            let inserted = previous.is_none();
+            Ok((previous, inserted))
+        })
+    }
+    /**
+    Applies `f` to the value of the key and returns its result, fails with `MapError::KeyNotFound` when the key
+    is absent. `f` runs under the locks of the buckets owning the key, so concurrent updates of a key never
+    lose one another, and must not access the map.
+    */
+    pub fn update<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Result<R, MapError> {
+        let mut f = Some(f);
+        self.write(key, |target, key_hash, meta_hash, guard| {
+            // `f` is only taken once the key is found under the locks, never by an attempt that is retried
+            let apply = |value: &mut V| (f.take().unwrap())(value);
+            target
+                .update(key, key_hash, meta_hash, &self.storage, guard, apply)
+                .map(|result| (result, false))
+        })
+    }
+    /**
+    Replaces the value of the key with `new` if it is equal to `expected`, and returns whether it was replaced.
+    The value is compared and replaced under the locks of the buckets owning the key, so exactly one of
+    concurrent swaps from the same expected value succeeds. Fails with `MapError::KeyNotFound` when the key is absent.
+    */
+    pub fn compare_and_swap(&self, key: &K, expected: &V, new: V) -> Result<bool, MapError>
+    where
+        V: PartialEq,
+    {
+        self.write(key, |target, key_hash, meta_hash, guard| {
+            target
+                .compare_and_swap(
+                    key,
+                    expected,
+                    &new,
+                    key_hash,
+                    meta_hash,
+                    &self.storage,
+                    guard,
+                )
+                .map(|swapped| (swapped, false))
+        })
+    }
+    /**
+    Removes the key if its value is equal to `expected`, and returns whether it was removed.
+    Like `compare_and_swap` the value is checked under the bucket locks, and like `remove` the segment may be
+    merged afterwards. Fails with `MapError::KeyNotFound` when the key is absent.
+    */
+    pub fn remove_if(&self, key: &K, expected: &V) -> Result<bool, MapError>
+    where
+        V: PartialEq,
+    {
+        self.remove_with(key, |target, key_hash, meta_hash, guard| {
+            target.delete_if(key, key_hash, meta_hash, &self.storage, guard, |value| {
+                value == expected
+            })
+        })
+    }
+    /**
+    Returns the entry of the key, to update it in place or insert it when absent.
+    */
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, S, BUCKETS, STASH> {
+        Entry {
+            map: self,
+            key,
+            modify: None,
+        }
+    }
+    /**
+    Returns an iterator over copies of the entries, one segment at a time in the order of their hash prefixes.
+    The iteration is weakly consistent: a key present from the start to the end of the iteration is returned
+    exactly once, even if segments are split or merged or the directory is doubled or halved meanwhile.
+    Keys inserted or removed during the iteration may or may not be returned.
+    Only the copy of a segment is held between two calls, no lock and no epoch guard.
+    */
+    pub fn iter(&self) -> Iter<'_, K, V, S, BUCKETS, STASH> {
+        Iter {
+            map: self,
+            position: Some(0),
+            entries: Vec::new().into_iter(),
+        }
+    }
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+    /**
+    Resumes a scan at `cursor`, `0` to start one, and returns the next cursor with the entries of the segments
+    read, at least `count` of them unless the scan is complete. The returned cursor is `0` once every segment
+    was read, like the SCAN of Redis.
+    A key present from the first to the last call of the scan is returned exactly once, whatever splits,
+    merges, doublings or halvings happen between and during the calls. The cursor is the first hash of
+    the next segment to read, so it does not depend on the directory. It stays valid for as long as the keys
+    hash the same way, across a reopen for the stable hashers of persistent maps.
+    */
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(K, V)>) {
+        let mut entries = Vec::new();
+        let mut position = Some(cursor as usize);
+        while let Some(start) = position {
+            let (segment, next) = self.read_segment(start);
+            entries.extend(segment);
+            position = next;
+            if entries.len() >= count {
+                break;
+            }
+        }
+        (position.map_or(0, |next| next as u64), entries)
+    }
+    /**
+    Copies the entries of the segment owning the hash `position` whose hash is at least `position`,
+    and returns them with the first hash of the next segment, `None` after the last one.
+    The copy is retried until no split or merge published a directory during it, so it holds every key
+    of the segment's hash range present for the whole copy. The positions only move forward and the hash
+    ranges of the segments read from successive directories never overlap past `position`, so a key
+    is never returned twice.
+    */
+    fn read_segment(&self, position: usize) -> (Vec<(K, V)>, Option<usize>) {
+        let guard = self.enter();
+        loop {
+            let dir = guard.dir();
+            let dir_index = segment_pattern(position, dir.global_depth);
+            // SAFETY: Segments are only freed once retired and every guard pinned before is dropped
+            let table = unsafe { &*dir.segments[dir_index] };
+            self.recover(dir, dir_index, table);
+            let mut entries = table.entries(&guard.guard);
+            // A split or a merge moved entries in or out of the segment meanwhile
+            if !std::ptr::eq(guard.dir(), dir) {
+                continue;
+            }
+            let (local_depth, pattern) = dir.segment_depth(dir_index);
+            let range_bits = usize::BITS as usize - local_depth;
+            let start = pattern.checked_shl(range_bits as u32).unwrap_or(0);
+            // A merge may have given the segment a part of the hash range that was already read
+            if position > start {
+                entries.retain(|(key, _)| self.hash(key) >= position);
+            }
+            let next = if range_bits == usize::BITS as usize {
+                None
+            } else {
+                start.checked_add(1 << range_bits)
+            };
+            return (entries, next);
+        }
+    }
+    /**
+    Recovers the segment at `dir_index` if it was not touched since the last crash.
+    The directory gives the segment its local depth and pattern, see `Table::recover`.
+    */
+    fn recover(
+        &self,
+        dir: &Directory<K, V, BUCKETS, STASH>,
+        dir_index: usize,
+        table: &Table<K, V, BUCKETS, STASH>,
+    ) {
+        if table.recovered(self.crash_version) {
+            return;
+        }
+        let (local_depth, pattern) = dir.segment_depth(dir_index);
+        let dropped = table.recover(
+            self.crash_version,
+            local_depth,
+            pattern,
+            &self.hash_builder,
+            &self.storage,
+        );
+        self.len.fetch_sub(dropped, Relaxed);
+    }
+    /**
+    Sets the lock bit of `lock_and_counter`, waiting for the current holder to release it.
+    Operations are not blocked by the lock, it only serializes the directory updates.
+    */
+    fn lock_directory(&self) {
+        let mut current = self.lock_and_counter.load(Relaxed);
+        loop {
+            if current & DIRECTORY_LOCK_SET != 0 {
+                spin_loop();
+                current = self.lock_and_counter.load(Relaxed);
+                continue;
+            }
+            match self.lock_and_counter.compare_exchange_weak(
+                current,
+                current | DIRECTORY_LOCK_SET,
+                Acquire,
+                Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+    /**
+    Clears the lock bit and counts the release, so the counter tells how often the directory lock was taken.
+    */
+    fn unlock_directory(&self) {
+        let _ = self
+            .lock_and_counter
+            .fetch_update(Release, Relaxed, |current| {
+                Some((current + 1) & DIRECTORY_COUNTER_MASK)
+            });
+    }
+    /**
+    Publishes the new directory and retires the current one, the caller must hold the directory lock.
+    */
+    fn publish(
+        &self,
+        guard: &DirectoryGuard<'_, K, V, S, BUCKETS, STASH>,
+        dir: Directory<K, V, BUCKETS, STASH>,
+    ) {
+        let old_dir = self.dir.swap(Box::into_raw(Box::new(dir)), SeqCst);
+        // SAFETY: The directory was allocated with `Box::into_raw` and is now unreachable for new operations
+        unsafe { guard.guard.retire(old_dir) };
+    }
+    /**
+    Splits the segment `target_ptr` that was found full for the key hash.
+    The directory is updated, or doubled when the segment was already at the global depth.
+    Nothing is done if the segment was split or merged by another thread in the meantime.
+    */
+    fn split(
+        &self,
+        guard: &DirectoryGuard<'_, K, V, S, BUCKETS, STASH>,
+        key_hash: usize,
+        target_ptr: *mut Table<K, V, BUCKETS, STASH>,
+    ) -> Result<(), MapError> {
+        self.lock_directory();
+        let dir = guard.dir();
+        let dir_index = segment_pattern(key_hash, dir.global_depth);
+        // Verifying if the target table is not changed in between
+        if dir.segments[dir_index] != target_ptr {
+            self.unlock_directory();
+            return Ok(());
+        }
+        // SAFETY: The segment is referenced by the published directory and the guard keeps it alive
+        let target = unsafe { &*target_ptr };
+        // The local depth only changes under the directory lock
+        let old_local_depth = target.local_depth();
+        let new_dir_len = if old_local_depth < dir.global_depth {
+            dir.segments.len()
+        } else {
+            2 * dir.segments.len()
+        };
+        // Allocating before the split, so running out of space leaves the segment untouched
+        let allocated = self.storage.new_table(0, 0).and_then(|new_table| {
+            match self.storage.reserve_directory(new_dir_len) {
+                Ok(dir_block) => Ok((new_table, dir_block)),
                 Err(err) => {
-                    match err {
-                        TableError::TableFull => {
-                            // Splitting the table
-
-                            let dir_index = key_hash >> (8 * size_of::<usize>() - dir.global_depth);
-                            let new_table: Table<T> = dir.segments[dir_index as u64 & TAIL_MASK];
-                            // Verifying if the target table is not changed in between
-                            if calculate_hash(target_table) != calculate_hash(&new_table) {
-                                target_table.release_locks();
-                                continue 'RETRY;
+                    // SAFETY: The table was just allocated and never published
+                    unsafe { self.storage.free_table(new_table) };
+                    Err(err)
+                }
+            }
+        });
+        let (new_table, dir_block) = match allocated {
+            Ok(allocated) => allocated,
+            Err(err) => {
+                self.unlock_directory();
+                return Err(err);
+            }
+        };
+        // Splitting the table
+        target.acquire_locks();
+        // SAFETY: The new table is not reachable from the directory yet
+        if let Err(err) = target.split(
+            unsafe { &mut *new_table },
+            &self.hash_builder,
+            &self.storage,
+        ) {
+            unsafe { self.storage.free_table(new_table) };
+            self.storage.free_directory(dir_block);
+            target.release_locks();
+            self.unlock_directory();
+            return Err(MapError::Internal(format!(
+                "Unable to split the segment {:?}",
+                err
+            )));
+        }
+        let new_dir = if old_local_depth < dir.global_depth {
+            Self::directory_update(dir, dir_index, new_table)
+        } else {
+            Self::directory_doubling(dir, dir_index, new_table)
+        };
+        // Persisting the directory commits the split, a crash before leaves the target with all its entries
+        self.storage
+            .write_directory(dir_block, &new_dir, &[new_table], &[]);
+        self.publish(guard, new_dir);
+        // SAFETY: The new table was just allocated and is now owned by the directory
+        let new_table = unsafe { &*new_table };
+        target.complete_split(new_table, &self.hash_builder, &self.storage, &guard.guard);
+        new_table.release_first_lock();
+        target.release_locks();
+        self.unlock_directory();
+        Ok(())
+    }
+    /**
+    Merges the segment owning the key hash with its buddy, the segment with the same local depth whose pattern
+    only differs in the last bit, if both of them together are under `MERGE_LOW_WATER_MARK`.
+    The directory entries of both segments are re-pointed to the merged segment and the directory is halved
+    for as long as no segment needs the current global depth.
+    */
+    fn try_merge(&self, guard: &DirectoryGuard<'_, K, V, S, BUCKETS, STASH>, key_hash: usize) {
+        // Most deletes from an underfull segment find a buddy that is deeper or too full, they leave the directory
+        // lock to the ones that can merge
+        if self.merge_buddies(guard.dir(), key_hash).is_none() {
+            return;
+        }
+        self.lock_directory();
+        let dir = guard.dir();
+        let global_depth = dir.global_depth;
+        let Some([target_ptr, buddy_ptr]) = self.merge_buddies(dir, key_hash) else {
+            self.unlock_directory();
+            return;
+        };
+        // SAFETY: Both segments are referenced by the published directory and the guard keeps them alive
+        let (target, buddy) = unsafe { (&*target_ptr, &*buddy_ptr) };
+        let local_depth = target.local_depth();
+        let buddy_pattern = buddy.pattern();
+        // The merged directory is never larger than the current one
+        let Ok((merged_table, dir_block)) = self.storage.new_table(0, 0).and_then(|merged_table| {
+            match self.storage.reserve_directory(dir.segments.len()) {
+                Ok(dir_block) => Ok((merged_table, dir_block)),
+                Err(err) => {
+                    // SAFETY: The table was just allocated and never published
+                    unsafe { self.storage.free_table(merged_table) };
+                    Err(err)
+                }
+            }
+        }) else {
+            // The pool is full, the segments stay as they are until a later delete retries
+            self.unlock_directory();
+            return;
+        };
+        target.acquire_locks();
+        buddy.acquire_locks();
+        // SAFETY: The merged table is not reachable from the directory yet
+        if target
+            .merge(
+                buddy,
+                unsafe { &mut *merged_table },
+                &self.hash_builder,
+                &self.storage,
+            )
+            .is_err()
+        {
+            // The entries did not fit in one segment, both segments stay as they are
+            unsafe { self.storage.free_table(merged_table) };
+            self.storage.free_directory(dir_block);
+            buddy.release_locks();
+            target.release_locks();
+            self.unlock_directory();
+            return;
+        }
+        let mut new_dir = Directory {
+            segments: dir.segments.clone(),
+            global_depth,
+            version: dir.version,
+            depth_count: dir.depth_count,
+        };
+        let chunk_size = 1 << (global_depth - local_depth + 1);
+        let chunk_start = (buddy_pattern >> 1) << (global_depth - local_depth + 1);
+        for entry in &mut new_dir.segments[chunk_start..chunk_start + chunk_size] {
+            *entry = merged_table;
+        }
+        if local_depth == global_depth {
+            new_dir.depth_count -= 2;
+        }
+        while new_dir.depth_count == 0 && new_dir.global_depth > 0 {
+            new_dir = Self::directory_halving(&new_dir);
+        }
+        self.storage.write_directory(
+            dir_block,
+            &new_dir,
+            &[merged_table],
+            &[target_ptr, buddy_ptr],
+        );
+        self.publish(guard, new_dir);
+        // Both segments stay in the `Merging` state, so operations that loaded them before the new directory
+        // was published see that they no longer own their hash once the locks are released
+        buddy.release_locks();
+        target.release_locks();
+        // SAFETY: Both segments are unreachable from the published directory
+        unsafe {
+            self.storage.retire_table(&guard.guard, target_ptr);
+            self.storage.retire_table(&guard.guard, buddy_ptr);
+        }
+        self.unlock_directory();
+    }
+    /**
+    Returns the segment owning the key hash in `dir` and its buddy if they can be merged, i.e. they have the same
+    local depth and their entries fit under `MERGE_LOW_WATER_MARK`. Only final under the directory lock,
+    without it the segments may be split or merged meanwhile.
+    */
+    fn merge_buddies(
+        &self,
+        dir: &Directory<K, V, BUCKETS, STASH>,
+        key_hash: usize,
+    ) -> Option<[*mut Table<K, V, BUCKETS, STASH>; 2]> {
+        let global_depth = dir.global_depth;
+        let dir_index = segment_pattern(key_hash, global_depth);
+        let target_ptr = dir.segments[dir_index];
+        // SAFETY: Both segments are referenced by the directory, which the caller's guard keeps alive
+        let target = unsafe { &*target_ptr };
+        self.recover(dir, dir_index, target);
+        let local_depth = target.local_depth();
+        // A segment at depth 0 covers the whole hash range and has no buddy. A segment deeper than the directory
+        // was split after the directory was loaded
+        if local_depth == 0 || local_depth > global_depth || !target.owns(key_hash) {
+            return None;
+        }
+        let buddy_pattern = target.pattern() ^ 1;
+        let buddy_index = buddy_pattern << (global_depth - local_depth);
+        let buddy_ptr = *dir.segments.get(buddy_index)?;
+        let buddy = unsafe { &*buddy_ptr };
+        self.recover(dir, buddy_index, buddy);
+        if buddy.local_depth() != local_depth
+            || buddy.pattern() != buddy_pattern
+            || target.len() + buddy.len() > Table::<K, V, BUCKETS, STASH>::MERGE_LOW_WATER_MARK
+        {
+            return None;
+        }
+        Some([target_ptr, buddy_ptr])
+    }
+    /**
+    Halves the directory, assuming no segment has a local depth equal to the global depth.
+    Each pair of entries `2i` and `2i + 1` points to the same segment, so entry `i` of the new directory takes it over.
+    */
+    fn directory_halving(dir: &Directory<K, V, BUCKETS, STASH>) -> Directory<K, V, BUCKETS, STASH> {
+        let global_depth = dir.global_depth - 1;
+        let segments: Vec<*mut Table<K, V, BUCKETS, STASH>> =
+            dir.segments.iter().step_by(2).copied().collect();
+        let mut halved = Directory {
+            segments,
+            global_depth,
+            version: dir.version + 1,
+            depth_count: 0,
+        };
+        let mut i = 0;
+        while i < halved.segments.len() {
+            // The depth stored in a segment not recovered since a crash is stale if its split was committed
+            let (local_depth, _) = halved.segment_depth(i);
+            if local_depth == global_depth {
+                halved.depth_count += 1;
+            }
+            i += 1 << (global_depth - local_depth);
+        }
+        halved
+    }
+    /**
+    Marks the map as cleanly shut down, in its pool when it has one, so the next `open` needs no recovery.
+    No operation may follow.
+    */
+    pub fn shut_down(&mut self) {
+        self.clean = true;
+        if let Some(pool) = self.storage.pool() {
+            pool.set_clean(true);
+        }
+    }
+    /**
+    Returns the usage of the slab the segments and directories are allocated from, per block size.
+    The blocks of the segments freed by merges are counted as free, and reused by the next splits.
+    */
+    pub fn memory_usage(&self) -> SlabUsage {
+        self.storage.usage()
+    }
+    /**
+        Returns a copy of the directory with the entries of the upper half of the split segment's chunk
+        pointing to the new segment.
+        Used when the split segment had a local depth lower than the global depth, so no doubling is needed.
+    */
+    fn directory_update(
+        dir: &Directory<K, V, BUCKETS, STASH>,
+        dir_index: usize,
+        new_table: *mut Table<K, V, BUCKETS, STASH>,
+    ) -> Directory<K, V, BUCKETS, STASH> {
+        let mut new_dir = Directory {
+            segments: dir.segments.clone(),
+            global_depth: dir.global_depth,
+            version: dir.version,
+            depth_count: dir.depth_count,
+        };
+        let global_depth = dir.global_depth;
+        // SAFETY: The new table is a valid allocation produced by the split
+        let local_depth = unsafe { (*new_table).local_depth() };
+        if local_depth == global_depth {
+            // The chunk had exactly two entries, the odd one now belongs to the new segment
+            new_dir.segments[dir_index | 1] = new_table;
+            new_dir.depth_count += 2;
+        } else {
+            let chunk_size = 1 << (global_depth - (local_depth - 1));
+            let chunk_start = dir_index - (dir_index % chunk_size);
+            let half = chunk_size / 2;
+            for entry in &mut new_dir.segments[chunk_start + half..chunk_start + chunk_size] {
+                *entry = new_table;
+            }
+        }
+        new_dir
+    }
+    /**
+        Returns the doubled directory, with `version + 1`.
+        This function assumes that the directory lock is held before calling it
+    */
+    fn directory_doubling(
+        dir: &Directory<K, V, BUCKETS, STASH>,
+        new_table_index: usize,
+        new_table: *mut Table<K, V, BUCKETS, STASH>,
+    ) -> Directory<K, V, BUCKETS, STASH> {
+        let old_ds = &dir.segments;
+        let global_depth = dir.global_depth;
+        let current_capacity = 1 << global_depth;
+        let mut new_ds = Vec::with_capacity(2 * current_capacity);
+        for segment in old_ds.iter().take(current_capacity) {
+            new_ds.push(*segment);
+            new_ds.push(*segment);
+        }
+        // Replacing the old duplicate table with new table
+        new_ds[2 * new_table_index + 1] = new_table;
+        Directory {
+            segments: new_ds,
+            global_depth: global_depth + 1,
+            version: dir.version + 1,
+            // Only the two halves of the split segment are at the new global depth
+            depth_count: 2,
+        }
+    }
+}
+// SAFETY: The segments behind the directory's raw pointers are owned by the map, and mutated under the bucket locks
+// or the directory lock. A thread dropping a removed pair may not be the one that inserted it, hence `Send`.
+// Lookups and iterations clone the keys and values of other threads, hence `Sync`: they clone from bitwise copies
+// validated by the bucket versions, see `Table::search`, and the pairs unlinked by the writers are retired like
+// the segments, so they are only freed, on any thread, once no guard can read them.
+unsafe impl<K, V, S, const BUCKETS: usize, const STASH: usize> Send
+    for ExtendableHashing<K, V, S, BUCKETS, STASH>
+where
+    K: PartialEq + Debug + Clone + std::hash::Hash + Send + Sync,
+    V: Clone + Send + Sync,
+    S: Send,
+{
+}
+unsafe impl<K, V, S, const BUCKETS: usize, const STASH: usize> Sync
+    for ExtendableHashing<K, V, S, BUCKETS, STASH>
+where
+    K: PartialEq + Debug + Clone + std::hash::Hash + Send + Sync,
+    V: Clone + Send + Sync,
+    S: Sync,
+{
+}
+// Used by `Drop`, which cannot require `S: BuildHasher`
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > ExtendableHashing<K, V, S, BUCKETS, STASH>
+{
+    /**
+    Frees every segment referenced by the directory, leaving the directory entries dangling.
+    Segments in a pool are kept for the next open, the pool is unmapped with the map.
+    */
+    fn free_segments(&mut self) {
+        if self.storage.pool().is_some() {
+            return;
+        }
+        // SAFETY: `&mut self` excludes any operation
+        let dir = unsafe { &**self.dir.get_mut() };
+        // A segment with local depth `l` covers `2^(global_depth - l)` consecutive entries, free each one once
+        let mut i = 0;
+        while i < dir.segments.len() {
+            let table = dir.segments[i];
+            // SAFETY: Every segment was allocated by `new_table` and is only referenced by this directory
+            unsafe {
+                i += 1 << (dir.global_depth - (*table).local_depth());
+                self.storage.free_table(table);
+            }
+        }
+    }
+}
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        S,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Drop for ExtendableHashing<K, V, S, BUCKETS, STASH>
+{
+    fn drop(&mut self) {
+        self.free_segments();
+        // SAFETY: The directory was allocated with `Box::into_raw` and nothing references it anymore
+        drop(unsafe { Box::from_raw(*self.dir.get_mut()) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extendable_hashing::storage::pool_layout;
+    use crate::extendable_hashing::table::{segment_pattern, Table};
+    use crate::extendable_hashing::{ExtendableHashing, K_NUM_BUCKET, K_STASH_BUCKET};
+    use crate::hash::{ConcurrentMap, MapConfig, MapError, ValueT};
+    use crate::pm::crash::{Crash, CrashSimulator, PmEvent};
+    use crate::pm::{layout_tag, LayoutTag, PmPool};
+    use crate::utils::hashing::{DashBuildHasher, DashHasher};
+    use crate::utils::pair::Key;
+    use std::collections::hash_map::RandomState;
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::hash::{BuildHasher, Hash, Hasher};
+    use std::mem::size_of;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::path::Path;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_directory_invariants<
+        K: PartialEq + Debug + Clone + Hash,
+        V: Clone,
+        S: BuildHasher,
+        const BUCKETS: usize,
+        const STASH: usize,
+    >(
+        hashing: &ExtendableHashing<K, V, S, BUCKETS, STASH>,
+    ) {
+        // Every entry has to point to the segment whose pattern matches the entry's prefix
+        let guard = hashing.enter();
+        let dir = guard.dir();
+        let global_depth = dir.global_depth;
+        assert_eq!(dir.segments.len(), 1 << global_depth);
+        let mut depth_count = 0;
+        for (index, segment) in dir.segments.iter().enumerate() {
+            let table = unsafe { &**segment };
+            assert!(table.local_depth() <= global_depth);
+            assert_eq!(
+                index >> (global_depth - table.local_depth()),
+                table.pattern()
+            );
+            if table.local_depth() == global_depth {
+                depth_count += 1;
+            }
+        }
+        assert_eq!(dir.depth_count, depth_count);
+    }
+
+    #[test]
+    pub fn test_insert_with_split_and_doubling() {
+        let hashing = ExtendableHashing::<u64>::new();
+        let initial_depth = hashing.enter().dir().global_depth;
+        for i in 0..50_000u64 {
+            let value = format!("value {}", i).into_bytes();
+            assert!(hashing.insert(i, value).is_ok());
+        }
+        assert!(hashing.enter().dir().global_depth > initial_depth);
+        assert_eq!(
+            hashing.enter().dir().segments.len(),
+            1 << hashing.enter().dir().global_depth
+        );
+        for i in 0..50_000u64 {
+            assert_eq!(hashing.get(&i), Some(format!("value {}", i).into_bytes()));
+        }
+        assert!(hashing.get(&50_000).is_none());
+    }
+
+    #[test]
+    pub fn test_insert_updates_directory_entries() {
+        let hashing = ExtendableHashing::<u64>::new();
+        for i in 0..50_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_insert_duplicate_key() {
+        let hashing = ExtendableHashing::<u64>::new();
+        assert!(hashing.insert(7, vec![1]).is_ok());
+        assert!(matches!(
+            hashing.insert(7, vec![2]),
+            Err(MapError::KeyExists)
+        ));
+        assert_eq!(hashing.get(&7), Some(vec![1]));
+    }
+
+    #[test]
+    pub fn test_delete() {
+        let hashing = ExtendableHashing::<u64>::new();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i.to_le_bytes().to_vec()).is_ok());
+        }
+        for i in (0..20_000u64).step_by(2) {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        for i in 0..20_000u64 {
+            if i % 2 == 0 {
+                assert!(hashing.get(&i).is_none());
+                assert!(matches!(hashing.remove(&i), Err(MapError::KeyNotFound)));
+            } else {
+                assert_eq!(hashing.get(&i), Some(i.to_le_bytes().to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_reinsert_after_delete() {
+        let hashing = ExtendableHashing::<u64>::new();
+        for round in 0..3u64 {
+            for i in 0..20_000u64 {
+                assert!(hashing.insert(i, vec![round as u8]).is_ok());
+            }
+            for i in 0..20_000u64 {
+                assert_eq!(hashing.get(&i), Some(vec![round as u8]));
+                assert!(hashing.remove(&i).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_len_and_clear() {
+        let mut hashing = ExtendableHashing::<u64>::new();
+        assert!(hashing.is_empty());
+        for i in 0..10_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        assert!(hashing.insert(0, vec![]).is_err());
+        assert_eq!(hashing.len(), 10_000);
+        assert!(hashing.remove(&0).is_ok());
+        assert!(hashing.remove(&0).is_err());
+        assert_eq!(hashing.len(), 9_999);
+        assert!(!hashing.contains_key(&0));
+        assert!(hashing.contains_key(&1));
+
+        hashing.clear().unwrap();
+        assert!(hashing.is_empty());
+        assert!(!hashing.contains_key(&1));
+        assert!(hashing.insert(1, vec![1]).is_ok());
+        assert_eq!(hashing.get(&1), Some(vec![1]));
+    }
+
+    #[test]
+    pub fn test_with_config() {
+        assert!(matches!(
+            ExtendableHashing::<u64>::with_config(MapConfig::new(0)),
+            Err(MapError::InvalidConfig(_))
+        ));
+        let hashing = ExtendableHashing::<u64>::with_config(MapConfig::new(5)).unwrap();
+        assert_eq!(hashing.enter().dir().global_depth, 3);
+        assert_eq!(hashing.enter().dir().segments.len(), 8);
+    }
+
+    #[test]
+    pub fn test_trait_object() {
+        let map: Box<dyn ConcurrentMap<u64, ValueT>> =
+            Box::new(ExtendableHashing::<u64>::with_config(MapConfig::new(1)).unwrap());
+        for i in 0..5_000u64 {
+            assert!(map.insert(i, vec![i as u8]).is_ok());
+        }
+        assert_eq!(map.len(), 5_000);
+        assert_eq!(map.get(&42), Some(vec![42]));
+        assert!(map.remove(&42).is_ok());
+        assert!(!map.contains_key(&42));
+    }
+
+    #[test]
+    pub fn test_typed_values() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Record {
+            id: u64,
+            name: String,
+        }
+        let hashing = ExtendableHashing::<u64, Record>::new();
+        for i in 0..20_000u64 {
+            let record = Record {
+                id: i,
+                name: format!("record {}", i),
+            };
+            assert!(hashing.insert(i, record).is_ok());
+        }
+        assert_eq!(
+            hashing.get(&7),
+            Some(Record {
+                id: 7,
+                name: "record 7".to_string()
+            })
+        );
+        let counters = ExtendableHashing::<u64, u64>::new();
+        for i in 0..1_000u64 {
+            assert!(counters.insert(i, i * i).is_ok());
+        }
+        assert_eq!(counters.get(&12), Some(144));
+    }
+
+    #[test]
+    pub fn test_merge_and_directory_halving() {
+        let hashing = ExtendableHashing::<u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..50_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        let grown_depth = hashing.enter().dir().global_depth;
+        for i in 0..49_900u64 {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        assert_directory_invariants(&hashing);
+        assert!(hashing.enter().dir().global_depth < grown_depth);
+        for i in 49_900..50_000u64 {
+            assert!(hashing.contains_key(&i));
+        }
+        for i in 49_900..50_000u64 {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        // Less than the low water mark is left, so everything collapses into a single segment
+        assert!(hashing.is_empty());
+        assert_eq!(hashing.enter().dir().global_depth, 0);
+        assert_directory_invariants(&hashing);
+        for i in 0..50_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        assert_directory_invariants(&hashing);
+        assert_eq!(hashing.len(), 50_000);
+    }
+
+    #[test]
+    pub fn test_merge_keeps_entries_above_low_water_mark() {
+        let hashing = ExtendableHashing::<u64>::with_config(MapConfig::new(2)).unwrap();
+        let total = 4 * Table::<u64, ValueT>::MERGE_LOW_WATER_MARK as u64;
+        for i in 0..total {
+            assert!(hashing.insert(i, i.to_le_bytes().to_vec()).is_ok());
+        }
+        for i in (0..total).filter(|i| i % 3 != 0) {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        assert_directory_invariants(&hashing);
+        for i in 0..total {
+            if i % 3 == 0 {
+                assert_eq!(hashing.get(&i), Some(i.to_le_bytes().to_vec()));
+            } else {
+                assert!(hashing.get(&i).is_none());
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_deletes_skip_the_directory_lock_without_a_mergeable_buddy() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(2)).unwrap();
+        for i in 0..1_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        assert_eq!(hashing.enter().dir().global_depth, 1);
+        let locked = hashing.lock_and_counter.load(Relaxed);
+        // Emptying the first segment leaves it underfull, while its buddy stays above the low water mark
+        for i in 0..1_000u64 {
+            if segment_pattern(hashing.hash(&i), 1) == 0 {
+                assert!(hashing.remove(&i).is_ok());
+            }
+        }
+        assert_eq!(hashing.lock_and_counter.load(Relaxed), locked);
+        assert_eq!(hashing.enter().dir().global_depth, 1);
+        let segments = hashing.enter().dir().segments.clone();
+        // SAFETY: No segment is merged or split meanwhile
+        let counts: Vec<_> = segments
+            .iter()
+            .map(|segment| unsafe { ((**segment).len(), (**segment).count_entries()) })
+            .collect();
+        assert_eq!(counts[0], (0, 0));
+        assert_eq!(counts[1].0, counts[1].1);
+        assert_eq!(counts[1].0, hashing.len());
+    }
+
+    #[test]
+    pub fn test_iter_keys_and_values() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.iter().count(), 0);
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i * 2).is_ok());
+        }
+        // Every segment is read once, although most of them are referenced by several entries
+        let entries: HashMap<u64, u64> = hashing.iter().collect();
+        assert_eq!(hashing.iter().count(), 20_000);
+        assert_eq!(entries.len(), 20_000);
+        assert!(entries.iter().all(|(key, value)| *value == key * 2));
+        let mut keys: Vec<u64> = hashing.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..20_000).collect::<Vec<_>>());
+        assert_eq!(
+            hashing.values().sum::<u64>(),
+            (0..20_000u64).map(|i| i * 2).sum()
+        );
+        // The segments are read in the order of their hash prefixes
+        let hashes: Vec<usize> = hashing.keys().map(|key| hashing.hash(&key)).collect();
+        let min_depth = {
+            let guard = hashing.enter();
+            let dir = guard.dir();
+            (0..dir.segments.len())
+                .map(|i| dir.segment_depth(i).0)
+                .min()
+                .unwrap()
+        };
+        assert!(min_depth > 0);
+        let prefixes: Vec<usize> = hashes
+            .iter()
+            .map(|hash| segment_pattern(*hash, min_depth))
+            .collect();
+        assert!(prefixes.windows(2).all(|pair| pair[0] <= pair[1]));
+        for i in 0..19_990u64 {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        let mut keys: Vec<u64> = hashing.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (19_990..20_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn test_iter_during_splits_and_merges() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        // Present during the whole iteration
+        for i in 0..10_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        for i in 1_000_000..1_030_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                // Doubles the directory, then merges it back
+                for i in 10_000..40_000u64 {
+                    assert!(hashing.insert(i, i).is_ok());
+                }
+                for i in (10_000..40_000u64).chain(1_000_000..1_030_000) {
+                    assert!(hashing.remove(&i).is_ok());
+                }
+            });
+            for _ in 0..20 {
+                let mut keys: Vec<u64> = hashing.keys().filter(|key| *key < 10_000).collect();
+                keys.sort_unstable();
+                assert_eq!(keys, (0..10_000).collect::<Vec<_>>());
+            }
+            writer.join().unwrap();
+        });
+        assert_eq!(hashing.iter().count(), 10_000);
+    }
+
+    #[test]
+    pub fn test_scan_resumes_across_directory_changes() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..5_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        let mut scanned = Vec::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, entries) = hashing.scan(cursor, 500);
+            assert!(next == 0 || entries.len() >= 500);
+            scanned.extend(entries.into_iter().map(|(key, _)| key));
+            calls += 1;
+            // Doubles the directory in the middle of the scan, then halves it
+            match calls {
+                2 => (5_000..60_000u64).for_each(|i| assert!(hashing.insert(i, i).is_ok())),
+                4 => (5_000..60_000u64).for_each(|i| assert!(hashing.remove(&i).is_ok())),
+                _ => {}
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(calls > 4);
+        let mut kept: Vec<u64> = scanned.into_iter().filter(|key| *key < 5_000).collect();
+        kept.sort_unstable();
+        assert_eq!(kept, (0..5_000).collect::<Vec<_>>());
+        // A whole scan in one call
+        let (next, entries) = hashing.scan(0, usize::MAX);
+        assert_eq!((next, entries.len()), (0, 5_000));
+    }
+
+    #[test]
+    pub fn test_scan_cursor_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let (cursor, mut scanned) = {
+            let mut hashing =
+                ExtendableHashing::<u64, u64>::create(&path, 16 << 20, MapConfig::new(1)).unwrap();
+            for i in 0..10_000u64 {
+                assert!(hashing.insert(i, i).is_ok());
+            }
+            let (cursor, entries) = hashing.scan(0, 3_000);
+            assert_ne!(cursor, 0);
+            hashing.shut_down();
+            (cursor, entries)
+        };
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1)).unwrap();
+        for i in 10_000..30_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        let (next, entries) = hashing.scan(cursor, usize::MAX);
+        assert_eq!(next, 0);
+        scanned.extend(entries.into_iter().filter(|(key, _)| *key < 10_000));
+        scanned.sort_unstable();
+        assert_eq!(scanned, (0..10_000).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    pub fn test_entry_upsert_and_update() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.entry(1).or_insert(10).unwrap(), 10);
+        assert_eq!(hashing.entry(1).or_insert(20).unwrap(), 10);
+        let entry = hashing.entry(1).and_modify(|value| *value += 1);
+        assert_eq!(*entry.key(), 1);
+        let modified = entry.and_modify(|value| *value *= 2).or_insert(0).unwrap();
+        assert_eq!(modified, 22);
+        let mut called = false;
+        assert_eq!(
+            hashing
+                .entry(2)
+                .or_insert_with(|| {
+                    called = true;
+                    7
+                })
+                .unwrap(),
+            7
+        );
+        assert!(called);
+        assert_eq!(hashing.upsert(2, 8).unwrap(), Some(7));
+        assert_eq!(hashing.upsert(3, 9).unwrap(), None);
+        assert_eq!(
+            hashing
+                .update(&3, |value| std::mem::replace(value, 4))
+                .unwrap(),
+            9
+        );
+        assert_eq!(hashing.get(&3), Some(4));
+        assert!(matches!(
+            hashing.update(&4, |_| ()),
+            Err(MapError::KeyNotFound)
+        ));
+        assert_eq!(hashing.len(), 3);
+        // Upserts and entries insert through splits
+        for i in 10..20_000u64 {
+            assert_eq!(hashing.upsert(i, i).unwrap(), None);
+        }
+        for i in 10..20_000u64 {
+            assert_eq!(
+                hashing
+                    .entry(i)
+                    .and_modify(|value| *value += 1)
+                    .or_insert(0)
+                    .unwrap(),
+                i + 1
+            );
+        }
+        assert_eq!(hashing.len(), 20_000 - 7);
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_updates_never_split_segments() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        let blocks = hashing.memory_usage().blocks_in_use();
+        // The values of the keys in full buckets are replaced in place
+        for i in 0..20_000u64 {
+            assert!(hashing.update(&i, |value| *value += 1).is_ok());
+        }
+        for i in 0..20_000u64 {
+            assert_eq!(hashing.upsert(i, i + 2).unwrap(), Some(i + 1));
+        }
+        assert_eq!(hashing.memory_usage().blocks_in_use(), blocks);
+        assert_eq!(hashing.len(), 20_000);
+        assert!((0..20_000u64).all(|i| hashing.get(&i) == Some(i + 2)));
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_concurrent_counters() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    // Enough keys to split while the counters are incremented
+                    for round in 0..3 {
+                        for i in 0..5_000u64 {
+                            if (i + round) % 2 == 0 {
+                                hashing
+                                    .entry(i)
+                                    .and_modify(|count| *count += 1)
+                                    .or_insert(1)
+                                    .unwrap();
+                            } else if hashing.update(&i, |count| *count += 1).is_err() {
+                                hashing
+                                    .entry(i)
+                                    .and_modify(|count| *count += 1)
+                                    .or_insert(1)
+                                    .unwrap();
                             }
-                            target_table.acquire_locks();
-                            let new_bucket = target_table.split(key_hash).unwrap();
-                            target_table.release_locks();
-                            'REINSERT: {
-                                let new_table: Table<T> = dir.segments[dir_index as u64 & TAIL_MASK];
-                                if calculate_hash(target_table) != calculate_hash(&new_table) {
-                                    continue 'RETRY;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(hashing.len(), 5_000);
+        for i in 0..5_000u64 {
+            assert_eq!(hashing.get(&i), Some(12), "lost an update of {}", i);
+        }
+    }
+
+    #[test]
+    pub fn test_compare_and_swap_and_remove_if() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        assert!(matches!(
+            hashing.compare_and_swap(&1, &0, 1),
+            Err(MapError::KeyNotFound)
+        ));
+        assert!(matches!(
+            hashing.remove_if(&1, &0),
+            Err(MapError::KeyNotFound)
+        ));
+        for i in 0..50_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        let grown_depth = hashing.enter().dir().global_depth;
+        for i in 0..50_000u64 {
+            assert!(!hashing.compare_and_swap(&i, &(i + 1), 0).unwrap());
+            assert!(hashing.compare_and_swap(&i, &i, i + 1).unwrap());
+            assert_eq!(hashing.get(&i), Some(i + 1));
+        }
+        for i in 0..50_000u64 {
+            assert!(!hashing.remove_if(&i, &i).unwrap());
+        }
+        assert_eq!(hashing.len(), 50_000);
+        for i in 0..49_900u64 {
+            assert!(hashing.remove_if(&i, &(i + 1)).unwrap());
+        }
+        // Conditional removes merge the segments like `remove`
+        assert_eq!(hashing.len(), 100);
+        assert!(hashing.enter().dir().global_depth < grown_depth);
+        assert_directory_invariants(&hashing);
+        for i in 49_900..50_000u64 {
+            assert_eq!(hashing.get(&i), Some(i + 1));
+        }
+    }
+
+    #[test]
+    pub fn test_concurrent_compare_and_swap() {
+        let hashing = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..2_000u64 {
+            assert!(hashing.insert(i, 0).is_ok());
+        }
+        // Every thread tries to take each lease from every version, exactly one of them wins each swap
+        let won: usize = thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut won = 0;
+                        for version in 0..5u64 {
+                            for i in 0..2_000u64 {
+                                won += hashing.compare_and_swap(&i, &version, version + 1).unwrap()
+                                    as usize;
+                            }
+                        }
+                        won
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum()
+        });
+        assert_eq!(won, 5 * 2_000);
+        for i in 0..2_000u64 {
+            assert_eq!(hashing.get(&i), Some(5));
+        }
+        let removed: usize = thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..2_000u64)
+                            .filter(|i| matches!(hashing.remove_if(i, &5), Ok(true)))
+                            .count()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum()
+        });
+        assert_eq!(removed, 2_000);
+        assert!(hashing.is_empty());
+    }
+
+    #[test]
+    pub fn test_concurrent_get_update_remove_of_heap_values() {
+        // Every value of a key is made of its low byte, with a length changing on every write
+        let value = |key: u64, round: u64| vec![key as u8; 1 + ((key + round) % 64) as usize];
+        let check = |key: u64, value: &[u8]| {
+            assert!(
+                !value.is_empty() && value.len() <= 64 && value.iter().all(|b| *b == key as u8),
+                "torn value of {}: {:?}",
+                key,
+                value
+            );
+        };
+        let hashing = ExtendableHashing::<u64, Vec<u8>>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..2_000u64 {
+            assert!(hashing.insert(i, value(i, 0)).is_ok());
+        }
+        // The writers free the values the readers clone, and split and merge the segments under them
+        thread::scope(|scope| {
+            for writer in 0..2u64 {
+                let hashing = &hashing;
+                scope.spawn(move || {
+                    for round in 1..17u64 {
+                        for i in (writer..4_000u64).step_by(2) {
+                            match round % 3 {
+                                0 => {
+                                    let _ = hashing.remove(&i);
+                                }
+                                1 => {
+                                    hashing.upsert(i, value(i, round)).unwrap();
+                                }
+                                _ => {
+                                    let _ = hashing.update(&i, |old| *old = value(i, round));
                                 }
                             }
                         }
-                        TableError::Internal => {}
-                        TableError::ItemDoesntExist => {}
-                        TableError::UnableToAcquireLock(_) => {
-                            continue 'RETRY;
+                    }
+                });
+            }
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..8 {
+                        for i in 0..4_000u64 {
+                            if let Some(value) = hashing.get(&i) {
+                                check(i, &value);
+                            }
+                        }
+                        for (key, value) in hashing.iter() {
+                            check(key, &value);
                         }
-                        TableError::KeyExists => {}
-                        TableError::UnableToInsertKey => {}
                     }
-                }
+                });
+            }
+        });
+        // The last round upserts every key
+        assert_eq!(hashing.len(), 4_000);
+        for i in 0..4_000u64 {
+            assert_eq!(hashing.get(&i), Some(value(i, 16)));
+        }
+    }
+
+    #[test]
+    pub fn test_persistent_updates_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        {
+            let mut hashing =
+                ExtendableHashing::<u64, [u64; 4]>::create(&path, 16 << 20, MapConfig::new(1))
+                    .unwrap();
+            for i in 0..5_000u64 {
+                assert!(hashing.insert(i, [i; 4]).is_ok());
+            }
+            for i in 0..5_000u64 {
+                assert!(hashing.update(&i, |value| value[3] = i + 1).is_ok());
+            }
+            assert_eq!(hashing.upsert(5_000, [0; 4]).unwrap(), None);
+            hashing.shut_down();
+        }
+        let hashing = ExtendableHashing::<u64, [u64; 4]>::open(&path, MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.len(), 5_001);
+        for i in 0..5_000u64 {
+            assert_eq!(hashing.get(&i), Some([i, i, i, i + 1]));
+        }
+    }
+
+    #[test]
+    pub fn test_persistent_updates_relocate_pairs_of_full_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let hashing =
+            ExtendableHashing::<u64, u64>::create(&path, 16 << 20, MapConfig::new(1)).unwrap();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        let blocks = hashing.memory_usage().blocks_in_use();
+        for i in 0..20_000u64 {
+            assert!(hashing.update(&i, |value| *value += 1).is_ok());
+        }
+        for i in 0..20_000u64 {
+            assert_eq!(hashing.upsert(i, i + 2).unwrap(), Some(i + 1));
+        }
+        // The copies of the pairs of full buckets go to their other bucket or to the stash
+        assert_eq!(hashing.memory_usage().blocks_in_use(), blocks);
+        assert_eq!(hashing.len(), 20_000);
+        assert!((0..20_000u64).all(|i| hashing.get(&i) == Some(i + 2)));
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_map_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ExtendableHashing<u64>>();
+        assert_send_sync::<ExtendableHashing<String, u64>>();
+    }
+
+    #[test]
+    pub fn test_concurrent_insert_get_and_remove() {
+        let hashing =
+            Arc::new(ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap());
+        let num_threads = 8u64;
+        let per_thread = 10_000u64;
+        let handles: Vec<_> = (0..num_threads)
+            .map(|t| {
+                let hashing = Arc::clone(&hashing);
+                thread::spawn(move || {
+                    for i in t * per_thread..(t + 1) * per_thread {
+                        assert!(hashing.insert(i, i + 1).is_ok());
+                        assert_eq!(hashing.get(&i), Some(i + 1));
+                    }
+                    // Each thread removes the even keys it inserted, while the others keep splitting segments
+                    for i in (t * per_thread..(t + 1) * per_thread).step_by(2) {
+                        assert!(hashing.remove(&i).is_ok());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(hashing.len(), (num_threads * per_thread / 2) as usize);
+        for i in 0..num_threads * per_thread {
+            let expected = if i % 2 == 0 { None } else { Some(i + 1) };
+            assert_eq!(hashing.get(&i), expected);
+        }
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_reads_during_directory_doubling() {
+        let hashing =
+            Arc::new(ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap());
+        let prefilled = 5_000u64;
+        for i in 0..prefilled {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        let initial_version = hashing.enter().dir().version;
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let hashing = Arc::clone(&hashing);
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        for i in 0..prefilled {
+                            assert_eq!(hashing.get(&i), Some(i));
+                        }
+                    }
+                })
+            })
+            .collect();
+        let writers: Vec<_> = (1..5u64)
+            .map(|t| {
+                let hashing = Arc::clone(&hashing);
+                thread::spawn(move || {
+                    for i in t * 100_000..t * 100_000 + 20_000 {
+                        assert!(hashing.insert(i, i).is_ok());
+                    }
+                })
+            })
+            .collect();
+        for handle in readers.into_iter().chain(writers) {
+            handle.join().unwrap();
+        }
+        assert!(hashing.enter().dir().version > initial_version);
+        assert_eq!(hashing.len(), prefilled as usize + 4 * 20_000);
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_retired_directories_are_collected() {
+        let hashing = ExtendableHashing::<u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        // The collector frees the retired directories in batches once no operation is pinned anymore
+        for _ in 0..3 {
+            hashing.collector.collect();
+        }
+        assert_eq!(hashing.collector.pending(), 0);
+        // Every split took and released the directory lock
+        assert!(hashing.lock_and_counter.load(Relaxed) > 0);
+    }
+
+    #[test]
+    pub fn test_merged_segments_are_reused() {
+        let hashing = ExtendableHashing::<u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        let grown = hashing.memory_usage();
+        assert_eq!(grown.capacity, None);
+        let table_size = size_of::<Table<u64, ValueT>>().next_multiple_of(64);
+        let tables = grown
+            .classes
+            .iter()
+            .find(|class| class.block_size == table_size);
+        assert!(tables.unwrap().in_use > 1);
+        for i in 0..20_000u64 {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        for _ in 0..3 {
+            hashing.collector.collect();
+        }
+        // Only the last segment is left, the blocks of the merged ones are free
+        let shrunk = hashing.memory_usage();
+        assert_eq!(shrunk.blocks_in_use(), 1);
+        assert!(shrunk.free_bytes() >= grown.used_bytes());
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, vec![]).is_ok());
+        }
+        // The splits reuse them instead of growing the slab
+        assert_eq!(hashing.memory_usage().region_used, shrunk.region_used);
+        // A pool also counts the directory, and keeps its free blocks across a reopen
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let pool_used = {
+            let hashing =
+                ExtendableHashing::<u64, u64>::create(&path, 16 << 20, MapConfig::new(1)).unwrap();
+            for i in 0..20_000u64 {
+                assert!(hashing.insert(i, i).is_ok());
             }
+            for i in 0..20_000u64 {
+                assert!(hashing.remove(&i).is_ok());
+            }
+            for _ in 0..3 {
+                hashing.collector.collect();
+            }
+            let usage = hashing.memory_usage();
+            assert_eq!(usage.capacity, Some(16 << 20));
+            usage.region_used
+        };
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.memory_usage().blocks_in_use(), 2);
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
         }
+        assert_eq!(hashing.memory_usage().region_used, pool_used);
     }
 
-    fn delete(key: T) {
-        todo!()
+    #[test]
+    pub fn test_persistent_map_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        {
+            let mut hashing =
+                ExtendableHashing::<u64, u64>::create(&path, 64 << 20, MapConfig::new(1)).unwrap();
+            for i in 0..20_000u64 {
+                assert!(hashing.insert(i, i * 3).is_ok());
+            }
+            for i in (0..20_000u64).step_by(4) {
+                assert!(hashing.remove(&i).is_ok());
+            }
+            hashing.shut_down();
+        }
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.len(), 15_000);
+        assert_directory_invariants(&hashing);
+        for i in 0..20_000u64 {
+            let expected = if i % 4 == 0 { None } else { Some(i * 3) };
+            assert_eq!(hashing.get(&i), expected);
+        }
+        // The reopened map keeps splitting and merging in the pool
+        for i in 20_000..30_000u64 {
+            assert!(hashing.insert(i, i * 3).is_ok());
+        }
+        for i in 0..30_000u64 {
+            let _ = hashing.remove(&i);
+        }
+        assert!(hashing.is_empty());
+        assert_directory_invariants(&hashing);
     }
 
-    fn get(key: T, buff: &mut [u8]) {
-        todo!()
+    #[test]
+    pub fn test_persistent_map_clear_and_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        {
+            let mut hashing =
+                ExtendableHashing::<u64, u64>::create(&path, 16 << 20, MapConfig::new(4)).unwrap();
+            for i in 0..5_000u64 {
+                assert!(hashing.insert(i, i).is_ok());
+            }
+            hashing.clear().unwrap();
+            assert!(hashing.insert(7, 8).is_ok());
+        }
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(4)).unwrap();
+        assert_eq!(hashing.len(), 1);
+        assert_eq!(hashing.get(&7), Some(8));
+        drop(hashing);
+        // The pool was written with other value types
+        assert!(matches!(
+            ExtendableHashing::<u64, u32>::open(&path, MapConfig::new(4)),
+            Err(MapError::Pm(_))
+        ));
+        // A pool too small for the segments reports it instead of panicking
+        let small = ExtendableHashing::<u64, u64>::create(
+            dir.path().join("small"),
+            1 << 20,
+            MapConfig::new(1),
+        )
+        .unwrap();
+        let full = (0..1_000_000u64).find_map(|i| small.insert(i, i).err());
+        assert!(matches!(full, Some(MapError::Pm(_))));
     }
-}
-impl<T> ExtendableHashing<T> {
-    fn shut_down(&mut self) {
-        self.clean = true;
-        // Persist after that
+
+    #[test]
+    pub fn test_lazy_recovery_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        {
+            let hashing =
+                ExtendableHashing::<u64, u64>::create(&path, 64 << 20, MapConfig::new(1)).unwrap();
+            for i in 0..20_000u64 {
+                assert!(hashing.insert(i, i).is_ok());
+            }
+            // The process dies in the middle of an operation, holding the locks of a segment
+            let guard = hashing.enter();
+            unsafe { &*guard.dir().segments[0] }.acquire_locks();
+        }
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.crash_version, 1);
+        {
+            let guard = hashing.enter();
+            let dir = guard.dir();
+            assert!(dir
+                .segments
+                .iter()
+                .all(|segment| !unsafe { &**segment }.recovered(1)));
+        }
+        for i in 0..20_000u64 {
+            assert_eq!(hashing.get(&i), Some(i));
+        }
+        for i in 20_000..30_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        assert_eq!(hashing.len(), 30_000);
+        assert_directory_invariants(&hashing);
+        let mut hashing = hashing;
+        hashing.shut_down();
+        drop(hashing);
+        // A clean shut down needs no recovery
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.crash_version, 1);
+        assert_eq!(hashing.get(&29_999), Some(29_999));
+    }
+
+    #[test]
+    pub fn test_custom_hasher() {
+        let hashing = ExtendableHashing::<u64, u64, RandomState>::with_hasher(
+            MapConfig::new(1),
+            RandomState::new(),
+        )
+        .unwrap();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        assert!(hashing.enter().dir().global_depth > 0);
+        assert_directory_invariants(&hashing);
+        for i in 0..15_000u64 {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        for i in 0..20_000u64 {
+            assert_eq!(hashing.get(&i), (i >= 15_000).then_some(i));
+        }
+        assert_directory_invariants(&hashing);
     }
+
+    #[test]
+    pub fn test_byte_string_keys() {
+        let hashing = ExtendableHashing::<Key, u64>::with_config(MapConfig::new(1)).unwrap();
+        // Lengths from the empty key to keys spanning several words, and UUID-like blobs
+        let key = |i: u64| match i % 3 {
+            0 => Key::from("k".repeat((i % 40) as usize) + &i.to_string()),
+            1 => Key::from(format!(
+                "{:08x}-{:04x}-4711-8899-{:012x}",
+                i,
+                i % 65536,
+                i * 31
+            )),
+            _ => Key::from(i.to_le_bytes()[..(i % 9) as usize].to_vec()),
+        };
+        let mut inserted = 0;
+        for i in 0..20_000u64 {
+            match hashing.insert(key(i), i) {
+                Ok(()) => inserted += 1,
+                // Short binary keys repeat
+                Err(MapError::KeyExists) => assert!(i % 3 == 2),
+                Err(err) => panic!("{:?}", err),
+            }
+        }
+        assert_eq!(hashing.len(), inserted);
+        assert!(hashing.enter().dir().global_depth > 0);
+        assert_directory_invariants(&hashing);
+        for i in (0..20_000u64).filter(|i| i % 3 != 2) {
+            assert_eq!(hashing.get(&key(i)), Some(i));
+        }
+        assert!(hashing.get(&Key::from("missing")).is_none());
+        for i in (0..20_000u64).filter(|i| i % 3 == 0) {
+            assert!(hashing.remove(&key(i)).is_ok());
+        }
+        assert_directory_invariants(&hashing);
+        for i in (0..20_000u64).filter(|i| i % 3 != 2) {
+            assert_eq!(hashing.get(&key(i)), (i % 3 == 1).then_some(i));
+        }
+    }
+
     /**
-        This function assumes that the entire directory is locked before calling it
+    A persistent hasher other than `DashBuildHasher`, whose pools cannot be opened with the default one.
     */
-    fn directory_doubling(
-        &mut self,
-        new_table_index: usize,
-        new_bucket: &mut Table<T>,
-        // old_bucket: &mut Table<T>,
-    ) {
-        let old_ds = &mut self.dir.segments;
-        let global_depth = &self.dir.global_depth;
-        println!("Directory is doubling to global depth {}", global_depth + 1);
-        let current_capacity = 2.pow(global_depth);
-        let mut new_d: Directory<T> = Directory::new(2 * current_capacity, self.dir.version + 1);
-        let new_ds = &mut new_d.segments;
-        for i in 0..current_capacity {
-            new_ds[2 * i] = old_ds[i].clone();
-            new_ds[2 * i + 1] = old_ds[i].clone();
-        }
-        // Replacing the old duplicate table with  new table
-        new_ds[2 * new_table_index + 1] = new_bucket;
-        new_d.depth_count += 1;
-
-        self.dir = new_d;
+    #[derive(Default)]
+    struct SeededBuildHasher;
+    impl BuildHasher for SeededBuildHasher {
+        type Hasher = DashHasher;
+        fn build_hasher(&self) -> DashHasher {
+            let mut hasher = DashHasher::default();
+            hasher.write_u64(1);
+            hasher
+        }
+    }
+    impl LayoutTag for SeededBuildHasher {
+        const TAG: u64 = layout_tag("SeededBuildHasher");
+    }
+
+    #[test]
+    pub fn test_pool_layout_is_stable() {
+        // Pools written by earlier builds are opened with this layout
+        assert_eq!(
+            pool_layout::<u64, u64, DashBuildHasher, K_NUM_BUCKET, K_STASH_BUCKET>(),
+            0xdfac_c27e_07f3_6ba3
+        );
+        // Types of the same size and alignment are told apart by their tags
+        assert_ne!(
+            pool_layout::<u64, u64, DashBuildHasher, K_NUM_BUCKET, K_STASH_BUCKET>(),
+            pool_layout::<u64, i64, DashBuildHasher, K_NUM_BUCKET, K_STASH_BUCKET>()
+        );
+        assert_ne!(
+            pool_layout::<u64, u64, DashBuildHasher, K_NUM_BUCKET, K_STASH_BUCKET>(),
+            pool_layout::<u64, u64, SeededBuildHasher, K_NUM_BUCKET, K_STASH_BUCKET>()
+        );
+    }
+
+    #[test]
+    pub fn test_pool_rejects_another_hasher() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let mut hashing =
+            ExtendableHashing::<u64, u64>::create(&path, 16 << 20, MapConfig::new(1)).unwrap();
+        assert!(hashing.insert(1, 1).is_ok());
+        hashing.shut_down();
+        drop(hashing);
+        let reopened =
+            ExtendableHashing::<u64, u64, SeededBuildHasher>::open(&path, MapConfig::new(1));
+        assert!(matches!(reopened, Err(MapError::Pm(_))));
+        let hashing =
+            ExtendableHashing::<u64, u64, DashBuildHasher>::open(&path, MapConfig::new(1)).unwrap();
+        assert_eq!(hashing.get(&1), Some(1));
+    }
+
+    fn check_geometry<const BUCKETS: usize, const STASH: usize>() {
+        let hashing = ExtendableHashing::<u64, u64, DashBuildHasher, BUCKETS, STASH>::with_config(
+            MapConfig::new(1),
+        )
+        .unwrap();
+        for i in 0..20_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        assert_directory_invariants(&hashing);
+        for i in 0..20_000u64 {
+            assert_eq!(hashing.get(&i), Some(i));
+        }
+        for i in 0..20_000u64 {
+            assert!(hashing.remove(&i).is_ok());
+        }
+        assert_eq!(hashing.enter().dir().global_depth, 0);
+        assert_directory_invariants(&hashing);
+    }
+
+    #[test]
+    pub fn test_segment_geometry() {
+        check_geometry::<4, 1>();
+        check_geometry::<16, 4>();
+        check_geometry::<1024, 2>();
+        // A small segment splits long before a default one
+        let small =
+            ExtendableHashing::<u64, u64, DashBuildHasher, 8, 1>::with_config(MapConfig::new(1))
+                .unwrap();
+        let default = ExtendableHashing::<u64, u64>::with_config(MapConfig::new(1)).unwrap();
+        for i in 0..500u64 {
+            assert!(small.insert(i, i).is_ok());
+            assert!(default.insert(i, i).is_ok());
+        }
+        assert!(small.enter().dir().global_depth > default.enter().dir().global_depth);
+    }
+
+    #[test]
+    pub fn test_pool_rejects_another_geometry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let mut hashing = ExtendableHashing::<u64, u64, DashBuildHasher, 16, 1>::create(
+            &path,
+            16 << 20,
+            MapConfig::new(1),
+        )
+        .unwrap();
+        for i in 0..2_000u64 {
+            assert!(hashing.insert(i, i).is_ok());
+        }
+        hashing.shut_down();
+        drop(hashing);
+        let reopened = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1));
+        assert!(matches!(reopened, Err(MapError::Pm(_))));
+        let hashing =
+            ExtendableHashing::<u64, u64, DashBuildHasher, 16, 1>::open(&path, MapConfig::new(1))
+                .unwrap();
+        for i in 0..2_000u64 {
+            assert_eq!(hashing.get(&i), Some(i));
+        }
+    }
+
+    const CRASH_WORKLOAD: u64 = 3_000;
+
+    /**
+    Inserts the keys of the crash workload in a simulated pool crashing at the `crash_at`-th flush or fence
+    after the map is created, or never if `crash_at` is `None`.
+    Returns the keys whose insert returned before the crash, and the events recorded since the map was created.
+    */
+    fn run_until_crash(path: &Path, crash_at: Option<usize>) -> (Vec<u64>, Vec<PmEvent>) {
+        let simulator = Arc::new(CrashSimulator::new());
+        let pool = PmPool::create_simulated(
+            path,
+            16 << 20,
+            pool_layout::<u64, u64, DashBuildHasher, K_NUM_BUCKET, K_STASH_BUCKET>(),
+            simulator.clone(),
+        )
+        .unwrap();
+        let hashing = ExtendableHashing::<u64, u64>::create_in(pool, MapConfig::new(1)).unwrap();
+        let created = simulator.events().len();
+        if let Some(crash_at) = crash_at {
+            simulator.crash_at(created + crash_at);
+        }
+        let mut committed = Vec::new();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            for i in 0..CRASH_WORKLOAD {
+                hashing.insert(i, i).unwrap();
+                committed.push(i);
+            }
+        }));
+        match result {
+            Ok(()) => assert!(crash_at.is_none()),
+            Err(payload) => assert!(payload.downcast::<Crash>().is_ok()),
+        }
+        drop(hashing);
+        (committed, simulator.events().split_off(created))
+    }
+
+    /**
+    Reopens the map left by `run_until_crash` and checks that every committed key survived exactly once,
+    and that the slab allocates exactly the published segments and directory.
+    */
+    fn verify_after_crash(path: &Path, committed: &[u64]) {
+        let hashing = ExtendableHashing::<u64, u64>::open(path, MapConfig::new(1)).unwrap();
+        for key in committed {
+            assert_eq!(hashing.get(key), Some(*key), "lost key {}", key);
+        }
+        {
+            let guard = hashing.enter();
+            let dir = guard.dir();
+            let mut keys = Vec::new();
+            let mut segments = 0;
+            let mut i = 0;
+            while i < dir.segments.len() {
+                let table = unsafe { &*dir.segments[i] };
+                hashing.recover(dir, i, table);
+                keys.extend(table.keys(&guard.guard));
+                assert!(hashing
+                    .storage
+                    .slab()
+                    .is_allocated(dir.segments[i] as *const u8));
+                segments += 1;
+                i += 1 << (dir.global_depth - dir.segment_depth(i).0);
+            }
+            let pool = hashing.storage.pool().unwrap();
+            let root = unsafe { pool.at::<u8>(pool.root()) };
+            assert!(hashing.storage.slab().is_allocated(root));
+            // Nothing leaked: the segments and the directory are the only blocks in use
+            assert_eq!(hashing.memory_usage().blocks_in_use(), segments + 1);
+            let stored = keys.len();
+            keys.sort_unstable();
+            keys.dedup();
+            assert_eq!(keys.len(), stored, "a key is stored twice");
+            assert!(keys.iter().all(|key| *key < CRASH_WORKLOAD));
+            assert_eq!(hashing.len(), stored);
+        }
+        assert_directory_invariants(&hashing);
+    }
+
+    /**
+    Returns the index of every event from the allocation of a split's table to the end of the publication
+    of its directory, the split being found by the persist of a whole table.
+    */
+    fn split_events(events: &[PmEvent]) -> Vec<usize> {
+        let table_size = size_of::<Table<u64, u64>>();
+        let mut indexes: Vec<usize> = events
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| matches!(event, PmEvent::Flush { len, .. } if *len >= table_size))
+            .flat_map(|(index, _)| index.saturating_sub(8)..(index + 28).min(events.len()))
+            .collect();
+        indexes.dedup();
+        indexes
+    }
+
+    #[test]
+    pub fn test_crash_during_insert() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let (committed, events) = run_until_crash(&path, None);
+        assert_eq!(committed.len(), CRASH_WORKLOAD as usize);
+        verify_after_crash(&path, &committed);
+        let splits = split_events(&events);
+        assert!(!splits.is_empty());
+        for crash_at in (0..events.len()).step_by(7) {
+            // Splits are covered by `test_crash_during_split`
+            if splits.contains(&crash_at) {
+                continue;
+            }
+            let (committed, _) = run_until_crash(&path, Some(crash_at));
+            verify_after_crash(&path, &committed);
+        }
+    }
+
+    #[test]
+    pub fn test_crash_during_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let (_, events) = run_until_crash(&path, None);
+        // The workload doubles the directory, so some of the splits are doublings
+        let hashing = ExtendableHashing::<u64, u64>::open(&path, MapConfig::new(1)).unwrap();
+        assert!(hashing.enter().dir().global_depth > 1);
+        drop(hashing);
+        for crash_at in split_events(&events) {
+            let (committed, _) = run_until_crash(&path, Some(crash_at));
+            verify_after_crash(&path, &committed);
+        }
     }
 }
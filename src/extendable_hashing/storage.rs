@@ -0,0 +1,288 @@
+use crate::extendable_hashing::directory::{persisted_size, Directory};
+use crate::extendable_hashing::table::Table;
+use crate::hash::MapError;
+use crate::pm::pool::CACHE_LINE_SIZE;
+use crate::pm::slab::LOG_CAPACITY;
+use crate::pm::{LayoutTag, Persist, PmPool, Pod, Slab, SlabUsage};
+use crate::utils::epoch::Guard;
+use crate::utils::hashing::DashHasher;
+use std::fmt::Debug;
+use std::hash::Hasher;
+use std::mem::{align_of, size_of};
+use std::ptr::{drop_in_place, null_mut};
+use std::sync::Arc;
+
+/**
+Where the directory and the segments of a map live, both allocated from a slab.
+In DRAM the slab takes its chunks from the heap. In a persistent memory pool it also keeps a copy of every published
+directory, and publishes the segments and directories of a split or a merge together with the new root,
+so a crash neither leaks the space of an unpublished segment nor frees a published one.
+The blocks of unpublished segments are reused once the epoch collector frees them.
+*/
+pub(crate) struct Storage {
+    slab: Arc<Slab>,
+}
+/**
+A segment unpublished by a merge, given back to the slab when the epoch collector drops it.
+*/
+struct RetiredTable<
+    K: PartialEq + Debug + Clone,
+    V: Clone,
+    const BUCKETS: usize,
+    const STASH: usize,
+> {
+    slab: Arc<Slab>,
+    table: *mut Table<K, V, BUCKETS, STASH>,
+}
+impl<K: PartialEq + Debug + Clone, V: Clone, const BUCKETS: usize, const STASH: usize> Drop
+    for RetiredTable<K, V, BUCKETS, STASH>
+{
+    fn drop(&mut self) {
+        // SAFETY: The table was unpublished and its merge freed it in the slab, nothing can reach it anymore
+        unsafe {
+            drop_in_place(self.table);
+            self.slab.release(self.table as *const u8);
+        }
+    }
+}
+impl Storage {
+    pub fn dram() -> Self {
+        Storage {
+            slab: Arc::new(Slab::heap()),
+        }
+    }
+    /**
+    Creates the slab of a new pool.
+    */
+    pub fn create(pool: PmPool) -> Result<Self, MapError> {
+        Ok(Storage {
+            slab: Arc::new(Slab::create(pool)?),
+        })
+    }
+    /**
+    Reopens the slab of a pool, completing the publish a crash interrupted.
+    */
+    pub fn open(pool: PmPool) -> Result<Self, MapError> {
+        Ok(Storage {
+            slab: Arc::new(Slab::open(pool)?),
+        })
+    }
+    pub fn pool(&self) -> Option<&PmPool> {
+        self.slab.pool()
+    }
+    pub fn usage(&self) -> SlabUsage {
+        self.slab.usage()
+    }
+    #[cfg(test)]
+    pub(crate) fn slab(&self) -> &Slab {
+        &self.slab
+    }
+    /**
+    Frees every block, the caller has to drop the segments first.
+    # Panics
+    If a retired segment was not collected yet.
+    */
+    pub fn reset(&mut self) -> Result<(), MapError> {
+        Arc::get_mut(&mut self.slab)
+            .expect("the retired segments have to be collected before a reset")
+            .reset()?;
+        Ok(())
+    }
+    /**
+    Allocates an empty, persisted table. It is lost on a crash until a directory referencing it is written.
+    */
+    pub fn new_table<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    >(
+        &self,
+        pattern: usize,
+        local_depth: usize,
+    ) -> Result<*mut Table<K, V, BUCKETS, STASH>, MapError> {
+        // Slab blocks are aligned to a cache line
+        assert!(align_of::<Table<K, V, BUCKETS, STASH>>() as u64 <= CACHE_LINE_SIZE);
+        let table = self
+            .slab
+            .reserve(size_of::<Table<K, V, BUCKETS, STASH>>())?
+            .as_ptr() as *mut Table<K, V, BUCKETS, STASH>;
+        // A DRAM map never crashes and keeps the crash version 0
+        let crash_version = self.pool().map_or(0, PmPool::crash_version);
+        // SAFETY: The block is large enough and aligned for a table, and not referenced by anything yet
+        unsafe {
+            Table::init(table, pattern, local_depth, crash_version);
+            (*table).persist(self);
+        }
+        Ok(table)
+    }
+    /**
+    Frees a table that was never published.
+    # Safety
+    `table` must come from `new_table` and be unreachable for any other thread.
+    */
+    pub unsafe fn free_table<
+        K: PartialEq + Debug + Clone,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    >(
+        &self,
+        table: *mut Table<K, V, BUCKETS, STASH>,
+    ) {
+        drop_in_place(table);
+        self.slab.release(table as *const u8);
+    }
+    /**
+    Frees a table unpublished by `write_directory` once every operation that could have loaded it is done.
+    # Safety
+    See `Guard::retire`, `table` must be in the `freed` tables of the written directory.
+    */
+    pub unsafe fn retire_table<
+        K: PartialEq + Debug + Clone,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    >(
+        &self,
+        guard: &Guard<'_>,
+        table: *mut Table<K, V, BUCKETS, STASH>,
+    ) {
+        guard.retire(Box::into_raw(Box::new(RetiredTable {
+            slab: self.slab.clone(),
+            table,
+        })));
+    }
+    /**
+    Reserves the space of the persistent copy of a directory with `len` entries, null in DRAM where there is none.
+    Splits and merges reserve it before changing any segment, so running out of space never leaves them half done.
+    */
+    pub fn reserve_directory(&self, len: usize) -> Result<*mut u8, MapError> {
+        match self.pool() {
+            None => Ok(null_mut()),
+            Some(_) => Ok(self.slab.reserve(persisted_size(len))?.as_ptr()),
+        }
+    }
+    /**
+    Frees the space returned by `reserve_directory` when the directory is not written.
+    */
+    pub fn free_directory(&self, block: *mut u8) {
+        if !block.is_null() {
+            // SAFETY: The block was reserved and never published
+            unsafe { self.slab.release(block) };
+        }
+    }
+    /**
+    Writes the directory to the space returned by `reserve_directory` and makes it the one found on the next open.
+    In the same failure-atomic step, the `allocated` tables and the directory become allocated, while the `freed`
+    tables and the previous directory become free. The previous directory is only read by `open`, so its space
+    is reused right away, the freed tables have to be retired.
+    */
+    pub fn write_directory<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    >(
+        &self,
+        block: *mut u8,
+        dir: &Directory<K, V, BUCKETS, STASH>,
+        allocated: &[*mut Table<K, V, BUCKETS, STASH>],
+        freed: &[*mut Table<K, V, BUCKETS, STASH>],
+    ) {
+        let Some(pool) = self.pool() else {
+            return;
+        };
+        // SAFETY: The space was reserved for this many entries and the segments were allocated by `new_table`
+        unsafe { dir.write_to(pool, pool.offset_of(block)) };
+        let old_root = pool.root();
+        let old_dir = (old_root != 0).then(|| unsafe { pool.at::<u8>(old_root) as *const u8 });
+        let allocated: Vec<*const u8> = allocated
+            .iter()
+            .map(|table| *table as *const u8)
+            .chain([block as *const u8])
+            .collect();
+        let freed: Vec<*const u8> = freed
+            .iter()
+            .map(|table| *table as *const u8)
+            .chain(old_dir)
+            .collect();
+        // SAFETY: The tables and the directory were reserved by this storage, the freed ones were published
+        unsafe {
+            self.slab.publish(&allocated, &freed, Some(block));
+            if let Some(old_dir) = old_dir {
+                self.slab.release(old_dir);
+            }
+        }
+    }
+    /**
+    Writes the directory of a new map, whose segments were all allocated by `new_table`.
+    */
+    pub fn publish_directory<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    >(
+        &self,
+        dir: &Directory<K, V, BUCKETS, STASH>,
+    ) -> Result<(), MapError> {
+        let block = self.reserve_directory(dir.segments.len())?;
+        // The pool has no root yet, so a crash before the directory is written leaves nothing to open
+        let (last, first) = dir
+            .segments
+            .split_last()
+            .expect("a directory has at least one entry");
+        for tables in first.chunks(LOG_CAPACITY) {
+            let tables: Vec<*const u8> = tables.iter().map(|table| *table as *const u8).collect();
+            // SAFETY: The tables were reserved by `new_table` and never published
+            unsafe { self.slab.publish(&tables, &[], None) };
+        }
+        self.write_directory(block, dir, &[*last], &[]);
+        Ok(())
+    }
+}
+impl Persist for Storage {
+    fn flush(&self, ptr: *const u8, len: usize) {
+        self.slab.flush(ptr, len);
+    }
+    fn fence(&self) {
+        self.slab.fence();
+    }
+    fn is_durable(&self) -> bool {
+        self.slab.is_durable()
+    }
+}
+// Bumped whenever the format of the pool, the slab, the directories or the tables changes
+const LAYOUT_VERSION: u64 = 1;
+/**
+Identifies the layout of the tables stored in a pool, so a pool is never opened with other key or value types,
+another hasher, or another bucket geometry. The descriptor is hashed with `DashHasher`, whose output never changes
+with the Rust release, and only holds the format version, the tags of the types and their sizes and alignments.
+*/
+pub(crate) fn pool_layout<
+    K: PartialEq + Debug + Clone + Pod,
+    V: Clone + Pod,
+    S: LayoutTag,
+    const BUCKETS: usize,
+    const STASH: usize,
+>() -> u64 {
+    let mut hasher = DashHasher::default();
+    for word in [
+        LAYOUT_VERSION,
+        K::TAG,
+        size_of::<K>() as u64,
+        align_of::<K>() as u64,
+        V::TAG,
+        size_of::<V>() as u64,
+        align_of::<V>() as u64,
+        S::TAG,
+        size_of::<Table<K, V, BUCKETS, STASH>>() as u64,
+        align_of::<Table<K, V, BUCKETS, STASH>>() as u64,
+        BUCKETS as u64,
+        STASH as u64,
+    ] {
+        hasher.write_u64(word);
+    }
+    hasher.finish()
+}
@@ -1,22 +1,40 @@
 use crate::extendable_hashing::bucket::{
-    check_bit_32, get_bitmap, get_count, stash_insert, Bucket, BucketError, K_NUM_PAIR_PER_BUCKET,
+    check_bit_32, get_bitmap, get_count, get_member, stash_insert, Bucket, BucketGuard,
+    K_NUM_PAIR_PER_BUCKET,
 };
-use crate::extendable_hashing::{BUCKET_MASK, K_FINGER_BITS, K_NUM_BUCKET, K_STASH_BUCKET};
-use crate::hash::ValueT;
-use crate::utils::hashing::calculate_hash;
-use crate::utils::pair::{Key, Pair};
+use crate::extendable_hashing::{K_FINGER_BITS, K_NUM_BUCKET, K_STASH_BUCKET};
+use crate::pm::Persist;
+use crate::utils::epoch::Guard;
+use crate::utils::pair::Pair;
+use std::alloc::{alloc, handle_alloc_error, Layout};
+use std::cell::UnsafeCell;
 use std::fmt::Debug;
-use std::hash::{Hash, Hasher};
-use std::sync::{Arc, Mutex};
+use std::hash::BuildHasher;
+use std::hint::spin_loop;
+use std::mem::{needs_drop, size_of};
+use std::ptr::addr_of_mut;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
 use thiserror::Error;
 
-#[derive(Debug, Hash)]
-enum TableState {
+#[repr(u8)]
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TableState {
     Merging,
     Splitting,
     NewTable,
     Normal,
 }
+impl TableState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => TableState::Merging,
+            1 => TableState::Splitting,
+            2 => TableState::NewTable,
+            _ => TableState::Normal,
+        }
+    }
+}
 #[derive(Debug, Error)]
 pub enum TableError {
     #[error("The table is full")]
@@ -31,332 +49,655 @@ pub enum TableError {
     KeyExists,
     #[error("Unable to insert key in any of the buckets")]
     UnableToInsertKey,
+    #[error("The key hash is no longer owned by this segment")]
+    KeyMoved,
 }
 #[derive(Debug, Error)]
 pub enum SplitError {
     #[error("Something wrong occurred")]
     InternalError(String),
 }
-// Segment
+/**
+A segment of the directory. The buckets are stored inline, so a table is a single allocation
+that can live on the heap or in a persistent memory pool, see `Table::init`.
+*/
 #[derive(Debug)]
-pub struct Table<T: PartialEq + Debug + Clone> {
-    // TODO: Check if we need the dummy array
-    // dummy: [char; 48],
-    bucket: Vec<Bucket<T>>,
-    local_depth: usize,
-    pattern: usize,
-    number: i32,
-    state: Arc<TableState>,
-    lock_bit: Arc<Mutex<u32>>, /* for the synchronization of the lazy recovery in one segment*/
+pub struct Table<
+    K: PartialEq + Debug + Clone,
+    V: Clone,
+    const BUCKETS: usize = K_NUM_BUCKET,
+    const STASH: usize = K_STASH_BUCKET,
+> {
+    // Mutated from `&self` under the bucket locks, see `bucket_mut`
+    bucket: [UnsafeCell<Bucket<K, V>>; BUCKETS],
+    stash: [UnsafeCell<Bucket<K, V>>; STASH],
+    // `local_depth` and `pattern` only change under all the bucket locks. Lock-free readers check the ownership
+    // before and after a versioned search, which cannot complete while a split holds the locks
+    local_depth: AtomicUsize,
+    pattern: AtomicUsize,
+    // The number of entries, kept by the inserts and deletes so `len` reads no bitmap. Recounted by `recover`
+    count: AtomicUsize,
+    number: AtomicU64, // The crash version the table was created or last recovered in, see `Table::recover`
+    state: AtomicU8,   // A `TableState`
+    lock_bit: AtomicU64, /* for the synchronization of the lazy recovery in one segment*/
 }
-impl<T> Hash for Table<T> {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.bucket.hash(state);
-        self.local_depth.hash(state);
-        self.pattern.hash(state);
-        self.number.hash(state);
-        self.state.hash(state);
+impl<
+        K: PartialEq + Debug + Clone + std::hash::Hash,
+        V: Clone,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Table<K, V, BUCKETS, STASH>
+{
+    /**
+    Wraps the bucket indexes, `BUCKETS` is a power of two.
+    */
+    pub const BUCKET_MASK: usize = BUCKETS - 1;
+    /**
+    Checked when a table is initialized, so an invalid geometry fails to compile.
+    The probing bucket wraps around with `BUCKET_MASK`, and the overflow indicators keep the index
+    of the stash bucket in two bits.
+    */
+    const VALID_GEOMETRY: () = {
+        assert!(
+            BUCKETS.is_power_of_two() && BUCKETS >= 4 && BUCKETS <= 1 << 16,
+            "the buckets of a segment have to be a power of two between 4 and 65536"
+        );
+        assert!(
+            STASH >= 1 && STASH <= 4,
+            "a segment has to have between 1 and 4 stash buckets"
+        );
+    };
+    /**
+    The number of entries under which a table is merged with its buddy, a quarter of its normal slots.
+    */
+    pub const MERGE_LOW_WATER_MARK: usize = BUCKETS * K_NUM_PAIR_PER_BUCKET as usize / 4;
+    pub fn new(pattern: usize) -> Box<Self> {
+        Self::with_local_depth(pattern, 0, 0)
     }
-}
-impl<T: PartialEq + Debug + Clone> Table<T> {
-    pub fn new(pattern: usize) -> Self {
-        let mut buckets = vec![];
-        for _i in 0..(K_NUM_BUCKET + K_STASH_BUCKET) {
-            buckets.push(Bucket::new());
+    pub(crate) fn with_local_depth(
+        pattern: usize,
+        local_depth: usize,
+        crash_version: u64,
+    ) -> Box<Self> {
+        let layout = Layout::new::<Self>();
+        // SAFETY: The table is initialized in place before the box takes it over, `Box` frees it with the same layout
+        unsafe {
+            let table = alloc(layout) as *mut Self;
+            if table.is_null() {
+                handle_alloc_error(layout);
+            }
+            Self::init(table, pattern, local_depth, crash_version);
+            Box::from_raw(table)
         }
-        Table {
-            bucket: buckets,
-            local_depth: 0,
-            pattern,
-            number: 0,
-            state: Arc::from(TableState::Normal),
-            lock_bit: Arc::new(Mutex::new(0)),
+    }
+    /**
+    Initializes an empty table in place, the table is too large to be built on the stack and moved.
+    A new table needs no recovery until the next crash, so it starts at the current `crash_version`.
+    # Safety
+    `table` must be valid for writes and aligned for `Self`, any previous content is overwritten without being dropped.
+    */
+    pub(crate) unsafe fn init(
+        table: *mut Self,
+        pattern: usize,
+        local_depth: usize,
+        crash_version: u64,
+    ) {
+        let () = Self::VALID_GEOMETRY;
+        let buckets = addr_of_mut!((*table).bucket) as *mut UnsafeCell<Bucket<K, V>>;
+        for i in 0..BUCKETS {
+            buckets.add(i).write(UnsafeCell::new(Bucket::new()));
+        }
+        let stash = addr_of_mut!((*table).stash) as *mut UnsafeCell<Bucket<K, V>>;
+        for i in 0..STASH {
+            stash.add(i).write(UnsafeCell::new(Bucket::new()));
         }
+        addr_of_mut!((*table).local_depth).write(AtomicUsize::new(local_depth));
+        addr_of_mut!((*table).pattern).write(AtomicUsize::new(pattern));
+        addr_of_mut!((*table).count).write(AtomicUsize::new(0));
+        addr_of_mut!((*table).number).write(AtomicU64::new(crash_version));
+        addr_of_mut!((*table).state).write(AtomicU8::new(TableState::Normal as u8));
+        addr_of_mut!((*table).lock_bit).write(AtomicU64::new(0));
+    }
+    /**
+    Returns the cell of the bucket at `index`, the stash buckets follow the `BUCKETS` normal buckets.
+    */
+    fn cell(&self, index: usize) -> &UnsafeCell<Bucket<K, V>> {
+        if index < BUCKETS {
+            &self.bucket[index]
+        } else {
+            &self.stash[index - BUCKETS]
+        }
+    }
+    fn bucket(&self, index: usize) -> &Bucket<K, V> {
+        // SAFETY: Writers only mutate a bucket while holding its lock, readers without the lock only read its version
+        // and copy it with `Bucket::snapshot`
+        unsafe { &*self.cell(index).get() }
+    }
+    /**
+    Gives mutable access to a bucket from `&self`, used by the insert and delete paths.
+    # Safety
+    The caller must hold the lock of the bucket (or of the whole stash, for stash buckets),
+    or have exclusive access to the table.
+    */
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn bucket_mut(&self, index: usize) -> &mut Bucket<K, V> {
+        &mut *self.cell(index).get()
+    }
+    /**
+    Makes the stores to the bucket at `index` durable.
+    */
+    fn persist_bucket<P: Persist>(&self, index: usize, persist: &P) {
+        persist.persist(
+            self.cell(index).get() as *const u8,
+            size_of::<Bucket<K, V>>(),
+        );
+    }
+    /**
+    Makes the whole table durable, used once a split or a merge rewrote it.
+    */
+    pub(crate) fn persist<P: Persist>(&self, persist: &P) {
+        persist.persist(self as *const Self as *const u8, size_of::<Self>());
+    }
+    fn buckets(&self) -> impl Iterator<Item = &Bucket<K, V>> {
+        (0..BUCKETS + STASH).map(|i| self.bucket(i))
+    }
+    fn stash(&self) -> [&Bucket<K, V>; STASH] {
+        std::array::from_fn(|i| self.bucket(BUCKETS + i))
+    }
+    pub fn local_depth(&self) -> usize {
+        self.local_depth.load(Acquire)
+    }
+    pub fn pattern(&self) -> usize {
+        self.pattern.load(Acquire)
+    }
+    pub(crate) fn state(&self) -> TableState {
+        TableState::from_u8(self.state.load(Acquire))
+    }
+    pub(crate) fn set_state(&self, state: TableState) {
+        self.state.store(state as u8, Release);
+    }
+    fn persist_state<P: Persist>(&self, state: TableState, persist: &P) {
+        self.set_state(state);
+        persist.persist(
+            &self.state as *const AtomicU8 as *const u8,
+            size_of::<AtomicU8>(),
+        );
     }
     /**
-    Acquiring the lock for a table or segment is same as acquiring locks for all the buckets inside it
+    Acquiring the lock for a table or segment is same as acquiring locks for all the buckets inside it.
+    The stash buckets are locked last, so optimistic readers of the stash also see the version change.
     */
     pub fn acquire_locks(&self) {
-        for i in 0..K_NUM_BUCKET {
-            self.bucket[i].get_lock();
+        for i in 0..BUCKETS + STASH {
+            self.bucket(i).get_lock();
         }
     }
     pub fn release_locks(&self) {
-        for i in 0..K_NUM_BUCKET {
-            self.bucket[i].release_lock();
+        for i in 0..BUCKETS + STASH {
+            self.bucket(i).release_lock();
         }
     }
-    pub fn insert(
-        &mut self,
-        key: Key<T>,
-        value: ValueT,
+    /**
+    Returns true if the key hash falls in the hash range of this segment, i.e. its `local_depth` MSBs match the pattern
+    */
+    pub fn owns(&self, key_hash: usize) -> bool {
+        // A merged segment stays in the `Merging` state, its hash range belongs to the merged segment
+        self.state() != TableState::Merging
+            && segment_pattern(key_hash, self.local_depth()) == self.pattern()
+    }
+    /**
+    Releases the lock a freshly split table holds on its first bucket, see `Table::split`
+    */
+    pub fn release_first_lock(&self) {
+        self.bucket(0).release_lock();
+    }
+    /**
+    Locks the target and neighbor buckets of the key hash and checks that the table still owns it.
+    Both locks are released when the returned guard is dropped.
+    */
+    fn lock_buckets(&self, key_hash: usize) -> Result<BucketLocks<'_, K, V>, TableError> {
+        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, Self::BUCKET_MASK);
+        // (bucket_index + 1) & Self::BUCKET_MASK used for wrapping up to 0 when the bucket_index is 63
+        // (63 + 1) & 63 = 64 & 63 = 0
+        let target = self.bucket(bucket_index).lock();
+        // A split locks the buckets in order, waiting for the neighbor of the last bucket could deadlock
+        let Some(neighbor) = self
+            .bucket((bucket_index + 1) & Self::BUCKET_MASK)
+            .try_lock()
+        else {
+            return Err(TableError::UnableToAcquireLock(
+                "Unable to acquire neighbor lock".to_string(),
+            ));
+        };
+        // A split may have moved the hash range of the key to a new segment while we waited for the locks
+        if !self.owns(key_hash) {
+            return Err(TableError::KeyMoved);
+        }
+        Ok(BucketLocks {
+            index: bucket_index,
+            _target: target,
+            _neighbor: neighbor,
+            stash: None,
+        })
+    }
+    /**
+    Inserts the key in the target or neighbor bucket, displacing an entry or falling back to the stash when both are full.
+    Every modified bucket is persisted before its lock is released.
+    */
+    pub fn insert<P: Persist>(
+        &self,
+        key: &K,
+        value: &V,
         key_hash: usize,
-        meta_hash: u8, // directory: &Directory<T>,
+        meta_hash: u8,
+        persist: &P,
     ) -> Result<i32, TableError> {
-        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, BUCKET_MASK);
-
-        let buckets_ptr = self.bucket.as_mut_ptr();
-        unsafe {
-            let target = &mut *buckets_ptr.add(bucket_index);
-            // (bucket_index + 1) & BUCKET_MASK used for wrapping up to 0 when the bucket_index is 63
-            // (63 + 1) & 63 = 64 & 63 = 0
-            let neighbor = &mut *buckets_ptr.add((bucket_index + 1) & BUCKET_MASK);
-            target.get_lock();
-            if !neighbor.try_get_lock() {
-                target.release_lock();
+        let locks = self.lock_buckets(key_hash)?;
+        let bucket_index = locks.index;
+        let target = self.bucket(bucket_index);
+        let neighbor = self.bucket((bucket_index + 1) & Self::BUCKET_MASK);
+        if !target.unique_check(meta_hash, key, neighbor, &self.stash()) {
+            return Err(TableError::KeyExists);
+        }
+        // SAFETY: The target and neighbor locks are held
+        unsafe { self.insert_locked(bucket_index, key, value, meta_hash, persist) }.inspect(|_| {
+            self.count.fetch_add(1, Relaxed);
+        })
+    }
+    /**
+    The insert of a key known to be absent, see `insert`.
+    # Safety
+    The caller must hold the locks of the target and neighbor buckets.
+    */
+    unsafe fn insert_locked<P: Persist>(
+        &self,
+        bucket_index: usize,
+        key: &K,
+        value: &V,
+        meta_hash: u8,
+        persist: &P,
+    ) -> Result<i32, TableError> {
+        let neighbor_index = (bucket_index + 1) & Self::BUCKET_MASK;
+        let target = self.bucket_mut(bucket_index);
+        let neighbor = self.bucket_mut(neighbor_index);
+        if get_count(target.bitmap) == K_NUM_PAIR_PER_BUCKET
+            && get_count(neighbor.bitmap) == K_NUM_PAIR_PER_BUCKET
+        {
+            // Both the buckets are full, We have to do the displacement
+            let next_index = (bucket_index + 2) & Self::BUCKET_MASK;
+            let Some(next_lock) = self.bucket(next_index).try_lock() else {
                 return Err(TableError::UnableToAcquireLock(
-                    "Unable to acquire neighbor lock".to_string(),
+                    "Unable to acquire the lock for next neighbor".to_string(),
                 ));
-            }
-            // let dir = directory;
-            // TODO: Check if we need to add the next block
-            // Trying to get the MSBs of the key to determine the segment index
-            // let segment_index = key_hash >> (8 * size_of::<usize>() - dir.global_depth);
-            // if dir.x[segment_index] != self {
-            //     target.release_lock();
-            //     neighbor.release_lock();
-            //     return Err(TableError::Internal);
-            // }
-            if !target.unique_check(meta_hash, &key, neighbor, &self.bucket[K_NUM_BUCKET..]) {
-                neighbor.release_lock();
-                target.release_lock();
-                return Err(TableError::KeyExists);
-            }
-            if get_count(target.bitmap) == K_NUM_PAIR_PER_BUCKET
-                && get_count(neighbor.bitmap) == K_NUM_PAIR_PER_BUCKET
-            {
-                // Both the buckets are full, We have to do the displacement
-                let next_neighbor = &mut *buckets_ptr.add((bucket_index + 2) & BUCKET_MASK);
-                if !next_neighbor.try_get_lock() {
-                    neighbor.release_lock();
-                    target.release_lock();
-                    return Err(TableError::UnableToAcquireLock(
-                        "Unable to acquire the lock for next neighbor".to_string(),
-                    ));
-                }
-
-                let displacement_res = Self::next_displace(
-                    neighbor,
-                    next_neighbor,
-                    key.clone(),
-                    value.clone(),
-                    meta_hash,
-                );
-                next_neighbor.release_lock();
-                if displacement_res {
-                    // inserted in the neighboring bucket by displacement
-                    target.release_lock();
-                    neighbor.release_lock();
-                    return Ok(2);
-                }
-                // Now we check for previous neighbor
-                let prev_index = if bucket_index == 0 {
-                    K_NUM_BUCKET - 1
-                } else {
-                    bucket_index - 1
-                };
-                let prev_neighbor = &mut *buckets_ptr.add(prev_index);
-                if !prev_neighbor.try_get_lock() {
-                    target.release_lock();
-                    neighbor.release_lock();
-                    return Err(TableError::UnableToAcquireLock(
-                        "Unable to acquire the lock for previous neighbor".to_string(),
-                    ));
-                }
+            };
+            let next_neighbor = self.bucket_mut(next_index);
 
-                let displacement_res = Self::prev_displace(
-                    target,
-                    prev_neighbor,
-                    key.clone(),
-                    value.clone(),
-                    meta_hash,
-                );
+            let displacement_res =
+                Self::next_displace(neighbor, next_neighbor, key, value, meta_hash);
+            if displacement_res {
+                // The displaced entry has to be durable in its new bucket before the key takes its slot
+                self.persist_bucket(next_index, persist);
+                self.persist_bucket(neighbor_index, persist);
+            }
+            drop(next_lock);
+            if displacement_res {
+                // inserted in the neighboring bucket by displacement
+                return Ok(2);
+            }
+            // Now we check for previous neighbor
+            let prev_index = if bucket_index == 0 {
+                BUCKETS - 1
+            } else {
+                bucket_index - 1
+            };
+            let Some(prev_lock) = self.bucket(prev_index).try_lock() else {
+                return Err(TableError::UnableToAcquireLock(
+                    "Unable to acquire the lock for previous neighbor".to_string(),
+                ));
+            };
+            let prev_neighbor = self.bucket_mut(prev_index);
 
-                prev_neighbor.release_lock();
-                if displacement_res {
-                    // inserted in the prev neighboring bucket by displacement
-                    target.release_lock();
-                    neighbor.release_lock();
-                    return Ok(3);
-                }
+            let displacement_res =
+                Self::prev_displace(target, prev_neighbor, key, value, meta_hash);
+            if displacement_res {
+                self.persist_bucket(prev_index, persist);
+                self.persist_bucket(bucket_index, persist);
+            }
+            drop(prev_lock);
+            if displacement_res {
+                // inserted in the prev neighboring bucket by displacement
+                return Ok(3);
+            }
 
-                // Now we try to insert in the stash buckets
-                let stash_bucket = &mut *buckets_ptr.add(K_NUM_BUCKET);
-                if !stash_bucket.try_get_lock() {
-                    target.release_lock();
-                    neighbor.release_lock();
-                    return Err(TableError::UnableToAcquireLock(
-                        "Unable to acquire the lock for stash bucket".to_string(),
-                    ));
-                }
-                let mut stash_buckets: Vec<&mut Bucket<T>> = vec![];
-                for i in 0..K_STASH_BUCKET {
-                    stash_buckets.push(&mut *buckets_ptr.add(K_NUM_BUCKET + i));
-                }
-                let stash_insert_res = stash_insert(
-                    stash_buckets,
-                    target,
-                    neighbor,
-                    key.clone(),
-                    value.clone(),
-                    meta_hash,
-                );
-                stash_bucket.release_lock();
-                target.release_lock();
-                neighbor.release_lock();
-                if stash_insert_res {
-                    Ok(4)
-                } else {
-                    Err(TableError::TableFull)
+            // Now we try to insert in the stash buckets
+            let Some(stash_lock) = self.bucket(BUCKETS).try_lock() else {
+                return Err(TableError::UnableToAcquireLock(
+                    "Unable to acquire the lock for stash bucket".to_string(),
+                ));
+            };
+            let mut stash_buckets: Vec<&mut Bucket<K, V>> = vec![];
+            for i in 0..STASH {
+                stash_buckets.push(self.bucket_mut(BUCKETS + i));
+            }
+            let stash_insert_res =
+                stash_insert(stash_buckets, target, neighbor, key, value, meta_hash);
+            if stash_insert_res {
+                // The entry is persisted before the overflow indicator pointing to it
+                for i in 0..STASH {
+                    self.persist_bucket(BUCKETS + i, persist);
                 }
+                self.persist_bucket(bucket_index, persist);
+                self.persist_bucket(neighbor_index, persist);
+            }
+            drop(stash_lock);
+            if stash_insert_res {
+                Ok(4)
             } else {
-                // Insert in the bucket which has lesser keys
-                if get_count(target.bitmap) <= get_count(neighbor.bitmap) {
-                    match target.insert(key.clone(), value.clone(), meta_hash, false) {
-                        Ok(_) => {
-                            target.release_lock();
-                            neighbor.release_lock();
-                            Ok(0)
-                        }
-                        Err(error) => {
-                            target.release_lock();
-                            neighbor.release_lock();
-                            println!("Error while inserting the key in target bucket {:?}", error);
-                            Err(TableError::UnableToInsertKey)
-                        }
+                Err(TableError::TableFull)
+            }
+        } else {
+            // Insert in the bucket which has lesser keys
+            if get_count(target.bitmap) <= get_count(neighbor.bitmap) {
+                match target.insert(key.clone(), value.clone(), meta_hash, false) {
+                    Ok(_) => {
+                        self.persist_bucket(bucket_index, persist);
+                        Ok(0)
                     }
-                } else {
-                    match neighbor.insert(key.clone(), value.clone(), meta_hash, true) {
-                        Ok(_) => {
-                            target.release_lock();
-                            neighbor.release_lock();
-                            Ok(1)
-                        }
-                        Err(error) => {
-                            target.release_lock();
-                            neighbor.release_lock();
-                            println!(
-                                "Error while inserting the key in neighbor bucket {:?}",
-                                error
-                            );
-                            Err(TableError::UnableToInsertKey)
-                        }
+                    Err(_) => Err(TableError::UnableToInsertKey),
+                }
+            } else {
+                match neighbor.insert(key.clone(), value.clone(), meta_hash, true) {
+                    Ok(_) => {
+                        self.persist_bucket(neighbor_index, persist);
+                        Ok(1)
                     }
+                    Err(_) => Err(TableError::UnableToInsertKey),
+                }
+            }
+        }
+    }
+    /**
+    Finds the key in the target, neighbor or stash buckets, like `delete`. Returns the index of its bucket
+    and its slot. The stash lock is taken to probe the stash and kept in `locks` when the key is found there.
+    # Safety
+    `locks` must hold the target and neighbor buckets of the key in this table.
+    */
+    unsafe fn find_locked<'a>(
+        &'a self,
+        locks: &mut BucketLocks<'a, K, V>,
+        key: &K,
+        meta_hash: u8,
+    ) -> Option<(usize, u32)> {
+        let bucket_index = locks.index;
+        let neighbor_index = (bucket_index + 1) & Self::BUCKET_MASK;
+        let target = self.bucket(bucket_index);
+        if let Some(slot) = target.find(meta_hash, key, false) {
+            return Some((bucket_index, slot));
+        }
+        if let Some(slot) = self.bucket(neighbor_index).find(meta_hash, key, true) {
+            return Some((neighbor_index, slot));
+        }
+        if !target.test_stash_check() {
+            return None;
+        }
+        let stash_lock = self.bucket(BUCKETS).lock();
+        let found = (BUCKETS..BUCKETS + STASH).find_map(|index| {
+            self.bucket(index)
+                .find(meta_hash, key, false)
+                .map(|slot| (index, slot))
+        });
+        if found.is_some() {
+            locks.stash = Some(stash_lock);
+        }
+        found
+    }
+    /**
+    Applies `f` to a copy of the value found by `find_locked` and stores the copy, the old value is retired
+    as readers may still clone it. In DRAM the copy replaces the value in its slot, readers validate the bucket
+    version so they never clone a torn value. A crash could tear a value replaced in place in a pool, so the copy
+    goes to a free slot of the bucket instead, and replaces the old pair with a single store to the bitmap.
+    When the bucket is full the pair is moved to another bucket of the key, see `relocate_locked`.
+    # Safety
+    The locks found by `find_locked` must be held, and `(index, slot)` must come from it.
+    */
+    unsafe fn update_locked<'a, P: Persist, R>(
+        &'a self,
+        locks: &mut BucketLocks<'a, K, V>,
+        (index, slot): (usize, u32),
+        persist: &P,
+        guard: &Guard<'_>,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> Result<R, TableError> {
+        let bucket = self.bucket_mut(index);
+        if persist.is_durable() && bucket.find_empty_slot() == -1 {
+            return self.relocate_locked(locks, (index, slot), persist, guard, f);
+        }
+        let mut value = bucket.pair(slot).unwrap().value.clone();
+        let result = f(&mut value);
+        if !persist.is_durable() {
+            retire(guard, bucket.replace_value(slot, value));
+            return Ok(result);
+        }
+        let Ok(staged) = bucket.stage_replace(slot, value) else {
+            unreachable!("the bucket has a free slot");
+        };
+        self.persist_bucket(index, persist);
+        let old = bucket.commit_replace(slot, staged);
+        self.persist_bucket(index, persist);
+        retire(guard, old);
+        Ok(result)
+    }
+    /**
+    The update of a pair whose bucket is full in a pool. The copy is written to the other bucket of the key among
+    its target and neighbor, or else to a stash bucket, and persisted before the old pair is removed. A crash in
+    between leaves both pairs, `recover` keeps one of them. Fails with `TableFull`, without calling `f`, when none
+    of these buckets has a free slot.
+    # Safety
+    See `update_locked`.
+    */
+    unsafe fn relocate_locked<'a, P: Persist, R>(
+        &'a self,
+        locks: &mut BucketLocks<'a, K, V>,
+        (index, slot): (usize, u32),
+        persist: &P,
+        guard: &Guard<'_>,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> Result<R, TableError> {
+        let bucket_index = locks.index;
+        let neighbor_index = (bucket_index + 1) & Self::BUCKET_MASK;
+        let meta_hash = self.bucket(index).finger_array[slot as usize];
+        let destination = [bucket_index, neighbor_index]
+            .into_iter()
+            .filter(|i| *i != index && self.bucket(*i).find_empty_slot() != -1)
+            .min_by_key(|i| get_count(self.bucket(*i).bitmap));
+        let destination = match destination {
+            Some(destination) => destination,
+            None => {
+                if locks.stash.is_none() {
+                    locks.stash = Some(self.bucket(BUCKETS).lock());
+                }
+                (BUCKETS..BUCKETS + STASH)
+                    .find(|i| self.bucket(*i).find_empty_slot() != -1)
+                    .ok_or(TableError::TableFull)?
+            }
+        };
+        let pair = self.bucket(index).pair(slot).unwrap();
+        let key = pair.key.clone();
+        let mut value = pair.value.clone();
+        let result = f(&mut value);
+        let probe = destination == neighbor_index;
+        self.bucket_mut(destination)
+            .insert(key, value, meta_hash, probe)
+            .expect("the destination has a free slot");
+        self.persist_bucket(destination, persist);
+        if destination >= BUCKETS {
+            // The copy is persisted before the overflow indicator pointing to it
+            let target = self.bucket_mut(bucket_index);
+            target.set_indicator(
+                meta_hash,
+                self.bucket_mut(neighbor_index),
+                (destination - BUCKETS) as u8,
+            );
+            self.persist_bucket(bucket_index, persist);
+            self.persist_bucket(neighbor_index, persist);
+        }
+        let old = self.bucket_mut(index).take(slot);
+        self.persist_bucket(index, persist);
+        if index >= BUCKETS {
+            let target = self.bucket_mut(bucket_index);
+            target.unset_indicator(
+                meta_hash,
+                self.bucket_mut(neighbor_index),
+                (index - BUCKETS) as u64,
+            );
+            self.persist_bucket(bucket_index, persist);
+            self.persist_bucket(neighbor_index, persist);
+        }
+        retire(guard, old);
+        Ok(result)
+    }
+    /**
+    Applies `f` to the value of the key under the locks of its target and neighbor buckets, and of the stash when
+    the key is stored there, and returns its result. `f` works on a copy of the value, which replaces the stored one
+    once `f` returns. Fails with `ItemDoesntExist`, without calling `f`, when the key is absent, and with `TableFull`
+    when a table in a pool has no free slot for the copy in any bucket of the key, see `update_locked`.
+    */
+    pub fn update<P: Persist, R>(
+        &self,
+        key: &K,
+        key_hash: usize,
+        meta_hash: u8,
+        persist: &P,
+        guard: &Guard<'_>,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> Result<R, TableError> {
+        let mut locks = self.lock_buckets(key_hash)?;
+        // SAFETY: The target and neighbor locks are held
+        unsafe {
+            match self.find_locked(&mut locks, key, meta_hash) {
+                Some(found) => self.update_locked(&mut locks, found, persist, guard, f),
+                None => Err(TableError::ItemDoesntExist),
+            }
+        }
+    }
+    /**
+    Updates the key like `update` when it is present, or inserts it with `value` like `insert`, under the same locks.
+    Returns the result of `f`, or `None` when the key was inserted.
+    */
+    #[allow(clippy::too_many_arguments)]
+    pub fn upsert<P: Persist, R>(
+        &self,
+        key: &K,
+        value: &V,
+        key_hash: usize,
+        meta_hash: u8,
+        persist: &P,
+        guard: &Guard<'_>,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> Result<Option<R>, TableError> {
+        let mut locks = self.lock_buckets(key_hash)?;
+        // SAFETY: The target and neighbor locks are held
+        unsafe {
+            match self.find_locked(&mut locks, key, meta_hash) {
+                Some(found) => self
+                    .update_locked(&mut locks, found, persist, guard, f)
+                    .map(Some),
+                None => self
+                    .insert_locked(locks.index, key, value, meta_hash, persist)
+                    .map(|_| {
+                        self.count.fetch_add(1, Relaxed);
+                        None
+                    }),
+            }
+        }
+    }
+    /**
+    Replaces the value of the key with `new` like `update`, if it is equal to `expected`.
+    Returns whether the value was replaced, or fails with `ItemDoesntExist` when the key is absent.
+    */
+    #[allow(clippy::too_many_arguments)]
+    pub fn compare_and_swap<P: Persist>(
+        &self,
+        key: &K,
+        expected: &V,
+        new: &V,
+        key_hash: usize,
+        meta_hash: u8,
+        persist: &P,
+        guard: &Guard<'_>,
+    ) -> Result<bool, TableError>
+    where
+        V: PartialEq,
+    {
+        let mut locks = self.lock_buckets(key_hash)?;
+        // SAFETY: The target and neighbor locks are held
+        unsafe {
+            match self.find_locked(&mut locks, key, meta_hash) {
+                Some((index, slot))
+                    if self.bucket(index).pair(slot).unwrap().value != *expected =>
+                {
+                    Ok(false)
                 }
+                Some(found) => self
+                    .update_locked(&mut locks, found, persist, guard, |value| {
+                        *value = new.clone()
+                    })
+                    .map(|_| true),
+                None => Err(TableError::ItemDoesntExist),
             }
         }
     }
     /**
     This insert function is very similar to the traditional insert function.
-    The only difference is we are trying to shift the values from a split bucket to its neighbor
+    The only difference is we are trying to shift the values from a split bucket to its neighbor.
+    The new table is not reachable from the directory yet, so no bucket locks are taken here.
      */
     pub fn insert_4_split(
         &mut self,
-        key: &Key<T>,
-        value: &ValueT,
+        key: &K,
+        value: &V,
         key_hash: usize,
         meta_hash: u8,
     ) -> Result<i32, TableError> {
-        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, BUCKET_MASK);
-        let buckets_ptr = self.bucket.as_mut_ptr();
+        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, Self::BUCKET_MASK);
         unsafe {
-            let target = &mut *buckets_ptr.add(bucket_index);
-
-            let neighbor = &mut *buckets_ptr.add((bucket_index + 1) & BUCKET_MASK);
-            let insert_bucket;
-            let mut probe = false;
-            if get_count(target.bitmap) <= get_count(neighbor.bitmap) {
-                insert_bucket = target;
-            } else {
-                probe = true;
-                insert_bucket = neighbor;
-            }
-            insert_bucket.get_lock();
+            let target = self.bucket_mut(bucket_index);
+            let neighbor = self.bucket_mut((bucket_index + 1) & Self::BUCKET_MASK);
+            let probe = get_count(target.bitmap) > get_count(neighbor.bitmap);
+            let insert_bucket = if probe { &mut *neighbor } else { &mut *target };
             if get_count(insert_bucket.bitmap) < K_NUM_PAIR_PER_BUCKET {
                 // Case where we can store the new element
-                match insert_bucket.insert(key.clone(), value.clone(), meta_hash, probe) {
-                    Ok(_) => {
-                        insert_bucket.release_lock();
-                        Ok(1)
-                    }
-                    Err(_) => {
-                        println!("Error occurred while inserting a new element inside insert4split function");
-                        Err(TableError::Internal)
-                    }
-                }
-            } else {
-                // Case where the target and neighbors are filled
-                let next_neighbor = &mut *buckets_ptr.add((bucket_index + 2) & BUCKET_MASK);
-                if !next_neighbor.try_get_lock() {
-                    insert_bucket.release_lock();
-                    return Err(TableError::UnableToAcquireLock(
-                        "Unable to acquire the lock for next neighbor".to_string(),
-                    ));
-                }
-                let displacement_res = Self::next_displace(
-                    insert_bucket,
-                    next_neighbor,
-                    key.clone(),
-                    value.clone(),
-                    meta_hash,
-                );
-                next_neighbor.release_lock();
-                if displacement_res {
-                    // inserted in the neighboring bucket by displacement
-                    insert_bucket.release_lock();
-                    return Ok(2);
-                }
-                // Now we check for previous neighbor
-                let prev_index = if bucket_index == 0 {
-                    K_NUM_BUCKET - 1
-                } else {
-                    bucket_index - 1
+                return match insert_bucket.insert(key.clone(), value.clone(), meta_hash, probe) {
+                    Ok(_) => Ok(1),
+                    Err(_) => Err(TableError::Internal),
                 };
-                let prev_neighbor = &mut *buckets_ptr.add(prev_index);
-                if !prev_neighbor.try_get_lock() {
-                    insert_bucket.release_lock();
-                    return Err(TableError::UnableToAcquireLock(
-                        "Unable to acquire the lock for previous neighbor".to_string(),
-                    ));
-                }
-
-                let displacement_res = Self::prev_displace(
-                    target,
-                    prev_neighbor,
-                    key.clone(),
-                    value.clone(),
-                    meta_hash,
-                );
-
-                prev_neighbor.release_lock();
-                if displacement_res {
-                    // inserted in the prev neighboring bucket by displacement
-                    insert_bucket.release_lock();
-                    return Ok(3);
-                }
-                // Trying to insert in stash_bucket
-
-                let mut stash_buckets: Vec<&mut Bucket<T>> = vec![];
-                for i in 0..K_STASH_BUCKET {
-                    stash_buckets.push(&mut *buckets_ptr.add(K_NUM_BUCKET + i));
-                }
-                target.get_lock();
-                neighbor.get_lock();
-                let stash_insert_res = stash_insert(
-                    stash_buckets,
-                    target,
-                    neighbor,
-                    key.clone(),
-                    value.clone(),
-                    meta_hash,
-                );
-                target.release_lock();
-                neighbor.release_lock();
-                if stash_insert_res {
-                    Ok(4)
-                } else {
-                    Err(TableError::TableFull)
-                }
+            }
+            // Case where the target and neighbors are filled
+            let next_neighbor = self.bucket_mut((bucket_index + 2) & Self::BUCKET_MASK);
+            if Self::next_displace(neighbor, next_neighbor, key, value, meta_hash) {
+                // inserted in the neighboring bucket by displacement
+                return Ok(2);
+            }
+            // Now we check for previous neighbor
+            let prev_index = if bucket_index == 0 {
+                BUCKETS - 1
+            } else {
+                bucket_index - 1
+            };
+            let prev_neighbor = self.bucket_mut(prev_index);
+            if Self::prev_displace(target, prev_neighbor, key, value, meta_hash) {
+                // inserted in the prev neighboring bucket by displacement
+                return Ok(3);
+            }
+            // Trying to insert in stash_bucket
+            let mut stash_buckets: Vec<&mut Bucket<K, V>> = vec![];
+            for i in 0..STASH {
+                stash_buckets.push(self.bucket_mut(BUCKETS + i));
+            }
+            if stash_insert(stash_buckets, target, neighbor, key, value, meta_hash) {
+                Ok(4)
+            } else {
+                Err(TableError::TableFull)
             }
         }
     }
@@ -366,31 +707,22 @@ impl<T: PartialEq + Debug + Clone> Table<T> {
     Returns boolean True - Success, False - Failure
     */
     fn next_displace(
-        target: &mut Bucket<T>,
-        neighbor: &mut Bucket<T>,
-        key: Key<T>,
-        value: ValueT,
+        target: &mut Bucket<K, V>,
+        neighbor: &mut Bucket<K, V>,
+        key: &K,
+        value: &V,
         meta_hash: u8,
     ) -> bool {
         let displace_index: i32 = target.find_org_displacement();
         if get_count(neighbor.bitmap) != K_NUM_PAIR_PER_BUCKET && displace_index != -1 {
-            let neighbor_pair: Pair<T> = target.pairs[displace_index as usize]
-                .clone()
-                .unwrap()
-                .clone();
-            return match neighbor.insert(
-                neighbor_pair.key,
-                neighbor_pair.value,
-                target.finger_array[displace_index as usize],
-                true,
-            ) {
-                Ok(_) => {
-                    target.unset_hash(displace_index as u32);
-                    target.insert_displace(key, value, meta_hash, displace_index, true);
-                    true
-                }
-                Err(_) => false,
-            };
+            let finger = target.finger_array[displace_index as usize];
+            // Moved instead of cloned and dropped, a reader may still clone it from a copy of the bucket
+            let displaced = target.take(displace_index as u32);
+            neighbor
+                .insert(displaced.key, displaced.value, finger, true)
+                .expect("the neighbor has a free slot");
+            target.insert_displace(key.clone(), value.clone(), meta_hash, displace_index, true);
+            return true;
         }
         false
     }
@@ -401,313 +733,663 @@ impl<T: PartialEq + Debug + Clone> Table<T> {
     The only difference is we pass Probe as false to prev_neighbor bucket which defines we store the pair in extra slots other than 14
      */
     pub fn prev_displace(
-        target: &mut Bucket<T>,
-        prev_neighbor: &mut Bucket<T>,
-        key: Key<T>,
-        value: ValueT,
+        target: &mut Bucket<K, V>,
+        prev_neighbor: &mut Bucket<K, V>,
+        key: &K,
+        value: &V,
         meta_hash: u8,
     ) -> bool {
         let displace_index = target.find_probe_displacement();
         if get_count(prev_neighbor.bitmap) != K_NUM_PAIR_PER_BUCKET && displace_index != -1 {
-            let neighbor_pair: Pair<T> = target.pairs[displace_index as usize]
-                .clone()
-                .unwrap()
-                .clone();
-            return match prev_neighbor.insert(
-                neighbor_pair.key,
-                neighbor_pair.value,
-                target.finger_array[displace_index as usize],
-                false,
-            ) {
-                Ok(_) => {
-                    target.unset_hash(displace_index as u32);
-                    target.insert_displace(key, value, meta_hash, displace_index, false);
-                    true
-                }
-                Err(_) => false,
-            };
+            let finger = target.finger_array[displace_index as usize];
+            // Moved like in `next_displace`
+            let displaced = target.take(displace_index as u32);
+            prev_neighbor
+                .insert(displaced.key, displaced.value, finger, false)
+                .expect("the previous neighbor has a free slot");
+            target.insert_displace(key.clone(), value.clone(), meta_hash, displace_index, false);
+            return true;
         }
         false
     }
 
-    pub fn search(&mut self, key: &Key<T>, key_hash: usize, meta_hash: u8) -> Option<ValueT> {
-        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, BUCKET_MASK);
-
-        let buckets_ptr = self.bucket.as_mut_ptr();
-        unsafe {
-            let target = &*buckets_ptr.add(bucket_index);
-
-            let mut value: ValueT = vec![];
-            if target.check_and_get(meta_hash, key, false, &mut value) {
-                return Some(value);
-            }
-            let neighbor = &*buckets_ptr.add((bucket_index + 1) & BUCKET_MASK);
-            if neighbor.check_and_get(meta_hash, key, true, &mut value) {
-                return Some(value);
-            }
-            for i in 0..K_STASH_BUCKET {
-                let current_stash_bucket = &*buckets_ptr.add(K_NUM_BUCKET + i);
-                if current_stash_bucket.check_and_get(meta_hash, key, false, &mut value) {
-                    return Some(value);
+    /**
+    Looks the key up without taking any lock, and returns a clone of its value.
+    The versions of the target, neighbor and stash buckets are read before copying the buckets and validated
+    afterwards, the copies are only probed once they are known to be a state the buckets were in together.
+    The stash buckets are only copied when the target bucket overflowed into them.
+    A pair removed or replaced after the copies is retired to the epoch collector instead of being dropped,
+    so `guard` has to pin the collector the writers retire to for as long as the value is cloned.
+    */
+    pub fn search(&self, key: &K, key_hash: usize, meta_hash: u8, _guard: &Guard<'_>) -> Option<V> {
+        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, Self::BUCKET_MASK);
+        let target = self.bucket(bucket_index);
+        let neighbor = self.bucket((bucket_index + 1) & Self::BUCKET_MASK);
+        let stash = self.stash();
+        'retry: loop {
+            let (Some(target_version), Some(neighbor_version)) =
+                (target.read_version(), neighbor.read_version())
+            else {
+                spin_loop();
+                continue;
+            };
+            let mut stash_versions = [0; STASH];
+            for (i, stash_bucket) in stash.iter().enumerate() {
+                match stash_bucket.read_version() {
+                    Some(version) => stash_versions[i] = version,
+                    None => {
+                        spin_loop();
+                        continue 'retry;
+                    }
                 }
             }
+            let target_copy = target.snapshot();
+            let neighbor_copy = neighbor.snapshot();
+            if target.version_changed(target_version) || neighbor.version_changed(neighbor_version)
+            {
+                continue;
+            }
+            // SAFETY: No writer locked the buckets during the copies
+            let (target_copy, neighbor_copy) = unsafe {
+                (
+                    target_copy.assume_init_ref(),
+                    neighbor_copy.assume_init_ref(),
+                )
+            };
+            let value = target_copy
+                .check_and_get(meta_hash, key, false)
+                .or_else(|| neighbor_copy.check_and_get(meta_hash, key, true));
+            if value.is_some() || !target_copy.test_stash_check() {
+                return value.cloned();
+            }
+            let stash_copies = stash.map(Bucket::snapshot);
+            // The target and neighbor are validated again, so the stash is read in the same window
+            if target.version_changed(target_version)
+                || neighbor.version_changed(neighbor_version)
+                || stash
+                    .iter()
+                    .zip(stash_versions)
+                    .any(|(stash_bucket, version)| stash_bucket.version_changed(version))
+            {
+                continue;
+            }
+            return stash_copies.iter().find_map(|stash_copy| {
+                // SAFETY: No writer locked the stash buckets during the copies
+                unsafe { stash_copy.assume_init_ref() }
+                    .check_and_get(meta_hash, key, false)
+                    .cloned()
+            });
         }
-        None
     }
 
-    pub fn delete(
-        &mut self,
-        key: &Key<T>,
+    /**
+    Deletes the key from the target, neighbor or stash buckets while holding the target and neighbor locks.
+    A key found in a stash bucket also clears the overflow indicator its insertion left in the target or neighbor.
+    The removed pair is retired with `guard`, a reader may still clone it from a copy of its bucket.
+    */
+    pub fn delete<P: Persist>(
+        &self,
+        key: &K,
         key_hash: usize,
         meta_hash: u8,
-    ) -> Result<(), BucketError> {
-        let bucket_index = bucket_index(key_hash, K_FINGER_BITS, BUCKET_MASK);
-
-        let buckets_ptr = self.bucket.as_mut_ptr();
+        persist: &P,
+        guard: &Guard<'_>,
+    ) -> Result<(), TableError> {
+        self.delete_if(key, key_hash, meta_hash, persist, guard, |_| true)
+            .map(|_| ())
+    }
+    /**
+    Deletes the key like `delete` if `predicate` accepts its value, under the same locks.
+    Returns whether the key was deleted, or fails with `ItemDoesntExist` when the key is absent.
+    */
+    pub fn delete_if<P: Persist>(
+        &self,
+        key: &K,
+        key_hash: usize,
+        meta_hash: u8,
+        persist: &P,
+        guard: &Guard<'_>,
+        predicate: impl FnOnce(&V) -> bool,
+    ) -> Result<bool, TableError> {
+        let mut locks = self.lock_buckets(key_hash)?;
+        let bucket_index = locks.index;
+        let neighbor_index = (bucket_index + 1) & Self::BUCKET_MASK;
+        // SAFETY: The target and neighbor locks are held, and the stash lock once the key is found there
         unsafe {
-            let target = &mut *buckets_ptr.add(bucket_index);
-            target.get_lock();
-            match target.delete(key, meta_hash, false) {
-                Ok(_) => {
-                    target.release_lock();
-                    return Ok(());
-                }
-                Err(err) => match err {
-                    BucketError::KeyDoesNotExist => {}
-                    _ => {
-                        target.release_lock();
-                        return Err(err);
-                    }
-                },
-            };
-            target.release_lock();
-            let neighbor = &mut *buckets_ptr.add((bucket_index + 1) & BUCKET_MASK);
-            neighbor.get_lock();
-            match neighbor.delete(key, meta_hash, true) {
-                Ok(_) => {
-                    neighbor.release_lock();
-                    return Ok(());
-                }
-                Err(err) => match err {
-                    BucketError::KeyDoesNotExist => {}
-                    _ => {
-                        neighbor.release_lock();
-                        return Err(err);
-                    }
-                },
+            let Some((index, slot)) = self.find_locked(&mut locks, key, meta_hash) else {
+                return Err(TableError::ItemDoesntExist);
             };
-            neighbor.release_lock();
-            for i in 0..K_STASH_BUCKET {
-                let current_stash_bucket = &mut *buckets_ptr.add(K_NUM_BUCKET + i);
-                current_stash_bucket.get_lock();
-                match current_stash_bucket.delete(&key, meta_hash, false) {
-                    Ok(_) => {
-                        current_stash_bucket.release_lock();
-                        return Ok(());
-                    }
-                    Err(err) => match err {
-                        BucketError::KeyDoesNotExist => {}
-                        _ => {
-                            current_stash_bucket.release_lock();
-                            return Err(err);
-                        }
-                    },
-                };
-                current_stash_bucket.release_lock();
+            let bucket = self.bucket_mut(index);
+            if !predicate(&bucket.pair(slot).unwrap().value) {
+                return Ok(false);
             }
+            let removed = bucket.take(slot);
+            self.persist_bucket(index, persist);
+            retire(guard, removed);
+            self.count.fetch_sub(1, Relaxed);
+            if index >= BUCKETS {
+                let target = self.bucket_mut(bucket_index);
+                let neighbor = self.bucket_mut(neighbor_index);
+                target.unset_indicator(meta_hash, neighbor, (index - BUCKETS) as u64);
+                self.persist_bucket(bucket_index, persist);
+                self.persist_bucket(neighbor_index, persist);
+            }
+            Ok(true)
         }
-        Err(BucketError::KeyDoesNotExist)
     }
     /**
-    This assumes the locks for all the buckets of this table are acquired
-    1. Increments the pattern for auditing the change
-    2. Creates a new table to divide the existing table to 2 parts.
-    3. Rehash each entry in all the buckets to find the new positions in new table
-    4.
+    This assumes the locks for all the buckets of this table are acquired.
+    The first half of a split, which leaves this table untouched so it can be rolled back:
+    1. Persists the `Splitting` state of this table.
+    2. Gives the empty `next_table` the pattern `(pattern << 1) + 1` and the `NewTable` state.
+    3. Rehashes each entry in all the buckets and copies the ones matching the new pattern to the new table,
+       which is persisted at the end.
+
+    The split is committed once a directory pointing to the new table is persisted, then `complete_split` removes
+    the copied entries from this table. A crash in between is resolved by `recover`.
+    The first bucket of the new table stays locked, the caller releases it once the table is installed in the directory.
     */
-    pub fn split(&mut self, origin_key_hash: usize) -> Result<Table<T>, SplitError> {
-        let new_pattern = (self.pattern << 1) + 1;
-        let old_pattern = self.pattern << 1;
-        self.state = Arc::from(TableState::Splitting);
-        let mut next_table: Table<T> = Table::new(new_pattern);
-        next_table.local_depth = self.local_depth + 1;
-        next_table.state = Arc::from(TableState::Splitting);
+    pub fn split<S: BuildHasher, P: Persist>(
+        &self,
+        next_table: &mut Self,
+        hash_builder: &S,
+        persist: &P,
+    ) -> Result<(), SplitError> {
+        let local_depth = self.local_depth();
+        let new_pattern = (self.pattern() << 1) + 1;
+        self.persist_state(TableState::Splitting, persist);
+        debug_assert!(next_table.is_empty());
+        *next_table.local_depth.get_mut() = local_depth + 1;
+        *next_table.pattern.get_mut() = new_pattern;
+        next_table.set_state(TableState::NewTable);
 
         // Getting the lock of the first bucket to make sure the new table does not get split in between
-        next_table.bucket[0].get_lock();
+        next_table.bucket(0).get_lock();
 
-        let key_hash;
-        let mut invalid_buckets: Vec<u32> = vec![];
-        for i in 0..K_NUM_BUCKET {
-            let current_bucket = &mut self.bucket[i];
+        for (i, current_bucket) in self.buckets().enumerate() {
             let mask = get_bitmap(current_bucket.bitmap);
-            let mut invalid_mask = 0;
             for j in 0..K_NUM_PAIR_PER_BUCKET {
-                if check_bit_32(mask, j) {
-                    let current_pair: Pair<T> = current_bucket.pairs[j].unwrap();
-                    if current_pair.key.is_pointer {
-                        key_hash = calculate_hash(&current_pair.key);
-                    } else {
-                        key_hash = calculate_hash(&current_pair.key.key);
-                    }
-                    // FIXME: Verify if this is working as needed
-                    if (key_hash >> (8 * size_of::<usize>() - self.local_depth - 1)) == new_pattern
-                    {
-                        invalid_mask = invalid_mask | (1 << j);
-                        match next_table.insert_4_split(
-                            &current_pair.key,
-                            &current_pair.value,
-                            key_hash,
-                            current_bucket.finger_array[j],
-                        ) {
-                            Ok(_) => {}
-                            Err(_) => {
-                                println!(
-                                    "Some error occurred while splitting {:?} in pair {:?}",
-                                    current_bucket, current_pair.value
-                                );
-                                return Err(SplitError::InternalError(format!(
-                                    "Some error occurred while splitting {:?} in pair {:?}",
-                                    current_bucket, current_pair.value
-                                )));
-                            }
-                        }
-                    }
+                if !check_bit_32(mask, j) {
+                    continue;
+                }
+                let current_pair: &Pair<K, V> = current_bucket.pair(j).unwrap();
+                let key_hash = key_hash(&current_pair.key, hash_builder);
+                if segment_pattern(key_hash, local_depth + 1) != new_pattern {
+                    continue;
+                }
+                let meta_hash = current_bucket.finger_array[j as usize];
+                if next_table
+                    .insert_4_split(&current_pair.key, &current_pair.value, key_hash, meta_hash)
+                    .is_err()
+                {
+                    let message = format!(
+                        "Some error occurred while splitting bucket {} for key {:?}",
+                        i, current_pair.key
+                    );
+                    // Nothing was removed from this table, the split is rolled back
+                    self.persist_state(TableState::Normal, persist);
+                    return Err(SplitError::InternalError(message));
                 }
             }
-            invalid_buckets.append(invalid_mask);
         }
+        next_table.recount();
+        next_table.persist(persist);
+        Ok(())
+    }
+    /**
+    The second half of a split, once the directory pointing to `next_table` is persisted.
+    1. Invalidates the entries copied to `next_table`, clearing the overflow indicators of the stash entries.
+    2. Increments the local depth of this table and shifts its pattern to `pattern << 1`.
+    3. Persists this table, then `next_table`, in the `Normal` state.
 
-        // Splitting the values stored in Stash Buckets
-        for i in K_NUM_BUCKET..K_NUM_BUCKET + K_STASH_BUCKET {
-            let curr_stash_bucket = &mut self.bucket[i];
-            let mask = get_bitmap(curr_stash_bucket);
-            let mut invalid_mask = 0;
+    The invalidated pairs are retired with `guard`, readers that copied a bucket before the split may still clone them.
+    */
+    pub fn complete_split<S: BuildHasher, P: Persist>(
+        &self,
+        next_table: &Self,
+        hash_builder: &S,
+        persist: &P,
+        guard: &Guard<'_>,
+    ) {
+        let local_depth = self.local_depth();
+        let new_pattern = (self.pattern() << 1) + 1;
+        let old_pattern = self.pattern() << 1;
+        for i in 0..BUCKETS + STASH {
+            let mask = get_bitmap(self.bucket(i).bitmap);
             for j in 0..K_NUM_PAIR_PER_BUCKET {
-                if check_bit_32(mask, j) {
-                    let current_pair: Pair<T> = curr_stash_bucket.pairs[j].unwrap();
-                    if current_pair.key.is_pointer {
-                        key_hash = calculate_hash(&current_pair.key);
-                    } else {
-                        key_hash = calculate_hash(&current_pair.key.key);
+                if !check_bit_32(mask, j) {
+                    continue;
+                }
+                // SAFETY: The caller holds the locks of all the buckets
+                let current_bucket = unsafe { self.bucket_mut(i) };
+                let current_pair: &Pair<K, V> = current_bucket.pair(j).unwrap();
+                let key_hash = key_hash(&current_pair.key, hash_builder);
+                if segment_pattern(key_hash, local_depth + 1) != new_pattern {
+                    continue;
+                }
+                if i >= BUCKETS {
+                    // The entry lived in a stash bucket, so its home bucket carries an overflow indicator for it
+                    let meta_hash = current_bucket.finger_array[j as usize];
+                    let bucket_ix = bucket_index(key_hash, K_FINGER_BITS, Self::BUCKET_MASK);
+                    unsafe {
+                        let target = self.bucket_mut(bucket_ix);
+                        let neighbor = self.bucket_mut((bucket_ix + 1) & Self::BUCKET_MASK);
+                        target.unset_indicator(meta_hash, neighbor, (i - BUCKETS) as u64);
                     }
-                    // FIXME: Verify if this is working as needed
-                    if (key_hash >> (8 * size_of::<usize>() - self.local_depth - 1)) == new_pattern
-                    {
-                        invalid_mask = invalid_mask | (1 << j);
-                        match next_table.insert_4_split(
-                            &current_pair.key,
-                            &current_pair.value,
-                            key_hash,
-                            curr_stash_bucket.finger_array[j],
-                        ) {
-                            Ok(_) => {
-                                let bucket_ix = bucket_index(key_hash, K_FINGER_BITS, BUCKET_MASK);
-                                let buckets_ptr = self.bucket.as_mut_ptr();
-                                unsafe {
-                                    let target = &mut *buckets_ptr.add(bucket_ix);
-                                    let neighbor =
-                                        &mut *buckets_ptr.add((bucket_ix + 1) & BUCKET_MASK);
-                                    target.get_lock();
-                                    neighbor.get_lock();
-                                    target.unset_indicator(
-                                        curr_stash_bucket.finger_array[j],
-                                        neighbor,
-                                        i,
-                                    );
-                                    target.release_lock();
-                                    neighbor.release_lock();
-                                }
-                            }
-                            Err(_) => {
-                                println!(
-                                    "Some error occurred while splitting {:?} in pair {:?}",
-                                    curr_stash_bucket, current_pair.value
-                                );
-                                return Err(SplitError::InternalError(format!(
-                                    "Some error occurred while splitting {:?} in pair {:?}",
-                                    curr_stash_bucket, current_pair.value
-                                )));
-                            }
-                        }
+                }
+                retire(guard, current_bucket.take(j));
+            }
+        }
+        self.recount();
+        self.local_depth.store(local_depth + 1, Release);
+        self.pattern.store(old_pattern, Release);
+        self.set_state(TableState::Normal);
+        self.persist(persist);
+        next_table.persist_state(TableState::Normal, persist);
+    }
+    /**
+    This assumes the locks for all the buckets of both tables are acquired.
+    Fills the empty `merged_table` with the entries of this table and its buddy, so it covers the hash range of both,
+    i.e. the inverse of `Table::split`.
+    Both tables are left untouched, so the merge can be abandoned if the entries do not fit in one table.
+    */
+    pub fn merge<S: BuildHasher, P: Persist>(
+        &self,
+        buddy: &Self,
+        merged_table: &mut Self,
+        hash_builder: &S,
+        persist: &P,
+    ) -> Result<(), TableError> {
+        assert_eq!(self.local_depth(), buddy.local_depth());
+        assert_eq!(self.pattern() ^ 1, buddy.pattern());
+        self.set_state(TableState::Merging);
+        buddy.set_state(TableState::Merging);
+        debug_assert!(merged_table.is_empty());
+        *merged_table.local_depth.get_mut() = self.local_depth() - 1;
+        *merged_table.pattern.get_mut() = self.pattern() >> 1;
+        let mut response = Ok(0);
+        'tables: for table in [self, buddy] {
+            for current_bucket in table.buckets() {
+                let mask = get_bitmap(current_bucket.bitmap);
+                for j in 0..K_NUM_PAIR_PER_BUCKET {
+                    if !check_bit_32(mask, j) {
+                        continue;
+                    }
+                    let current_pair: &Pair<K, V> = current_bucket.pair(j).unwrap();
+                    response = merged_table.insert_4_split(
+                        &current_pair.key,
+                        &current_pair.value,
+                        key_hash(&current_pair.key, hash_builder),
+                        current_bucket.finger_array[j as usize],
+                    );
+                    if response.is_err() {
+                        break 'tables;
+                    }
+                }
+            }
+        }
+        if let Err(err) = response {
+            self.set_state(TableState::Normal);
+            buddy.set_state(TableState::Normal);
+            return Err(err);
+        }
+        merged_table.recount();
+        merged_table.persist(persist);
+        Ok(())
+    }
+    /**
+    Returns the number of entries stored in the normal and stash buckets of the table
+    */
+    pub fn len(&self) -> usize {
+        self.count.load(Relaxed)
+    }
+    /**
+    Counts the entries in the allocation bitmaps, which are authoritative after a crash while `count` may be stale.
+    */
+    pub(crate) fn count_entries(&self) -> usize {
+        self.buckets()
+            .map(|bucket| get_count(bucket.bitmap) as usize)
+            .sum()
+    }
+    /**
+    Resets `count` to the entries in the bitmaps, once a split, a merge or a recovery rewrote the table
+    under all its bucket locks.
+    */
+    fn recount(&self) {
+        self.count.store(self.count_entries(), Relaxed);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /**
+    Copies the entries of the normal and stash buckets, walking their allocation bitmaps.
+    Like `search`, the versions of every bucket are read before and validated after copying the buckets, which is
+    retried until the copies are a state the table was in even while inserts displace entries between buckets,
+    and `guard` keeps the pairs removed meanwhile alive while they are cloned.
+    */
+    pub(crate) fn entries(&self, _guard: &Guard<'_>) -> Vec<(K, V)> {
+        let mut versions = Vec::with_capacity(BUCKETS + STASH);
+        'retry: loop {
+            versions.clear();
+            for bucket in self.buckets() {
+                match bucket.read_version() {
+                    Some(version) => versions.push(version),
+                    None => {
+                        spin_loop();
+                        continue 'retry;
                     }
                 }
             }
-            invalid_buckets.append(invalid_mask);
+            let copies: Vec<_> = self.buckets().map(Bucket::snapshot).collect();
+            if self
+                .buckets()
+                .zip(&versions)
+                .any(|(bucket, version)| bucket.version_changed(*version))
+            {
+                continue;
+            }
+            return copies
+                .iter()
+                .flat_map(|copy| {
+                    // SAFETY: No writer locked the buckets during the copies
+                    let bucket = unsafe { copy.assume_init_ref() };
+                    let mask = get_bitmap(bucket.bitmap);
+                    (0..K_NUM_PAIR_PER_BUCKET)
+                        .filter(move |slot| check_bit_32(mask, *slot))
+                        .filter_map(|slot| bucket.pair(slot))
+                        .map(|pair| (pair.key.clone(), pair.value.clone()))
+                })
+                .collect();
+        }
+    }
+    /**
+    Returns copies of the entries of the table, see `entries`.
+    */
+    pub fn iter(&self, guard: &Guard<'_>) -> std::vec::IntoIter<(K, V)> {
+        self.entries(guard).into_iter()
+    }
+    pub fn keys(&self, guard: &Guard<'_>) -> impl Iterator<Item = K> {
+        self.iter(guard).map(|(key, _)| key)
+    }
+    pub fn values(&self, guard: &Guard<'_>) -> impl Iterator<Item = V> {
+        self.iter(guard).map(|(_, value)| value)
+    }
+    /**
+    Returns true if the table was created or recovered since the last crash, i.e. it needs no `recover`.
+    */
+    pub(crate) fn recovered(&self, crash_version: u64) -> bool {
+        self.number.load(Acquire) == crash_version
+    }
+    /**
+    Recovers the table the first time it is touched after a crash, instead of scanning the whole map on open.
+    The directory is authoritative, `local_depth` and `pattern` are the ones its entries give the table:
+    1. Resets the bucket locks held by the crashed process.
+    2. Resolves a split interrupted by the crash, the persisted directory telling whether it was committed.
+       A `Splitting` table still holds the entries copied to the new segment: they are dropped when the directory
+       gives the table the deeper pattern (roll forward), otherwise the table keeps all of them (roll back).
+       A `NewTable` is only reachable once the directory of its split was persisted, so it is kept as it is.
+    3. Drops the second copy of the entries a displacement had persisted in two buckets.
+    4. Rebuilds the overflow metadata of the normal buckets from the entries of the stash buckets.
+    5. Persists the table and only then records the `crash_version`, so a crash during the recovery restarts it.
+
+    `lock_bit` holds the crash version of the thread recovering the table, the other threads touching it wait
+    for the recovery to finish. A `lock_bit` left by a process that crashed while recovering holds an older version.
+    Returns the number of dropped entries.
+    */
+    pub(crate) fn recover<S: BuildHasher, P: Persist>(
+        &self,
+        crash_version: u64,
+        local_depth: usize,
+        pattern: usize,
+        hash_builder: &S,
+        persist: &P,
+    ) -> usize {
+        loop {
+            if self.recovered(crash_version) {
+                return 0;
+            }
+            let current = self.lock_bit.load(Acquire);
+            if current != crash_version
+                && self
+                    .lock_bit
+                    .compare_exchange(current, crash_version, Acquire, Relaxed)
+                    .is_ok()
+            {
+                break;
+            }
+            spin_loop();
+        }
+        if self.recovered(crash_version) {
+            return 0;
+        }
+        for bucket in self.buckets() {
+            bucket.reset_lock();
+        }
+        debug_assert!(
+            self.state() != TableState::NewTable || self.local_depth() == local_depth,
+            "a new table is only reachable once its split is committed"
+        );
+        self.local_depth.store(local_depth, Release);
+        self.pattern.store(pattern, Release);
+        self.set_state(TableState::Normal);
+        let mut dropped = 0;
+        for i in 0..BUCKETS + STASH {
+            // SAFETY: Every other thread waits for the recovery before touching the table
+            let current_bucket = unsafe { self.bucket_mut(i) };
+            let mask = get_bitmap(current_bucket.bitmap);
+            for j in 0..K_NUM_PAIR_PER_BUCKET {
+                if !check_bit_32(mask, j) {
+                    continue;
+                }
+                let current_pair = current_bucket.pair(j).unwrap();
+                if segment_pattern(key_hash(&current_pair.key, hash_builder), local_depth)
+                    != pattern
+                {
+                    current_bucket.remove(j);
+                    dropped += 1;
+                }
+            }
         }
-        // Invalidating the entries in target
-        for i in 0..K_NUM_BUCKET + K_STASH_BUCKET {
-            let current_bucket = &mut self.bucket[i];
-            current_bucket.get_lock();
-            current_bucket.bitmap = current_bucket.bitmap
-                & (!(invalid_buckets[i] << 18))
-                & (!(invalid_buckets[i] << 4));
-            current_bucket.bitmap -= invalid_buckets[i].count_ones();
-            current_bucket.release_lock();
+        // A displacement persists the bucket receiving the entry before the one it leaves. The entry left
+        // is always owned by its bucket, the copy is a probing entry of the next bucket and is dropped
+        for i in 0..BUCKETS {
+            let (current_bucket, neighbor) =
+                unsafe { (self.bucket(i), self.bucket_mut((i + 1) & Self::BUCKET_MASK)) };
+            let mask = get_bitmap(current_bucket.bitmap) & !get_member(current_bucket.bitmap);
+            for j in 0..K_NUM_PAIR_PER_BUCKET {
+                if !check_bit_32(mask, j) {
+                    continue;
+                }
+                let current_pair = current_bucket.pair(j).unwrap();
+                let finger = current_bucket.finger_array[j as usize];
+                if neighbor.delete(&current_pair.key, finger, true).is_ok() {
+                    dropped += 1;
+                }
+            }
         }
-        next_table.pattern = new_pattern;
-        Ok(next_table)
+        // An update relocating a pair out of a full bucket persists the copy before removing the old pair, see
+        // `relocate_locked`. A stash entry whose key is also in its target, its neighbor or an earlier stash bucket
+        // is one of the two and is dropped
+        for i in BUCKETS..BUCKETS + STASH {
+            let stash_bucket = unsafe { self.bucket_mut(i) };
+            let mask = get_bitmap(stash_bucket.bitmap);
+            for j in 0..K_NUM_PAIR_PER_BUCKET {
+                if !check_bit_32(mask, j) {
+                    continue;
+                }
+                let current_pair = stash_bucket.pair(j).unwrap();
+                let finger = stash_bucket.finger_array[j as usize];
+                let bucket_ix = bucket_index(
+                    key_hash(&current_pair.key, hash_builder),
+                    K_FINGER_BITS,
+                    Self::BUCKET_MASK,
+                );
+                let copied = self
+                    .bucket(bucket_ix)
+                    .find(finger, &current_pair.key, false)
+                    .is_some()
+                    || self
+                        .bucket((bucket_ix + 1) & Self::BUCKET_MASK)
+                        .find(finger, &current_pair.key, true)
+                        .is_some()
+                    || (BUCKETS..i).any(|k| {
+                        self.bucket(k)
+                            .find(finger, &current_pair.key, false)
+                            .is_some()
+                    });
+                if copied {
+                    stash_bucket.remove(j);
+                    dropped += 1;
+                }
+            }
+        }
+        for i in 0..BUCKETS {
+            unsafe { self.bucket_mut(i) }.reset_overflow_fp();
+        }
+        for i in 0..STASH {
+            let stash_bucket = self.bucket(BUCKETS + i);
+            let mask = get_bitmap(stash_bucket.bitmap);
+            for j in 0..K_NUM_PAIR_PER_BUCKET {
+                if !check_bit_32(mask, j) {
+                    continue;
+                }
+                let current_pair = stash_bucket.pair(j).unwrap();
+                let bucket_ix = bucket_index(
+                    key_hash(&current_pair.key, hash_builder),
+                    K_FINGER_BITS,
+                    Self::BUCKET_MASK,
+                );
+                unsafe {
+                    let target = self.bucket_mut(bucket_ix);
+                    let neighbor = self.bucket_mut((bucket_ix + 1) & Self::BUCKET_MASK);
+                    target.set_indicator(stash_bucket.finger_array[j as usize], neighbor, i as u8);
+                }
+            }
+        }
+        self.recount();
+        self.persist(persist);
+        self.number.store(crash_version, Release);
+        persist.persist(
+            &self.number as *const AtomicU64 as *const u8,
+            size_of::<AtomicU64>(),
+        );
+        dropped
+    }
+}
+/**
+The locks of the target and neighbor buckets of a key, see `Table::lock_buckets`, and of the stash once
+`Table::find_locked` found the key there. Dropping it releases them, also when a closure passed to `update`
+or a `Clone` of the keys or values panics while they are held.
+*/
+struct BucketLocks<'a, K: PartialEq + Debug + Clone, V: Clone> {
+    index: usize,
+    _target: BucketGuard<'a, K, V>,
+    _neighbor: BucketGuard<'a, K, V>,
+    stash: Option<BucketGuard<'a, K, V>>,
+}
+// SAFETY: The buckets are only mutated through `bucket_mut`, under the bucket locks or with exclusive access.
+// Readers clone pairs from copies taken without the locks only once the bucket versions show no writer touched
+// them, and the writers retire the pairs they unlink, so a reader never sees a pair being written or dropped
+unsafe impl<
+        K: PartialEq + Debug + Clone + Send + Sync,
+        V: Clone + Send + Sync,
+        const BUCKETS: usize,
+        const STASH: usize,
+    > Sync for Table<K, V, BUCKETS, STASH>
+{
+}
+/**
+Drops a pair or a value unlinked from its bucket once no reader can clone it anymore. Readers take no lock and may
+have copied the bucket before it was unlinked, so it is retired to the epoch collector they are pinned on.
+Types without drop glue free nothing and are dropped right away.
+*/
+fn retire<T>(guard: &Guard<'_>, unlinked: T) {
+    if needs_drop::<T>() {
+        // SAFETY: The object was unlinked under the bucket lock, readers pinning later cannot find it. The map is only
+        // `Send` and `Sync` for keys and values that are, otherwise the collector drops it on the only thread
+        unsafe { guard.retire(Box::into_raw(Box::new(unlinked))) };
     }
 }
+/**
+Hashes the key the same way `ExtendableHashing` does, to rehash the entries when a table is split or merged.
+*/
+fn key_hash<K: std::hash::Hash, S: BuildHasher>(key: &K, hash_builder: &S) -> usize {
+    hash_builder.hash_one(key) as usize
+}
+/**
+Returns the `depth` most significant bits of the hash, i.e. the pattern of the segment owning it at that depth.
+*/
+pub fn segment_pattern(hash: usize, depth: usize) -> usize {
+    hash.checked_shr((usize::BITS as usize - depth) as u32)
+        .unwrap_or(0)
+}
 pub fn bucket_index(hash: usize, finger_bits: usize, bucket_mask: usize) -> usize {
     // We do the finger_bits right shift because we use that last 8 bits for finger-print.
     (hash >> finger_bits) & bucket_mask
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::extendable_hashing::bucket::meta_hash;
-    use crate::extendable_hashing::table::Table;
-    use crate::extendable_hashing::{K_MASK, K_NUM_BUCKET, K_STASH_BUCKET};
-    use crate::utils::hashing::calculate_hash;
-    use crate::utils::pair::Key;
+    use crate::extendable_hashing::bucket::{get_count, meta_hash, K_NUM_PAIR_PER_BUCKET};
+    use crate::extendable_hashing::table::{
+        bucket_index, segment_pattern, Table, TableError, TableState,
+    };
+    use crate::extendable_hashing::{K_FINGER_BITS, K_MASK, K_NUM_BUCKET, K_STASH_BUCKET};
+    use crate::hash::ValueT;
+    use crate::pm::{Persist, Volatile};
+    use crate::utils::epoch::Collector;
+    use crate::utils::hashing::{calculate_hash, DashBuildHasher};
     use std::collections::HashSet;
     use std::io;
     use std::io::Write;
+    use std::sync::atomic::Ordering::Relaxed;
     use std::time::SystemTime;
 
+    /**
+    Makes the tables built on the heap behave as if they lived in a pool, without writing the stores anywhere.
+    */
+    struct Durable;
+    impl Persist for Durable {
+        fn flush(&self, _ptr: *const u8, _len: usize) {}
+        fn fence(&self) {}
+    }
+
     #[test]
     pub fn test_new_table() {
-        let table = Table::<i32>::new(0);
-        assert_eq!(table.bucket.len(), K_NUM_BUCKET + K_STASH_BUCKET);
-        assert_eq!(table.local_depth, 0);
-        assert_eq!(table.pattern, 0);
-        assert_eq!(table.number, 0);
+        let table = Table::<i32, ValueT>::new(0);
+        assert_eq!(table.buckets().count(), K_NUM_BUCKET + K_STASH_BUCKET);
+        assert_eq!(table.local_depth(), 0);
+        assert_eq!(table.pattern(), 0);
+        assert_eq!(table.number.load(Relaxed), 0);
     }
     #[test]
     pub fn test_acquire_locks() {
-        let mut table = Table::<i32>::new(0);
+        let table = Table::<i32, ValueT>::new(0);
         table.acquire_locks();
-        assert_eq!(
-            table.bucket[0..K_NUM_BUCKET]
-                .iter()
-                .map(|item| item.is_lock())
-                .all(|x| x),
-            true
-        );
+        assert!(table
+            .buckets()
+            .take(K_NUM_BUCKET)
+            .all(|item| item.is_lock()));
         table.release_locks();
-        assert_eq!(
-            table.bucket[0..K_NUM_BUCKET]
-                .iter()
-                .map(|item| item.is_lock())
-                .all(|x| !x),
-            true
-        );
+        assert!(table
+            .buckets()
+            .take(K_NUM_BUCKET)
+            .map(|item| item.is_lock())
+            .all(|x| !x));
     }
     #[test]
     pub fn test_insert_basic() {
-        let mut table = Table::<i32>::new(0);
-        let key = Key::new(&10);
+        let table = Table::<i32, ValueT>::new(0);
+        let key = 10;
         let value = String::from("Hello World");
-        let hash = calculate_hash(&key.key);
-        unsafe {
-            let res = table.insert(key, value.into_bytes(), hash, meta_hash(hash));
-            assert_eq!(res.unwrap(), 0);
-        }
+        let hash = calculate_hash(&key);
+        let res = table.insert(&key, &value.into_bytes(), hash, meta_hash(hash), &Volatile);
+        assert_eq!(res.unwrap(), 0);
     }
 
     #[test]
     pub fn test_insert_for_all_buckets() {
-        let mut table = Table::<i32>::new(0);
+        let table = Table::<i32, ValueT>::new(0);
         let value = String::from("Hello World");
         let mut target_bucket = 0;
         let mut neighbor_bucket = 0;
@@ -717,8 +1399,8 @@ mod tests {
         let mut failed_count = 0;
         let start_time = SystemTime::now();
         for i in 13000..14500 {
-            let key = Key::new(&i);
-            let hash = calculate_hash(&key.key);
+            let key = i;
+            let hash = calculate_hash(&key);
             let meta_hash = (hash & K_MASK) as u8;
             let value = value.clone();
             // let bucket_index = bucket_index(hash, K_FINGER_BITS, BUCKET_MASK);
@@ -730,31 +1412,29 @@ mod tests {
             //     "{:?} inserted in {} with meta_hash {}",
             //     key, bucket_index, meta_hash
             // );
-            unsafe {
-                let res = table.insert(key, value.into_bytes(), hash, meta_hash);
-                match res {
-                    Ok(ans) => match ans {
-                        0 => target_bucket += 1,
-                        1 => neighbor_bucket += 1,
-                        2 => next_neighbor_bucket += 1,
-                        3 => prev_neighbor_bucket += 1,
-                        4 => stash_bucket += 1,
-                        _ => {
-                            println!("Some other bucket")
-                        }
-                    },
-                    Err(err) => {
-                        // println!("Error occurred while inserting element {:?}", err);
-                        // println!(
-                        //     "target: {} \n neighbor: {} \n next: {} \n prev: {} \n stash:{}",
-                        //     target_bucket,
-                        //     neighbor_bucket,
-                        //     next_neighbor_bucket,
-                        //     prev_neighbor_bucket,
-                        //     stash_bucket
-                        // );
-                        failed_count += 1;
+            let res = table.insert(&key, &value.into_bytes(), hash, meta_hash, &Volatile);
+            match res {
+                Ok(ans) => match ans {
+                    0 => target_bucket += 1,
+                    1 => neighbor_bucket += 1,
+                    2 => next_neighbor_bucket += 1,
+                    3 => prev_neighbor_bucket += 1,
+                    4 => stash_bucket += 1,
+                    _ => {
+                        println!("Some other bucket")
                     }
+                },
+                Err(_err) => {
+                    // println!("Error occurred while inserting element {:?}", err);
+                    // println!(
+                    //     "target: {} \n neighbor: {} \n next: {} \n prev: {} \n stash:{}",
+                    //     target_bucket,
+                    //     neighbor_bucket,
+                    //     next_neighbor_bucket,
+                    //     prev_neighbor_bucket,
+                    //     stash_bucket
+                    // );
+                    failed_count += 1;
                 }
             }
         }
@@ -774,87 +1454,523 @@ mod tests {
 
     #[test]
     pub fn test_search_for_all_buckets() {
-        let mut table = Table::<i32>::new(0);
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, ValueT>::new(0);
         let value = String::from("Hello World");
-        let mut target_bucket = 0;
-        let mut neighbor_bucket = 0;
-        let mut next_neighbor_bucket = 0;
-        let mut prev_neighbor_bucket = 0;
-        let mut stash_bucket = 0;
-        let mut failed_count = 0;
         let mut inserted = HashSet::new();
-        let mut stash_inserted = HashSet::new();
         for i in 13000..14500 {
-            let key = Key::new(&i);
-            let hash = calculate_hash(&key.key);
+            let key = i;
+            let hash = calculate_hash(&key);
             let meta_hash = (hash & K_MASK) as u8;
             let value = value.clone();
-
-            let res = table.insert(key, value.into_bytes(), hash, meta_hash);
-            match &res {
-                Ok(ans) => match ans {
-                    0 => target_bucket += 1,
-                    1 => neighbor_bucket += 1,
-                    2 => next_neighbor_bucket += 1,
-                    3 => prev_neighbor_bucket += 1,
-                    4 => {
-                        stash_bucket += 1;
-                        stash_inserted.insert(i);
-                    }
-                    _ => {
-                        println!("Some other bucket")
-                    }
-                },
-                Err(err) => {
-                    failed_count += 1;
-                }
-            }
-            if res.is_ok() {
+            if table
+                .insert(&key, &value.into_bytes(), hash, meta_hash, &Volatile)
+                .is_ok()
+            {
                 inserted.insert(i);
             }
         }
         for i in 13000..14500 {
-            let key = Key::new(&i);
-            let hash = calculate_hash(&key.key);
+            let key = i;
+            let hash = calculate_hash(&key);
             let meta_hash = (hash & K_MASK) as u8;
             if inserted.contains(&i) {
-                assert!(table.search(&key, hash, meta_hash).is_some());
+                assert!(table.search(&key, hash, meta_hash, &guard).is_some());
             } else {
-                assert!(table.search(&key, hash, meta_hash).is_none());
+                assert!(table.search(&key, hash, meta_hash, &guard).is_none());
             }
         }
     }
 
     #[test]
     pub fn test_delete_for_all_buckets() {
-        let mut table = Table::<i32>::new(0);
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, ValueT>::new(0);
         let value = String::from("Hello World");
         let mut inserted = Vec::new();
         for i in 13000..14500 {
-            let key = Key::new(&i);
-            let hash = calculate_hash(&key.key);
+            let key = i;
+            let hash = calculate_hash(&key);
             let meta_hash = (hash & K_MASK) as u8;
             let value = value.clone();
-            let res = table.insert(key, value.into_bytes(), hash, meta_hash);
-            match &res {
-                Ok(_) => {
-                    inserted.push(i);
-                }
-                Err(_) => {}
+            let res = table.insert(&key, &value.into_bytes(), hash, meta_hash, &Volatile);
+            if res.is_ok() {
+                inserted.push(i);
             }
         }
-        for i in 0..(inserted.len() / 2) {
-            let key = Key::new(&inserted[i]);
-            let hash = calculate_hash(&key.key);
+        let (deleted, kept) = inserted.split_at(inserted.len() / 2);
+        for i in deleted {
+            let key = *i;
+            let hash = calculate_hash(&key);
+            let meta_hash = (hash & K_MASK) as u8;
+            assert!(table
+                .delete(&key, hash, meta_hash, &Volatile, &guard)
+                .is_ok());
+            assert!(table.search(&key, hash, meta_hash, &guard).is_none());
+        }
+        // The deleted pairs are only freed once the readers pinned meanwhile are done
+        assert_eq!(collector.pending(), deleted.len());
+        for i in kept {
+            let key = *i;
+            let hash = calculate_hash(&key);
             let meta_hash = (hash & K_MASK) as u8;
-            assert!(table.delete(&key, hash, meta_hash).is_ok());
-            assert!(table.search(&key, hash, meta_hash).is_none());
+            assert!(table.search(&key, hash, meta_hash, &guard).is_some());
         }
-        for i in (inserted.len() / 2)..inserted.len() {
-            let key = Key::new(&inserted[i]);
-            let hash = calculate_hash(&key.key);
+    }
+
+    #[test]
+    pub fn test_iter_walks_normal_and_stash_buckets() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, u64>::new(0);
+        let mut inserted = HashSet::new();
+        let mut stashed = 0;
+        for key in 13000..14500 {
+            let hash = calculate_hash(&key);
             let meta_hash = (hash & K_MASK) as u8;
-            assert!(table.search(&key, hash, meta_hash).is_some());
+            match table.insert(&key, &(key as u64 * 2), hash, meta_hash, &Volatile) {
+                Ok(4) => stashed += 1,
+                Ok(_) => {}
+                Err(_) => continue,
+            }
+            inserted.insert(key);
+        }
+        assert!(stashed > 0);
+        let entries: Vec<(i32, u64)> = table.iter(&guard).collect();
+        assert_eq!(entries.len(), table.len());
+        assert!(entries.iter().all(|(key, value)| *value == *key as u64 * 2));
+        assert_eq!(table.keys(&guard).collect::<HashSet<_>>(), inserted);
+        assert_eq!(
+            table.values(&guard).sum::<u64>(),
+            inserted.iter().map(|key| *key as u64 * 2).sum()
+        );
+    }
+
+    #[test]
+    pub fn test_update_and_upsert_in_all_buckets() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, u64>::new(0);
+        let mut inserted = Vec::new();
+        let mut stashed = 0;
+        for key in 13000..14500 {
+            let hash = calculate_hash(&key);
+            match table.insert(&key, &0, hash, meta_hash(hash), &Volatile) {
+                Ok(bucket) => {
+                    stashed += (bucket == 4) as usize;
+                    inserted.push(key);
+                }
+                Err(_) => break,
+            }
+        }
+        assert!(stashed > 0);
+        let len = table.len();
+        let full_slot = |key: &i32| {
+            let hash = calculate_hash(key);
+            let target = table.bucket(bucket_index(
+                hash,
+                K_FINGER_BITS,
+                Table::<i32, u64>::BUCKET_MASK,
+            ));
+            (get_count(target.bitmap) == K_NUM_PAIR_PER_BUCKET)
+                .then(|| target.find(meta_hash(hash), key, false))
+                .flatten()
+        };
+        let in_full: Vec<(i32, u32)> = inserted
+            .iter()
+            .filter_map(|key| full_slot(key).map(|slot| (*key, slot)))
+            .collect();
+        assert!(!in_full.is_empty());
+        for key in &inserted {
+            let hash = calculate_hash(key);
+            let result = table.update(key, hash, meta_hash(hash), &Volatile, &guard, |value| {
+                *value += *key as u64;
+                *value
+            });
+            assert_eq!(result.unwrap(), *key as u64);
+            let result = table.upsert(key, &0, hash, meta_hash(hash), &Volatile, &guard, |value| {
+                *value += 1;
+            });
+            assert_eq!(result.unwrap(), Some(()));
+            assert_eq!(
+                table.search(key, hash, meta_hash(hash), &guard),
+                Some(*key as u64 + 1)
+            );
+        }
+        // In DRAM the values of full buckets are replaced in place, updates never add or remove entries
+        for (key, slot) in &in_full {
+            assert_eq!(full_slot(key), Some(*slot));
+        }
+        assert_eq!(table.len(), len);
+        let hash = calculate_hash(&1);
+        assert!(matches!(
+            table.update(
+                &1,
+                hash,
+                meta_hash(hash),
+                &Volatile,
+                &guard,
+                |_| unreachable!()
+            ),
+            Err(TableError::ItemDoesntExist)
+        ));
+        let table = Table::<i32, u64>::new(0);
+        let upserted = table.upsert(
+            &1,
+            &5,
+            hash,
+            meta_hash(hash),
+            &Volatile,
+            &guard,
+            |_| unreachable!(),
+        );
+        assert_eq!(upserted.unwrap(), None);
+        assert_eq!(table.search(&1, hash, meta_hash(hash), &guard), Some(5));
+    }
+
+    #[test]
+    pub fn test_update_relocates_the_pairs_of_full_buckets_in_a_pool() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, u64>::new(0);
+        let mut inserted = Vec::new();
+        for key in 13000..14500 {
+            let hash = calculate_hash(&key);
+            if table
+                .insert(&key, &0, hash, meta_hash(hash), &Durable)
+                .is_err()
+            {
+                break;
+            }
+            inserted.push(key);
+        }
+        let len = table.len();
+        let mut relocated = 0;
+        for key in &inserted {
+            let hash = calculate_hash(key);
+            let target = table.bucket(bucket_index(
+                hash,
+                K_FINGER_BITS,
+                Table::<i32, u64>::BUCKET_MASK,
+            ));
+            let slot = (get_count(target.bitmap) == K_NUM_PAIR_PER_BUCKET)
+                .then(|| target.find(meta_hash(hash), key, false))
+                .flatten();
+            let mut called = false;
+            let result = table.update(key, hash, meta_hash(hash), &Durable, &guard, |value| {
+                called = true;
+                *value = *key as u64;
+            });
+            match result {
+                Ok(()) => {
+                    relocated += slot.is_some_and(|slot| target.pair(slot).is_none()) as usize
+                }
+                // The target, the neighbor and the stash buckets are all full
+                Err(TableError::TableFull) => {
+                    assert!(!called);
+                }
+                Err(err) => panic!("unexpected error {:?}", err),
+            }
+        }
+        assert!(relocated > 0);
+        // Every key is still stored once
+        assert_eq!(table.len(), len);
+        assert_eq!(table.count_entries(), len);
+        let entries: Vec<(i32, u64)> = table.iter(&guard).collect();
+        assert_eq!(entries.len(), len);
+        assert!(entries
+            .iter()
+            .all(|(key, value)| *value == 0 || *value == *key as u64));
+        // The overflow indicators of the relocated stash entries are consistent
+        for key in &inserted {
+            let hash = calculate_hash(key);
+            assert!(table
+                .delete(key, hash, meta_hash(hash), &Durable, &guard)
+                .is_ok());
+        }
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    pub fn test_compare_and_swap_and_delete_if_in_all_buckets() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, u64>::new(0);
+        let mut inserted = Vec::new();
+        let mut stashed = 0;
+        for key in 13000..14500 {
+            let hash = calculate_hash(&key);
+            match table.insert(&key, &(key as u64), hash, meta_hash(hash), &Volatile) {
+                Ok(bucket) => {
+                    stashed += (bucket == 4) as usize;
+                    inserted.push(key);
+                }
+                Err(_) => break,
+            }
+        }
+        assert!(stashed > 0);
+        for key in &inserted {
+            let hash = calculate_hash(key);
+            let old = *key as u64;
+            let swap = |expected, new| {
+                table.compare_and_swap(
+                    key,
+                    &expected,
+                    &new,
+                    hash,
+                    meta_hash(hash),
+                    &Volatile,
+                    &guard,
+                )
+            };
+            assert!(!swap(old + 1, 0).unwrap());
+            // In DRAM the value is swapped in place, even in a full bucket
+            assert!(swap(old, old + 1).unwrap());
+            let current = old + 1;
+            assert_eq!(
+                table.search(key, hash, meta_hash(hash), &guard),
+                Some(current)
+            );
+            let delete_if = |expected| {
+                table.delete_if(key, hash, meta_hash(hash), &Volatile, &guard, |value| {
+                    *value == expected
+                })
+            };
+            assert!(!delete_if(current + 1).unwrap());
+            assert!(delete_if(current).unwrap());
+            assert_eq!(table.search(key, hash, meta_hash(hash), &guard), None);
+            assert!(matches!(
+                delete_if(old + 1),
+                Err(TableError::ItemDoesntExist)
+            ));
+            assert!(matches!(swap(old + 1, 0), Err(TableError::ItemDoesntExist)));
+        }
+        assert!(table.is_empty());
+        // Every stash lock was released and every overflow indicator cleared
+        for key in &inserted {
+            let hash = calculate_hash(key);
+            assert!(table
+                .insert(key, &0, hash, meta_hash(hash), &Volatile)
+                .is_ok());
+        }
+        assert_eq!(table.len(), inserted.len());
+    }
+
+    #[test]
+    pub fn test_update_releases_the_locks_when_the_closure_panics() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, ValueT>::new(0);
+        let key = 42;
+        let hash = calculate_hash(&key);
+        assert!(table
+            .insert(&key, &b"answer".to_vec(), hash, meta_hash(hash), &Volatile)
+            .is_ok());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            table.update(&key, hash, meta_hash(hash), &Volatile, &guard, |_| {
+                panic!("closure")
+            })
+        }));
+        assert!(result.is_err());
+        // The value was not touched and the buckets were unlocked while unwinding
+        assert_eq!(
+            table.search(&key, hash, meta_hash(hash), &guard),
+            Some(b"answer".to_vec())
+        );
+        let result = table.update(&key, hash, meta_hash(hash), &Volatile, &guard, |value| {
+            value.push(b'!');
+            value.len()
+        });
+        assert_eq!(result.unwrap(), 7);
+        let other = 7;
+        let hash = calculate_hash(&other);
+        assert!(table
+            .insert(&other, &vec![], hash, meta_hash(hash), &Volatile)
+            .is_ok());
+    }
+
+    #[test]
+    pub fn test_search_retries_while_bucket_is_locked() {
+        let collector = Collector::new();
+        let table = Table::<i32, ValueT>::new(0);
+        let key = 42;
+        let hash = calculate_hash(&key);
+        assert!(table
+            .insert(&key, &b"answer".to_vec(), hash, meta_hash(hash), &Volatile)
+            .is_ok());
+        let target = table.bucket(bucket_index(
+            hash,
+            K_FINGER_BITS,
+            Table::<i32, ValueT>::BUCKET_MASK,
+        ));
+        let version = target.read_version().unwrap();
+        target.get_lock();
+        assert!(target.read_version().is_none());
+        std::thread::scope(|scope| {
+            let reader =
+                scope.spawn(|| table.search(&key, hash, meta_hash(hash), &collector.pin()));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            // The reader cannot finish while the writer holds the lock
+            assert!(!reader.is_finished());
+            target.release_lock();
+            assert_eq!(reader.join().unwrap(), Some(b"answer".to_vec()));
+        });
+        assert!(target.version_changed(version));
+    }
+
+    #[test]
+    pub fn test_split_and_merge() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, ValueT>::new(0);
+        let mut inserted = Vec::new();
+        for i in 0..400 {
+            let key = i;
+            let hash = calculate_hash(&key);
+            if table
+                .insert(
+                    &key,
+                    &i.to_le_bytes().to_vec(),
+                    hash,
+                    meta_hash(hash),
+                    &Volatile,
+                )
+                .is_ok()
+            {
+                inserted.push(i);
+            }
+        }
+        table.acquire_locks();
+        let mut new_table = Table::new(0);
+        table
+            .split(&mut new_table, &DashBuildHasher, &Volatile)
+            .unwrap();
+        // Nothing leaves the table before the split is committed
+        assert_eq!(table.len(), inserted.len());
+        assert_eq!(
+            (table.state(), new_table.state()),
+            (TableState::Splitting, TableState::NewTable)
+        );
+        table.complete_split(&new_table, &DashBuildHasher, &Volatile, &guard);
+        new_table.release_first_lock();
+        assert_eq!(
+            (table.state(), new_table.state()),
+            (TableState::Normal, TableState::Normal)
+        );
+        assert_eq!(table.len() + new_table.len(), inserted.len());
+        assert_eq!((table.local_depth(), table.pattern()), (1, 0));
+        assert_eq!((new_table.local_depth(), new_table.pattern()), (1, 1));
+
+        new_table.acquire_locks();
+        let mut merged_table = Table::new(0);
+        table
+            .merge(&new_table, &mut merged_table, &DashBuildHasher, &Volatile)
+            .unwrap();
+        assert_eq!((merged_table.local_depth(), merged_table.pattern()), (0, 0));
+        assert_eq!(merged_table.len(), inserted.len());
+        for i in inserted {
+            let key = i;
+            let hash = calculate_hash(&key);
+            assert_eq!(
+                merged_table.search(&key, hash, meta_hash(hash), &guard),
+                Some(i.to_le_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_recover_after_crash() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, ValueT>::new(0);
+        let mut inserted = Vec::new();
+        for i in 13000..14500 {
+            let key = i;
+            let hash = calculate_hash(&key);
+            if table
+                .insert(&key, &Vec::new(), hash, meta_hash(hash), &Volatile)
+                .is_ok()
+            {
+                inserted.push(i);
+            }
+        }
+        // The crash interrupted a split before its directory was persisted, with locked buckets
+        // and lost overflow metadata
+        table.acquire_locks();
+        let mut new_table = Table::new(0);
+        table
+            .split(&mut new_table, &DashBuildHasher, &Volatile)
+            .unwrap();
+        table.release_locks();
+        table.bucket(3).get_lock();
+        table.bucket(K_NUM_BUCKET).get_lock();
+        for i in 0..K_NUM_BUCKET {
+            unsafe { table.bucket_mut(i) }.reset_overflow_fp();
+        }
+        assert!(!table.recovered(1));
+        // The split is rolled back
+        assert_eq!(table.recover(1, 0, 0, &DashBuildHasher, &Volatile), 0);
+        assert_eq!(table.len(), inserted.len());
+        assert!(table.recovered(1));
+        assert_eq!(
+            (table.local_depth(), table.state()),
+            (0, TableState::Normal)
+        );
+        assert!(table.buckets().all(|bucket| !bucket.is_lock()));
+        // Deleting a stash entry needs the rebuilt overflow indicators
+        let (deleted, kept) = inserted.split_at(inserted.len() / 2);
+        for i in deleted {
+            let key = *i;
+            let hash = calculate_hash(&key);
+            assert!(table
+                .delete(&key, hash, meta_hash(hash), &Volatile, &guard)
+                .is_ok());
+        }
+        // The directory of a later split was persisted, the split is rolled forward
+        let moved = kept
+            .iter()
+            .filter(|i| segment_pattern(calculate_hash(*i), 1) != 1)
+            .count();
+        assert_eq!(table.recover(2, 1, 1, &DashBuildHasher, &Volatile), moved);
+        assert_eq!(table.recover(2, 1, 1, &DashBuildHasher, &Volatile), 0);
+        for i in kept {
+            let key = *i;
+            let hash = calculate_hash(&key);
+            let owned = segment_pattern(hash, 1) == 1;
+            assert_eq!(
+                table.search(&key, hash, meta_hash(hash), &guard).is_some(),
+                owned
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_recover_drops_the_stash_copy_of_a_relocated_pair() {
+        let collector = Collector::new();
+        let guard = collector.pin();
+        let table = Table::<i32, u64>::new(0);
+        let inserted: Vec<i32> = (13000..13100).collect();
+        for key in &inserted {
+            let hash = calculate_hash(key);
+            assert!(table
+                .insert(key, &0, hash, meta_hash(hash), &Durable)
+                .is_ok());
         }
+        // The crash interrupted a relocation once the copy was persisted in the stash
+        let key = inserted[0];
+        let hash = calculate_hash(&key);
+        assert!(unsafe { table.bucket_mut(K_NUM_BUCKET) }
+            .insert(key, 1, meta_hash(hash), false)
+            .is_ok());
+        assert_eq!(table.count_entries(), inserted.len() + 1);
+        assert_eq!(table.recover(1, 0, 0, &DashBuildHasher, &Durable), 1);
+        assert_eq!(table.count_entries(), inserted.len());
+        assert_eq!(table.search(&key, hash, meta_hash(hash), &guard), Some(0));
+        assert!(table
+            .delete(&key, hash, meta_hash(hash), &Durable, &guard)
+            .is_ok());
+        assert_eq!(table.search(&key, hash, meta_hash(hash), &guard), None);
     }
 }
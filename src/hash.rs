@@ -1,7 +1,97 @@
+use crate::extendable_hashing::table::TableError;
+use crate::extendable_hashing::DEFAULT_CAPACITY;
+use crate::pm::PmError;
+use thiserror::Error;
+
 pub type ValueT = Vec<u8>;
-pub trait Hash<T> {
-    fn new();
-    fn insert(&mut self, key: T, value: [u8]);
-    fn delete(key: T);
-    fn get(key: T, buff: &mut [u8]);
+
+/**
+Configuration shared by every map implementation.
+`capacity` is the initial number of directory entries (segments), rounded up to a power of two.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapConfig {
+    pub capacity: usize,
+}
+impl Default for MapConfig {
+    fn default() -> Self {
+        MapConfig {
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+impl MapConfig {
+    pub fn new(capacity: usize) -> Self {
+        MapConfig { capacity }
+    }
+    pub fn validate(&self) -> Result<(), MapError> {
+        if self.capacity == 0 {
+            return Err(MapError::InvalidConfig(
+                "capacity must be greater than 0".to_string(),
+            ));
+        }
+        if self.capacity.checked_next_power_of_two().is_none() {
+            return Err(MapError::InvalidConfig(format!(
+                "capacity {} cannot be rounded up to a power of two",
+                self.capacity
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MapError {
+    #[error("Duplicate key insertion")]
+    KeyExists,
+    #[error("Item does not exist")]
+    KeyNotFound,
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+    #[error("Persistent memory error: {0}")]
+    Pm(#[from] PmError),
+}
+impl From<TableError> for MapError {
+    fn from(err: TableError) -> Self {
+        match err {
+            TableError::KeyExists => MapError::KeyExists,
+            TableError::ItemDoesntExist => MapError::KeyNotFound,
+            err => MapError::Internal(err.to_string()),
+        }
+    }
+}
+
+/**
+The public interface of the hash indexes in this crate.
+Apart from the constructor every method is object safe, so callers can hold a `Box<dyn ConcurrentMap<K, V>>`
+and swap the implementation behind it.
+Every operation but `clear` takes `&self`, so a map can be shared between threads through an `Arc`.
+*/
+pub trait ConcurrentMap<K, V> {
+    fn with_config(config: MapConfig) -> Result<Self, MapError>
+    where
+        Self: Sized;
+    /**
+    Inserts a new key, fails with `MapError::KeyExists` if the key is already present.
+    */
+    fn insert(&self, key: K, value: V) -> Result<(), MapError>;
+    fn get(&self, key: &K) -> Option<V>;
+    /**
+    Removes the key, fails with `MapError::KeyNotFound` if the key is not present.
+    */
+    fn remove(&self, key: &K) -> Result<(), MapError>;
+    fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /**
+    Removes every key. Fails when the storage of the map cannot be reset, e.g. when its pool cannot be
+    formatted again or has no room left for the initial directory, and the map has to be dropped afterwards.
+    */
+    fn clear(&mut self) -> Result<(), MapError>;
 }
@@ -0,0 +1,26 @@
+//! A Rust port of Dash, the extendible hashing scheme for (persistent) memory.
+//!
+//! ```
+//! use r_dash::{ConcurrentMap, ExtendableHashing};
+//!
+//! let map = ExtendableHashing::<u64>::new();
+//! map.insert(7, b"seven".to_vec()).unwrap();
+//! assert_eq!(map.get(&7), Some(b"seven".to_vec()));
+//! ```
+#![cfg_attr(feature = "portable_simd", feature(portable_simd))]
+pub mod extendable_hashing;
+pub mod hash;
+pub mod pm;
+pub mod utils;
+
+pub use extendable_hashing::bucket::{Bucket, BucketError, K_NUM_PAIR_PER_BUCKET};
+pub use extendable_hashing::table::{SplitError, Table, TableError};
+pub use extendable_hashing::{
+    ExtendableHashing, K_FINGER_BITS, K_MASK, K_NUM_BUCKET, K_STASH_BUCKET,
+};
+pub use hash::{ConcurrentMap, MapConfig, MapError, ValueT};
+pub use pm::{
+    layout_tag, ClassUsage, LayoutTag, Persist, PmError, PmPool, Pod, SlabUsage, Volatile,
+};
+pub use utils::hashing::{DashBuildHasher, DashHasher};
+pub use utils::pair::{Key, Pair, INLINE_KEY_SIZE};
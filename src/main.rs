@@ -1,25 +1,16 @@
-#![feature(let_chains)]
-#![feature(integer_atomics)]
-extern crate core;
-
-mod extendable_hashing;
-mod hash;
-mod utils;
+use r_dash::{ConcurrentMap, ExtendableHashing};
+use std::time::Instant;
 
 fn main() {
-    // println!("Hello, world!");
-    // let mut bucket: Bucket<String> = Bucket::new();
-    // println!("hey {:?}", bucket.find_empty_slot());
-    // let mut a = String::from("hello");
-    // bucket.delete(a.clone(), 100, true);
-    // bucket.delete_with_key_pointer(a.as_mut_ptr(), 200, true);
-    // unsafe { println!("{:#?}", a.as_ptr()); }
-    // let address: &i64 = &0x600003730000;
-    // println!("{}", *address);
-    // let mut string = String::from("some_value");
-    // let value: ValueT = unsafe { string.as_bytes_mut() };
-    // let pair: Pair<f32> = Pair::new(77.0, value);
-    // println!("Pair is {:?}", pair);
+    let map = ExtendableHashing::<u64>::new();
+    let start = Instant::now();
+    for i in 0..100_000u64 {
+        map.insert(i, i.to_le_bytes().to_vec())
+            .expect("Unable to insert the key");
+    }
+    println!("Inserted {} keys in {:?}", map.len(), start.elapsed());
 
-    // println!("Successfully insertions are {} {:?}", success, ans);
+    let start = Instant::now();
+    let found = (0..100_000u64).filter(|i| map.contains_key(i)).count();
+    println!("Found {} keys in {:?}", found, start.elapsed());
 }
@@ -0,0 +1,95 @@
+//! Crash simulation for the tests of the persistent structures.
+//!
+//! A simulated pool works on an anonymous copy of its file, and only the cache lines covered by a flush
+//! are written to the file. The `CrashSimulator` records every flush and fence, and when the event chosen
+//! as crash point is reached it unwinds out of the running operation with a `Crash` payload.
+//! Everything not flushed before that point is lost with the anonymous memory, exactly like the caches
+//! of a machine losing power, and the file can be reopened to check what survived.
+use std::panic::resume_unwind;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PmEvent {
+    Flush { offset: u64, len: usize },
+    Fence,
+}
+
+/**
+The payload of the unwinding started at the crash point.
+*/
+#[derive(Debug)]
+pub(crate) struct Crash;
+
+pub(crate) struct CrashSimulator {
+    events: Mutex<Vec<PmEvent>>,
+    crash_at: AtomicUsize,
+}
+impl CrashSimulator {
+    pub fn new() -> Self {
+        CrashSimulator {
+            events: Mutex::new(Vec::new()),
+            crash_at: AtomicUsize::new(usize::MAX),
+        }
+    }
+    /**
+    Crashes instead of applying the event at index `index`, counting every event recorded so far.
+    */
+    pub fn crash_at(&self, index: usize) {
+        self.crash_at.store(index, Relaxed);
+    }
+    pub fn events(&self) -> Vec<PmEvent> {
+        self.events.lock().unwrap().clone()
+    }
+    pub fn crashed(&self) -> bool {
+        self.events.lock().unwrap().len() > self.crash_at.load(Relaxed)
+    }
+    /**
+    Records an event, returning whether it has to be applied.
+    Unwinds at the crash point, the events of other threads after it are dropped.
+    */
+    pub fn record(&self, event: PmEvent) -> bool {
+        let mut events = self.events.lock().unwrap();
+        let crash_at = self.crash_at.load(Relaxed);
+        if events.len() > crash_at {
+            return false;
+        }
+        if events.len() == crash_at {
+            // Never applied, but counted so the other threads see the crash
+            events.push(event);
+            drop(events);
+            // `resume_unwind` skips the panic hook, a crash is not worth a message
+            resume_unwind(Box::new(Crash));
+        }
+        events.push(event);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn test_crash_point() {
+        let simulator = CrashSimulator::new();
+        assert!(simulator.record(PmEvent::Fence));
+        simulator.crash_at(2);
+        assert!(simulator.record(PmEvent::Flush { offset: 0, len: 8 }));
+        assert!(!simulator.crashed());
+        let result = catch_unwind(AssertUnwindSafe(|| simulator.record(PmEvent::Fence)));
+        assert!(result.unwrap_err().downcast::<Crash>().is_ok());
+        assert!(simulator.crashed());
+        assert!(!simulator.record(PmEvent::Fence));
+        assert_eq!(
+            simulator.events(),
+            vec![
+                PmEvent::Fence,
+                PmEvent::Flush { offset: 0, len: 8 },
+                PmEvent::Fence
+            ]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Emulated persistent memory.
+//!
+//! Dash is designed for byte-addressable persistent memory, where a store becomes durable once its cache line
+//! is written back (`clwb`) and ordered by a fence (`sfence`). On regular servers the pool is a memory-mapped file
+//! instead, `PmPool::flush` writes the pages back with `msync` and `PmPool::fence` orders the stores.
+#[cfg(test)]
+pub(crate) mod crash;
+pub mod pool;
+pub mod slab;
+
+pub use pool::{PmError, PmPool};
+pub use slab::{ClassUsage, Slab, SlabUsage};
+
+/**
+Makes stores durable, implemented by `PmPool` and by `Volatile` for tables living in DRAM.
+The tables call it after every store that has to survive a crash, so they are written once for both.
+*/
+pub trait Persist {
+    /**
+    Writes the cache lines covering `[ptr, ptr + len)` back to the medium.
+    */
+    fn flush(&self, ptr: *const u8, len: usize);
+    /**
+    Orders the preceding flushes before the following stores.
+    */
+    fn fence(&self);
+    fn persist(&self, ptr: *const u8, len: usize) {
+        self.flush(ptr, len);
+        self.fence();
+    }
+    /**
+    Returns whether the stores have to survive a crash. Values can only be replaced in place when they don't,
+    a crash could tear them otherwise.
+    */
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
+/**
+The `Persist` of DRAM, where nothing has to be written back.
+*/
+pub struct Volatile;
+impl Persist for Volatile {
+    fn flush(&self, _ptr: *const u8, _len: usize) {}
+    fn fence(&self) {}
+    fn is_durable(&self) -> bool {
+        false
+    }
+}
+
+/**
+Names a type in the layout of a pool, see `ExtendableHashing::create`. Unlike `std::any::type_name` the tag is chosen by the
+implementation, so it stays the same across compiler releases, and only has to change with the format of the type.
+*/
+pub trait LayoutTag {
+    const TAG: u64;
+}
+/**
+Hashes a name into a `LayoutTag::TAG` with 64-bit FNV-1a, e.g. `layout_tag("my_crate::Lease v1")`.
+*/
+pub const fn layout_tag(name: &str) -> u64 {
+    let bytes = name.as_bytes();
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u64).wrapping_mul(0x100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+/**
+Types that can be stored in a pool by copying their bytes, and read back after a restart.
+# Safety
+The type must not contain pointers, references or anything else that is only valid in the process that wrote it,
+and every bit pattern written by a value of the type must be a valid value.
+*/
+pub unsafe trait Pod: Copy + LayoutTag + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(
+            impl LayoutTag for $t {
+                const TAG: u64 = layout_tag(stringify!($t));
+            }
+            unsafe impl Pod for $t {}
+        )*
+    };
+}
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+impl<T: Pod, const N: usize> LayoutTag for [T; N] {
+    const TAG: u64 = (T::TAG ^ N as u64).wrapping_mul(0x100_0000_01b3);
+}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
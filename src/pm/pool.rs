@@ -0,0 +1,375 @@
+#[cfg(test)]
+use crate::pm::crash::{CrashSimulator, PmEvent};
+use crate::pm::Persist;
+use memmap2::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, SeqCst};
+use std::sync::atomic::{fence, AtomicU64};
+#[cfg(test)]
+use std::sync::Arc;
+use thiserror::Error;
+
+const POOL_MAGIC: u64 = 0x6461_7368_706f_6f6c; // "dashpool"
+
+// Every allocation starts on its own cache line, like the segments of Dash
+pub const CACHE_LINE_SIZE: u64 = 64;
+
+#[derive(Debug, Error)]
+pub enum PmError {
+    #[error("Unable to map the pool file: {0}")]
+    Io(#[from] io::Error),
+    #[error("The pool has no space left for {0} bytes")]
+    PoolFull(usize),
+    #[error("Invalid pool: {0}")]
+    InvalidPool(String),
+}
+
+/**
+The header at the start of every pool, the only location known without reading the pool.
+`layout` identifies the format of the stored data, so a pool is never opened with a different one.
+*/
+#[repr(C)]
+struct PoolHeader {
+    magic: u64,
+    layout: u64,
+    size: u64,
+    next: AtomicU64, // Offset of the first free byte
+    root: AtomicU64, // Offset of the root object, 0 until one is set
+    clean: AtomicU64,
+    crash_version: AtomicU64,
+}
+
+/**
+A persistent memory pool backed by a memory-mapped file.
+Objects are addressed by their offset from the start of the pool, which stays valid across restarts,
+unlike the address the file is mapped at.
+*/
+pub struct PmPool {
+    map: MmapMut,
+    base: *mut u8,
+    size: usize,
+    #[cfg_attr(not(test), allow(dead_code))]
+    file: File,
+    // Set for the pools of the crash tests, which only write the flushed lines to the file
+    #[cfg(test)]
+    simulator: Option<Arc<CrashSimulator>>,
+}
+// SAFETY: The mapping lives as long as the pool, concurrent writers synchronize through the structures stored in it
+unsafe impl Send for PmPool {}
+unsafe impl Sync for PmPool {}
+
+impl PmPool {
+    /**
+    Creates (or truncates) the pool file with `size` bytes and initializes its header.
+    */
+    pub fn create(path: impl AsRef<Path>, size: usize, layout: u64) -> Result<Self, PmError> {
+        let file = Self::create_file(path, size)?;
+        let pool = Self::map(file)?;
+        pool.init(size, layout);
+        Ok(pool)
+    }
+    /**
+    Creates a pool working on an anonymous copy of its file, where only flushed cache lines reach the file.
+    Dropping it after `simulator` crashed leaves the file as a machine losing power would.
+    */
+    #[cfg(test)]
+    pub(crate) fn create_simulated(
+        path: impl AsRef<Path>,
+        size: usize,
+        layout: u64,
+        simulator: Arc<CrashSimulator>,
+    ) -> Result<Self, PmError> {
+        let file = Self::create_file(path, size)?;
+        let mut map = MmapMut::map_anon(size)?;
+        let pool = PmPool {
+            base: map.as_mut_ptr(),
+            map,
+            size,
+            file,
+            simulator: Some(simulator),
+        };
+        pool.init(size, layout);
+        Ok(pool)
+    }
+    fn create_file(path: impl AsRef<Path>, size: usize) -> Result<File, PmError> {
+        if (size as u64) < Self::header_size() {
+            return Err(PmError::InvalidPool(format!(
+                "the pool needs at least {} bytes",
+                Self::header_size()
+            )));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(size as u64)?;
+        Ok(file)
+    }
+    fn init(&self, size: usize, layout: u64) {
+        let header = self.header_mut();
+        header.magic = POOL_MAGIC;
+        header.layout = layout;
+        header.size = size as u64;
+        header.next.store(Self::header_size(), Relaxed);
+        header.root.store(0, Relaxed);
+        header.clean.store(0, Relaxed);
+        header.crash_version.store(0, Relaxed);
+        self.persist(self.base, size_of::<PoolHeader>());
+    }
+    /**
+    Maps an existing pool file, failing if it was not created by `create` with the same layout.
+    */
+    pub fn open(path: impl AsRef<Path>, layout: u64) -> Result<Self, PmError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() < Self::header_size() {
+            return Err(PmError::InvalidPool("the file is too small".to_string()));
+        }
+        let pool = Self::map(file)?;
+        let header = pool.header();
+        if header.magic != POOL_MAGIC {
+            return Err(PmError::InvalidPool("bad magic number".to_string()));
+        }
+        if header.layout != layout {
+            return Err(PmError::InvalidPool(format!(
+                "the pool was written with layout {:#x}, expected {:#x}",
+                header.layout, layout
+            )));
+        }
+        if header.size != pool.size as u64 {
+            return Err(PmError::InvalidPool("the file size changed".to_string()));
+        }
+        Ok(pool)
+    }
+    fn map(file: File) -> Result<Self, PmError> {
+        // SAFETY: The file is owned by the pool, other processes must not modify it while it is mapped
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let base = map.as_mut_ptr();
+        let size = map.len();
+        Ok(PmPool {
+            map,
+            base,
+            size,
+            file,
+            #[cfg(test)]
+            simulator: None,
+        })
+    }
+    /**
+    Returns the offset of the first allocation of a pool.
+    */
+    pub(crate) fn header_size() -> u64 {
+        (size_of::<PoolHeader>() as u64).next_multiple_of(CACHE_LINE_SIZE)
+    }
+    fn header(&self) -> &PoolHeader {
+        // SAFETY: The pool is at least as large as the header, and the mapping is page aligned
+        unsafe { &*(self.base as *const PoolHeader) }
+    }
+    #[allow(clippy::mut_from_ref)]
+    fn header_mut(&self) -> &mut PoolHeader {
+        // SAFETY: Only used by `create` and `reset`, before the pool is shared
+        unsafe { &mut *(self.base as *mut PoolHeader) }
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    /**
+    Allocates `size` bytes aligned to a cache line and returns their offset.
+    The bump pointer is persisted, so the allocation survives a restart even if it is never published.
+    */
+    pub fn allocate(&self, size: usize) -> Result<u64, PmError> {
+        let size_aligned = (size as u64).next_multiple_of(CACHE_LINE_SIZE);
+        let header = self.header();
+        let offset = header
+            .next
+            .fetch_update(SeqCst, SeqCst, |next| {
+                next.checked_add(size_aligned)
+                    .filter(|end| *end <= self.size as u64)
+            })
+            .map_err(|_| PmError::PoolFull(size))?;
+        self.persist(
+            &header.next as *const AtomicU64 as *const u8,
+            size_of::<u64>(),
+        );
+        Ok(offset)
+    }
+    /**
+    Returns the number of bytes handed out by `allocate`, including the header.
+    */
+    pub fn used(&self) -> u64 {
+        self.header().next.load(Acquire)
+    }
+    /**
+    Gives the bytes from `next` on back to `allocate`, for the allocations a crash left unreferenced.
+    */
+    pub fn rewind(&self, next: u64) {
+        debug_assert!(next >= Self::header_size() && next <= self.used());
+        let header = self.header();
+        header.next.store(next, SeqCst);
+        self.persist(
+            &header.next as *const AtomicU64 as *const u8,
+            size_of::<u64>(),
+        );
+    }
+    /**
+    Forgets every allocation and the root object, used to clear the structure stored in the pool.
+    */
+    pub fn reset(&mut self) {
+        let header_size = Self::header_size();
+        let header = self.header_mut();
+        header.next.store(header_size, Relaxed);
+        header.root.store(0, Relaxed);
+        self.persist(self.base, size_of::<PoolHeader>());
+    }
+    /**
+    Returns the address of the object stored at `offset`.
+    # Safety
+    `offset` must be returned by `allocate` for an object of type `T`.
+    */
+    pub unsafe fn at<T>(&self, offset: u64) -> *mut T {
+        debug_assert!(offset + size_of::<T>() as u64 <= self.size as u64);
+        self.base.add(offset as usize) as *mut T
+    }
+    /**
+    Returns the offset of an object stored in the pool, the inverse of `at`.
+    */
+    pub fn offset_of<T>(&self, ptr: *const T) -> u64 {
+        let offset = ptr as usize - self.base as usize;
+        debug_assert!(offset + size_of::<T>() <= self.size);
+        offset as u64
+    }
+    pub fn root(&self) -> u64 {
+        self.header().root.load(Acquire)
+    }
+    /**
+    Durably replaces the root object with a single 8-byte store, the object must be persisted before.
+    */
+    pub fn set_root(&self, offset: u64) {
+        let root = &self.header().root;
+        root.store(offset, SeqCst);
+        self.persist(root as *const AtomicU64 as *const u8, size_of::<u64>());
+    }
+    pub fn clean(&self) -> bool {
+        self.header().clean.load(Acquire) != 0
+    }
+    pub fn set_clean(&self, clean: bool) {
+        let flag = &self.header().clean;
+        flag.store(clean as u64, SeqCst);
+        self.persist(flag as *const AtomicU64 as *const u8, size_of::<u64>());
+    }
+    pub fn crash_version(&self) -> u64 {
+        self.header().crash_version.load(Acquire)
+    }
+    pub fn set_crash_version(&self, crash_version: u64) {
+        let version = &self.header().crash_version;
+        version.store(crash_version, SeqCst);
+        self.persist(version as *const AtomicU64 as *const u8, size_of::<u64>());
+    }
+    /**
+    Writes the cache lines covering `[ptr, ptr + len)` back to the file, the `clwb` of the emulation.
+    */
+    pub fn flush(&self, ptr: *const u8, len: usize) {
+        let offset = ptr as usize - self.base as usize;
+        debug_assert!(offset + len <= self.size);
+        #[cfg(test)]
+        if let Some(simulator) = &self.simulator {
+            self.flush_simulated(simulator, offset, len);
+            return;
+        }
+        // `msync` works on whole pages, `flush_range` aligns the range down to the page boundary
+        self.map
+            .flush_range(offset, len)
+            .expect("Unable to write the pool back to its file");
+    }
+    /**
+    Orders the preceding stores and flushes before the following ones, the `sfence` of the emulation.
+    */
+    pub fn fence(&self) {
+        #[cfg(test)]
+        if let Some(simulator) = &self.simulator {
+            simulator.record(PmEvent::Fence);
+        }
+        fence(SeqCst);
+    }
+    #[cfg(test)]
+    fn flush_simulated(&self, simulator: &CrashSimulator, offset: usize, len: usize) {
+        use std::os::unix::fs::FileExt;
+        let line = CACHE_LINE_SIZE as usize;
+        let start = offset / line * line;
+        let end = (offset + len).next_multiple_of(line).min(self.size);
+        let event = PmEvent::Flush {
+            offset: offset as u64,
+            len,
+        };
+        if simulator.record(event) {
+            // SAFETY: The range is inside the mapping
+            let lines = unsafe { std::slice::from_raw_parts(self.base.add(start), end - start) };
+            self.file
+                .write_all_at(lines, start as u64)
+                .expect("Unable to write the pool back to its file");
+        }
+    }
+    pub fn persist(&self, ptr: *const u8, len: usize) {
+        self.flush(ptr, len);
+        self.fence();
+    }
+}
+impl Persist for PmPool {
+    fn flush(&self, ptr: *const u8, len: usize) {
+        PmPool::flush(self, ptr, len)
+    }
+    fn fence(&self) {
+        PmPool::fence(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pool");
+        let offset = {
+            let pool = PmPool::create(&path, 1 << 16, 7).unwrap();
+            let offset = pool.allocate(size_of::<u64>()).unwrap();
+            assert_eq!(offset % CACHE_LINE_SIZE, 0);
+            unsafe {
+                *pool.at::<u64>(offset) = 42;
+                pool.persist(pool.at::<u64>(offset) as *const u8, size_of::<u64>());
+            }
+            pool.set_root(offset);
+            offset
+        };
+        let pool = PmPool::open(&path, 7).unwrap();
+        assert_eq!(pool.root(), offset);
+        assert_eq!(unsafe { *pool.at::<u64>(offset) }, 42);
+        // Allocations of the previous run are kept
+        assert_ne!(pool.allocate(8).unwrap(), offset);
+    }
+
+    #[test]
+    fn test_open_with_other_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pool");
+        PmPool::create(&path, 1 << 12, 1).unwrap();
+        assert!(matches!(
+            PmPool::open(&path, 2),
+            Err(PmError::InvalidPool(_))
+        ));
+    }
+
+    #[test]
+    fn test_pool_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = PmPool::create(dir.path().join("pool"), 1 << 12, 0).unwrap();
+        while pool.allocate(1024).is_ok() {}
+        assert!(matches!(pool.allocate(1024), Err(PmError::PoolFull(1024))));
+        assert!(pool.used() <= pool.size() as u64);
+    }
+}
@@ -0,0 +1,686 @@
+//! A slab allocator for the segments, directories and other blocks of a map, on the heap or in a pool.
+//!
+//! The slab takes chunks from its region and splits each chunk into blocks of a single size, so a segment
+//! split or merge reuses a freed block instead of going through the global or the bump allocator.
+//! A chunk in a pool keeps a persistent allocation bitmap, the blocks allocated before a restart
+//! are known again when the pool is reopened.
+//!
+//! Allocations only become durable once published. `reserve` marks a block used in DRAM only, so a crash
+//! frees every block reserved but not published yet. `publish` writes the blocks becoming allocated, the blocks
+//! becoming free and the new root of the pool to a redo log, then applies it, and `Slab::open` replays a log
+//! the crash interrupted. The bitmaps and the root always change together: after a crash a block is allocated
+//! exactly when the published structure references it, nothing leaks and nothing is freed twice.
+//! A published free only becomes reusable once `release`d, after the operations that could still read it.
+use crate::pm::pool::CACHE_LINE_SIZE;
+use crate::pm::{Persist, PmError, PmPool};
+use std::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ptr::{addr_of, NonNull};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const SLAB_MAGIC: u64 = 0x6461_7368_736c_6162; // "dashslab"
+const BITMAP_WORDS: usize = 8;
+// The bitmap of a chunk fills one cache line
+const MAX_BLOCKS: usize = BITMAP_WORDS * u64::BITS as usize;
+// The chunks of a size class double until they hold this many bytes of blocks
+const MAX_CHUNK_SIZE: usize = 256 << 10;
+// The blocks allocated and freed by one publish, a merge needs five
+pub const LOG_CAPACITY: usize = 8;
+// Set in the log entries of the blocks becoming free, the offsets are aligned to a cache line
+const FREE_FLAG: u64 = 1;
+
+/**
+The persistent state of a slab in a pool, the first allocation of the pool.
+*/
+#[repr(C)]
+struct SlabHeader {
+    magic: u64,
+    chunks: AtomicU64,  // Offset of the last chunk taken from the pool, 0 for none
+    log_len: AtomicU64, // Persisted after the entries it validates, cleared once they are applied
+    log_root: u64,      // The root set by the logged publish, 0 to keep the current one
+    log: [u64; LOG_CAPACITY], // Block offsets, with `FREE_FLAG` for the blocks becoming free
+}
+/**
+The start of every chunk, followed by its blocks.
+*/
+#[repr(C, align(64))]
+struct ChunkHeader {
+    next: u64, // Offset of the chunk taken before this one, 0 for none
+    block_size: u64,
+    blocks: u64,
+    bitmap: [AtomicU64; BITMAP_WORDS], // Allocated blocks, only maintained in a pool
+}
+const CHUNK_HEADER_SIZE: usize = size_of::<ChunkHeader>();
+
+/**
+The DRAM state of a chunk.
+*/
+struct Chunk {
+    header: *mut ChunkHeader,
+    block_size: usize,
+    blocks: usize,
+    used: [u64; BITMAP_WORDS], // Allocated, reserved, or freed and not released yet
+}
+impl Chunk {
+    fn block(&self, index: usize) -> *mut u8 {
+        // SAFETY: The blocks follow the header inside the chunk
+        unsafe { (self.header as *mut u8).add(CHUNK_HEADER_SIZE + index * self.block_size) }
+    }
+    fn index_of(&self, block: *const u8) -> usize {
+        let offset = block as usize - self.header as usize - CHUNK_HEADER_SIZE;
+        debug_assert_eq!(offset % self.block_size, 0);
+        offset / self.block_size
+    }
+    fn size(&self) -> usize {
+        CHUNK_HEADER_SIZE + self.blocks * self.block_size
+    }
+    fn in_use(&self) -> usize {
+        self.used
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+    fn take_free(&mut self) -> Option<*mut u8> {
+        let index = (0..self.blocks).find(|i| self.used[i / 64] & (1 << (i % 64)) == 0)?;
+        self.used[index / 64] |= 1 << (index % 64);
+        Some(self.block(index))
+    }
+}
+struct SlabState {
+    chunks: Vec<Chunk>,
+    by_address: BTreeMap<usize, usize>, // Chunk header address to index in `chunks`
+    classes: BTreeMap<usize, Vec<usize>>, // Block size to the indexes of its chunks
+}
+impl SlabState {
+    fn new() -> Self {
+        SlabState {
+            chunks: Vec::new(),
+            by_address: BTreeMap::new(),
+            classes: BTreeMap::new(),
+        }
+    }
+    fn add(&mut self, chunk: Chunk) {
+        let index = self.chunks.len();
+        self.by_address.insert(chunk.header as usize, index);
+        self.classes
+            .entry(chunk.block_size)
+            .or_default()
+            .push(index);
+        self.chunks.push(chunk);
+    }
+    /**
+    Returns the chunk of a block and the index of the block in it.
+    */
+    fn locate(&mut self, block: *const u8) -> (&mut Chunk, usize) {
+        let (_, index) = self
+            .by_address
+            .range(..=block as usize)
+            .next_back()
+            .expect("the block was not allocated by this slab");
+        let chunk = &mut self.chunks[*index];
+        let block_index = chunk.index_of(block);
+        assert!(block_index < chunk.blocks);
+        (chunk, block_index)
+    }
+}
+
+/**
+Where a slab takes its chunks from.
+*/
+enum Region {
+    /**
+    The global allocator, the heap grows as long as the process has memory.
+    */
+    Heap,
+    Pool(PmPool),
+}
+
+/**
+The usage of the blocks of one size.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassUsage {
+    pub block_size: usize,
+    pub chunks: usize,
+    pub blocks: usize,
+    pub in_use: usize, // Allocated, or reserved and not released
+}
+/**
+The usage of a slab and of its region.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlabUsage {
+    pub capacity: Option<usize>, // The size of the pool, the heap has none
+    pub region_used: usize,      // Bytes taken from the region, with the headers
+    pub classes: Vec<ClassUsage>,
+}
+impl SlabUsage {
+    pub fn blocks_in_use(&self) -> usize {
+        self.classes.iter().map(|class| class.in_use).sum()
+    }
+    /**
+    Returns the bytes of the blocks in use.
+    */
+    pub fn used_bytes(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| class.in_use * class.block_size)
+            .sum()
+    }
+    /**
+    Returns the bytes of the free blocks, available without taking more from the region.
+    */
+    pub fn free_bytes(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|class| (class.blocks - class.in_use) * class.block_size)
+            .sum()
+    }
+}
+
+pub struct Slab {
+    region: Region,
+    state: Mutex<SlabState>,
+}
+// SAFETY: The chunks are owned by the slab, their DRAM state is only accessed under the mutex
+unsafe impl Send for Slab {}
+unsafe impl Sync for Slab {}
+
+impl Slab {
+    pub fn heap() -> Self {
+        Slab {
+            region: Region::Heap,
+            state: Mutex::new(SlabState::new()),
+        }
+    }
+    /**
+    Creates a slab in a new pool, its header has to be the first allocation of the pool.
+    */
+    pub fn create(pool: PmPool) -> Result<Self, PmError> {
+        Self::format(&pool)?;
+        Ok(Slab {
+            region: Region::Pool(pool),
+            state: Mutex::new(SlabState::new()),
+        })
+    }
+    fn format(pool: &PmPool) -> Result<(), PmError> {
+        let offset = pool.allocate(size_of::<SlabHeader>())?;
+        if offset != PmPool::header_size() {
+            return Err(PmError::InvalidPool(
+                "the slab has to be the first allocation of the pool".to_string(),
+            ));
+        }
+        // SAFETY: The allocation is large enough for the header and not referenced by anything yet
+        unsafe {
+            pool.at::<SlabHeader>(offset).write(SlabHeader {
+                magic: SLAB_MAGIC,
+                chunks: AtomicU64::new(0),
+                log_len: AtomicU64::new(0),
+                log_root: 0,
+                log: [0; LOG_CAPACITY],
+            });
+        }
+        pool.persist(
+            // SAFETY: Written just above
+            unsafe { pool.at::<SlabHeader>(offset) as *const u8 },
+            size_of::<SlabHeader>(),
+        );
+        Ok(())
+    }
+    /**
+    Reopens the slab of a pool written by `create`. A publish interrupted by a crash is completed, and
+    a chunk the crash left unlinked is given back to the pool. Opening reads the headers of the chunks,
+    not their blocks.
+    */
+    pub fn open(pool: PmPool) -> Result<Self, PmError> {
+        let header_offset = PmPool::header_size();
+        if pool.used() < header_offset + size_of::<SlabHeader>() as u64 {
+            return Err(PmError::InvalidPool("the pool has no slab".to_string()));
+        }
+        // SAFETY: The pool is large enough for the header, checked above
+        let header = unsafe { &*pool.at::<SlabHeader>(header_offset) };
+        if header.magic != SLAB_MAGIC {
+            return Err(PmError::InvalidPool("bad slab magic number".to_string()));
+        }
+        let mut state = SlabState::new();
+        let mut end = header_offset + size_of::<SlabHeader>() as u64;
+        let mut offset = header.chunks.load(Acquire);
+        while offset != 0 {
+            if offset % CACHE_LINE_SIZE != 0 || offset + CHUNK_HEADER_SIZE as u64 > pool.used() {
+                return Err(PmError::InvalidPool(format!(
+                    "bad chunk offset {:#x}",
+                    offset
+                )));
+            }
+            // SAFETY: The offset is inside the allocated part of the pool
+            let chunk_header = unsafe { pool.at::<ChunkHeader>(offset) };
+            let (next, block_size, blocks) = unsafe {
+                let chunk_header = &*chunk_header;
+                (
+                    chunk_header.next,
+                    chunk_header.block_size as usize,
+                    chunk_header.blocks as usize,
+                )
+            };
+            let chunk = Chunk {
+                header: chunk_header,
+                block_size,
+                blocks,
+                used: std::array::from_fn(|i| unsafe { (*chunk_header).bitmap[i].load(Acquire) }),
+            };
+            if block_size == 0
+                || block_size % CACHE_LINE_SIZE as usize != 0
+                || blocks == 0
+                || blocks > MAX_BLOCKS
+                || offset + chunk.size() as u64 > pool.used()
+            {
+                return Err(PmError::InvalidPool(format!("bad chunk at {:#x}", offset)));
+            }
+            end = end.max(offset + chunk.size() as u64);
+            state.add(chunk);
+            offset = next;
+        }
+        // Taken from the pool, but the crash came before the chunk was linked
+        if pool.used() > end {
+            pool.rewind(end);
+        }
+        let slab = Slab {
+            region: Region::Pool(pool),
+            state: Mutex::new(state),
+        };
+        if let Region::Pool(pool) = &slab.region {
+            if header.log_len.load(Acquire) != 0 {
+                let mut state = slab.lock();
+                slab.apply(&mut state, pool, true);
+                header.log_len.store(0, Release);
+                pool.persist(addr_of!(header.log_len) as *const u8, size_of::<u64>());
+            }
+        }
+        Ok(slab)
+    }
+    pub fn pool(&self) -> Option<&PmPool> {
+        match &self.region {
+            Region::Heap => None,
+            Region::Pool(pool) => Some(pool),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, SlabState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn header(pool: &PmPool) -> &SlabHeader {
+        // SAFETY: Checked by `create` and `open`
+        unsafe { &*pool.at::<SlabHeader>(PmPool::header_size()) }
+    }
+    /**
+    Reserves a block of at least `size` bytes, aligned to a cache line. The block is lost on a crash
+    until it is published, and has to be released if it never is.
+    */
+    pub fn reserve(&self, size: usize) -> Result<NonNull<u8>, PmError> {
+        let block_size = size.max(1).next_multiple_of(CACHE_LINE_SIZE as usize);
+        let mut state = self.lock();
+        let state = &mut *state;
+        let class = state
+            .classes
+            .get(&block_size)
+            .map_or(&[][..], Vec::as_slice);
+        for index in class {
+            if let Some(block) = state.chunks[*index].take_free() {
+                // SAFETY: The blocks of a chunk are never null
+                return Ok(unsafe { NonNull::new_unchecked(block) });
+            }
+        }
+        // The chunks of a class double, so a few large blocks do not take a whole chunk
+        let capacity: usize = class.iter().map(|index| state.chunks[*index].blocks).sum();
+        let blocks = capacity.clamp(1, (MAX_CHUNK_SIZE / block_size).clamp(1, MAX_BLOCKS));
+        let mut chunk = self.grow(block_size, blocks)?;
+        let block = chunk.take_free().unwrap();
+        state.add(chunk);
+        // SAFETY: The blocks of a chunk are never null
+        Ok(unsafe { NonNull::new_unchecked(block) })
+    }
+    /**
+    Takes a new chunk from the region. In a pool the chunk is persisted before it is linked,
+    `open` gives the chunk back to the pool if the crash came in between.
+    */
+    fn grow(&self, block_size: usize, blocks: usize) -> Result<Chunk, PmError> {
+        let size = CHUNK_HEADER_SIZE + blocks * block_size;
+        let header = ChunkHeader {
+            next: 0,
+            block_size: block_size as u64,
+            blocks: blocks as u64,
+            bitmap: Default::default(),
+        };
+        let chunk_header = match &self.region {
+            Region::Heap => {
+                let layout = Layout::from_size_align(size, CACHE_LINE_SIZE as usize).unwrap();
+                // SAFETY: The layout has a non-zero size
+                let chunk_header = unsafe { alloc(layout) } as *mut ChunkHeader;
+                if chunk_header.is_null() {
+                    handle_alloc_error(layout);
+                }
+                // SAFETY: The allocation is large enough and aligned for the header
+                unsafe { chunk_header.write(header) };
+                chunk_header
+            }
+            Region::Pool(pool) => {
+                let slab_header = Self::header(pool);
+                let offset = pool.allocate(size)?;
+                // SAFETY: The allocation is large enough and aligned for the header
+                unsafe {
+                    let chunk_header = pool.at::<ChunkHeader>(offset);
+                    chunk_header.write(ChunkHeader {
+                        next: slab_header.chunks.load(Relaxed),
+                        ..header
+                    });
+                    pool.persist(chunk_header as *const u8, CHUNK_HEADER_SIZE);
+                    slab_header.chunks.store(offset, Release);
+                    pool.persist(addr_of!(slab_header.chunks) as *const u8, size_of::<u64>());
+                    chunk_header
+                }
+            }
+        };
+        Ok(Chunk {
+            header: chunk_header,
+            block_size,
+            blocks,
+            used: [0; BITMAP_WORDS],
+        })
+    }
+    /**
+    Durably allocates the reserved blocks of `allocated`, frees the blocks of `freed` and sets the root
+    of the pool, in one failure-atomic step. The freed blocks are only reused once they are released.
+    Nothing has to be made durable on the heap.
+    # Safety
+    The blocks have to come from this slab, `allocated` reserved and never published, `freed` allocated.
+    # Panics
+    If the publish holds more than `LOG_CAPACITY` blocks.
+    */
+    pub unsafe fn publish(
+        &self,
+        allocated: &[*const u8],
+        freed: &[*const u8],
+        root: Option<*const u8>,
+    ) {
+        let Region::Pool(pool) = &self.region else {
+            return;
+        };
+        assert!(allocated.len() + freed.len() <= LOG_CAPACITY);
+        let mut state = self.lock();
+        let header = Self::header(pool) as *const SlabHeader as *mut SlabHeader;
+        // The log is only written under the lock, and read by `open` before the slab is shared
+        let log = &mut (*header).log;
+        for (entry, block) in log.iter_mut().zip(allocated) {
+            *entry = pool.offset_of(*block);
+        }
+        for (entry, block) in log.iter_mut().skip(allocated.len()).zip(freed) {
+            *entry = pool.offset_of(*block) | FREE_FLAG;
+        }
+        (*header).log_root = root.map_or(0, |root| pool.offset_of(root));
+        pool.persist(
+            addr_of!((*header).log_root) as *const u8,
+            size_of::<u64>() * (1 + LOG_CAPACITY),
+        );
+        // The log is valid from here, a crash is completed by `open`
+        let log_len = &(*header).log_len;
+        log_len.store((allocated.len() + freed.len()) as u64, Release);
+        pool.persist(log_len as *const AtomicU64 as *const u8, size_of::<u64>());
+        self.apply(&mut state, pool, false);
+        log_len.store(0, Release);
+        pool.persist(log_len as *const AtomicU64 as *const u8, size_of::<u64>());
+    }
+    /**
+    Applies the logged publish to the persistent bitmaps and the root, which is idempotent.
+    When `replay`ing, the blocks of the log also change in DRAM, which was rebuilt from the bitmaps.
+    */
+    fn apply(&self, state: &mut SlabState, pool: &PmPool, replay: bool) {
+        let header = Self::header(pool);
+        let len = header.log_len.load(Acquire) as usize;
+        for entry in &header.log[..len] {
+            // SAFETY: The logged offsets are blocks of the slab
+            let block = unsafe { pool.at::<u8>(entry & !FREE_FLAG) };
+            let (chunk, index) = state.locate(block);
+            let (word, bit) = (index / 64, 1u64 << (index % 64));
+            // SAFETY: The chunk header is in the pool
+            let bitmap = unsafe { &(*chunk.header).bitmap[word] };
+            if entry & FREE_FLAG == 0 {
+                debug_assert!(
+                    replay || chunk.used[word] & bit != 0,
+                    "the block was not reserved"
+                );
+                bitmap.fetch_or(bit, SeqCst);
+                if replay {
+                    chunk.used[word] |= bit;
+                }
+            } else {
+                bitmap.fetch_and(!bit, SeqCst);
+                if replay {
+                    chunk.used[word] &= !bit;
+                }
+            }
+            pool.flush(bitmap as *const AtomicU64 as *const u8, size_of::<u64>());
+        }
+        pool.fence();
+        if header.log_root != 0 {
+            pool.set_root(header.log_root);
+        }
+    }
+    /**
+    Makes a block available again.
+    # Safety
+    The block has to come from this slab, and be either reserved and never published, or freed by a publish.
+    Nothing may access it anymore.
+    */
+    pub unsafe fn release(&self, block: *const u8) {
+        let mut state = self.lock();
+        let (chunk, index) = state.locate(block);
+        let bit = 1 << (index % 64);
+        debug_assert!(chunk.used[index / 64] & bit != 0, "the block is free");
+        debug_assert!(
+            matches!(self.region, Region::Heap)
+                || (*chunk.header).bitmap[index / 64].load(Relaxed) & bit == 0,
+            "the block is still allocated"
+        );
+        chunk.used[index / 64] &= !bit;
+    }
+    /**
+    Returns whether the block is allocated in its persistent bitmap.
+    */
+    #[cfg(test)]
+    pub(crate) fn is_allocated(&self, block: *const u8) -> bool {
+        let mut state = self.lock();
+        let (chunk, index) = state.locate(block);
+        // SAFETY: The chunk header is in the region
+        unsafe { (*chunk.header).bitmap[index / 64].load(Relaxed) & (1 << (index % 64)) != 0 }
+    }
+    pub fn usage(&self) -> SlabUsage {
+        let state = self.lock();
+        let classes = state
+            .classes
+            .iter()
+            .map(|(block_size, indexes)| {
+                let chunks = indexes.iter().map(|index| &state.chunks[*index]);
+                ClassUsage {
+                    block_size: *block_size,
+                    chunks: indexes.len(),
+                    blocks: chunks.clone().map(|chunk| chunk.blocks).sum(),
+                    in_use: chunks.map(Chunk::in_use).sum(),
+                }
+            })
+            .collect();
+        let (capacity, region_used) = match &self.region {
+            Region::Heap => (None, state.chunks.iter().map(Chunk::size).sum()),
+            Region::Pool(pool) => (Some(pool.size()), pool.used() as usize),
+        };
+        SlabUsage {
+            capacity,
+            region_used,
+            classes,
+        }
+    }
+    /**
+    Frees every block. The chunks of the heap are kept for the next allocations,
+    a pool is reset and gets a new, empty slab.
+    */
+    pub fn reset(&mut self) -> Result<(), PmError> {
+        let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+        match &mut self.region {
+            Region::Heap => {
+                for chunk in &mut state.chunks {
+                    chunk.used = [0; BITMAP_WORDS];
+                }
+            }
+            Region::Pool(pool) => {
+                pool.reset();
+                *state = SlabState::new();
+                Self::format(pool)?;
+            }
+        }
+        Ok(())
+    }
+}
+impl Persist for Slab {
+    fn flush(&self, ptr: *const u8, len: usize) {
+        if let Region::Pool(pool) = &self.region {
+            pool.flush(ptr, len);
+        }
+    }
+    fn fence(&self) {
+        if let Region::Pool(pool) = &self.region {
+            pool.fence();
+        }
+    }
+    fn is_durable(&self) -> bool {
+        matches!(self.region, Region::Pool(_))
+    }
+}
+impl Drop for Slab {
+    fn drop(&mut self) {
+        if let Region::Heap = self.region {
+            let state = self.state.get_mut().unwrap_or_else(PoisonError::into_inner);
+            for chunk in &state.chunks {
+                let layout =
+                    Layout::from_size_align(chunk.size(), CACHE_LINE_SIZE as usize).unwrap();
+                // SAFETY: The chunk was allocated by `grow` with this layout
+                unsafe { dealloc(chunk.header as *mut u8, layout) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(dir: &tempfile::TempDir, size: usize) -> PmPool {
+        PmPool::create(dir.path().join("pool"), size, 0).unwrap()
+    }
+
+    #[test]
+    fn test_heap_reuses_released_blocks() {
+        let slab = Slab::heap();
+        let blocks: Vec<_> = (0..100).map(|_| slab.reserve(1000).unwrap()).collect();
+        assert!(blocks
+            .iter()
+            .all(|block| (block.as_ptr() as usize).is_multiple_of(64)));
+        let usage = slab.usage();
+        assert_eq!(usage.classes.len(), 1);
+        assert_eq!(usage.classes[0].block_size, 1024);
+        assert_eq!(usage.blocks_in_use(), 100);
+        unsafe { slab.release(blocks[42].as_ptr()) };
+        assert_eq!(slab.reserve(1000).unwrap(), blocks[42]);
+        assert_eq!(slab.usage().capacity, None);
+    }
+
+    #[test]
+    fn test_unpublished_blocks_are_free_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let (published, root) = {
+            let slab = Slab::create(pool(&dir, 1 << 20)).unwrap();
+            let published = slab.reserve(256).unwrap().as_ptr();
+            let root = slab.reserve(64).unwrap().as_ptr();
+            let _lost = slab.reserve(256).unwrap();
+            unsafe { slab.publish(&[published, root], &[], Some(root)) };
+            let pool = slab.pool().unwrap();
+            (pool.offset_of(published), pool.offset_of(root))
+        };
+        let slab = Slab::open(PmPool::open(dir.path().join("pool"), 0).unwrap()).unwrap();
+        let pool = slab.pool().unwrap();
+        assert_eq!(pool.root(), root);
+        assert_eq!(slab.usage().blocks_in_use(), 2);
+        assert!(slab.is_allocated(unsafe { pool.at::<u8>(published) }));
+        // The lost block is the first free one
+        let block = slab.reserve(256).unwrap().as_ptr();
+        assert_ne!(pool.offset_of(block), published);
+        assert!(!slab.is_allocated(block));
+    }
+
+    #[test]
+    fn test_interrupted_publish_is_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old, new) = {
+            let slab = Slab::create(pool(&dir, 1 << 20)).unwrap();
+            let old = slab.reserve(64).unwrap().as_ptr();
+            unsafe { slab.publish(&[old], &[], Some(old)) };
+            let new = slab.reserve(64).unwrap().as_ptr();
+            let pool = slab.pool().unwrap();
+            // A crash right after the log was made valid
+            let header = Slab::header(pool) as *const SlabHeader as *mut SlabHeader;
+            unsafe {
+                (*header).log[0] = pool.offset_of(new);
+                (*header).log[1] = pool.offset_of(old) | FREE_FLAG;
+                (*header).log_root = pool.offset_of(new);
+                (*header).log_len.store(2, SeqCst);
+            }
+            (pool.offset_of(old), pool.offset_of(new))
+        };
+        let slab = Slab::open(PmPool::open(dir.path().join("pool"), 0).unwrap()).unwrap();
+        let pool = slab.pool().unwrap();
+        assert_eq!(pool.root(), new);
+        assert!(slab.is_allocated(unsafe { pool.at::<u8>(new) }));
+        assert!(!slab.is_allocated(unsafe { pool.at::<u8>(old) }));
+        assert_eq!(slab.usage().blocks_in_use(), 1);
+        assert_eq!(Slab::header(pool).log_len.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn test_unlinked_chunk_is_given_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let used = {
+            let slab = Slab::create(pool(&dir, 1 << 20)).unwrap();
+            slab.reserve(64).unwrap();
+            let pool = slab.pool().unwrap();
+            let used = pool.used();
+            // A crash after the chunk was taken from the pool, before it was linked
+            pool.allocate(4096).unwrap();
+            used
+        };
+        let slab = Slab::open(PmPool::open(dir.path().join("pool"), 0).unwrap()).unwrap();
+        assert_eq!(slab.pool().unwrap().used(), used);
+    }
+
+    #[test]
+    fn test_chunks_grow_and_report_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        let slab = Slab::create(pool(&dir, 4 << 20)).unwrap();
+        for _ in 0..40 {
+            slab.reserve(16 << 10).unwrap();
+        }
+        let usage = slab.usage();
+        assert_eq!(usage.capacity, Some(4 << 20));
+        let class = usage.classes[0];
+        // 1, 1, 2, 4, 8 and 16 blocks, then the chunks stop growing at 256 KiB
+        assert_eq!((class.block_size, class.in_use), (16 << 10, 40));
+        assert_eq!(class.blocks, 48);
+        assert_eq!(class.chunks, 7);
+        assert_eq!(usage.free_bytes(), 8 * (16 << 10));
+        assert!(usage.region_used >= usage.used_bytes() + usage.free_bytes());
+        // Running out of pool space is reported
+        assert!(matches!(
+            (0..1000).try_for_each(|_| slab.reserve(16 << 10).map(drop)),
+            Err(PmError::PoolFull(_))
+        ));
+    }
+}
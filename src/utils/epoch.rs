@@ -0,0 +1,358 @@
+//! Epoch-based memory reclamation.
+//!
+//! Every operation that dereferences shared objects pins the `Collector` first, which records the current
+//! global epoch in the participant of the calling thread. Objects unlinked from the shared structure are
+//! retired with the epoch they were retired in, and only freed once the global epoch moved two steps past it.
+//! The global epoch only advances when every pinned participant observed the current one, so no pinned
+//! operation can still reference an object freed this way.
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, PoisonError};
+
+// The local epoch of a participant is `(epoch << 1) | PINNED` while it is pinned, and 0 otherwise
+const PINNED: usize = 1;
+// Unpinning tries to advance the epoch and free garbage once this many objects are waiting
+const COLLECT_THRESHOLD: usize = 16;
+
+static NEXT_COLLECTOR_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static LOCALS: RefCell<Vec<Local>> = const { RefCell::new(Vec::new()) };
+}
+
+/**
+The epoch state a thread publishes for one collector.
+*/
+#[derive(Default)]
+struct Participant {
+    epoch: AtomicUsize,
+    in_use: AtomicBool,   // Claimed by a live thread
+    orphaned: AtomicBool, // The collector was dropped
+}
+/**
+The participant of the current thread for one collector, with the nesting depth of its pins.
+*/
+struct Local {
+    collector_id: usize,
+    participant: Arc<Participant>,
+    pins: usize,
+}
+impl Drop for Local {
+    fn drop(&mut self) {
+        // The thread is exiting, the participant can be claimed by another thread
+        self.participant.epoch.store(0, SeqCst);
+        self.participant.in_use.store(false, SeqCst);
+    }
+}
+/**
+A type erased object waiting for the global epoch to move past its retire epoch.
+*/
+struct Deferred {
+    ptr: *mut (),
+    drop: unsafe fn(*mut ()),
+    epoch: usize,
+}
+// SAFETY: `Guard::retire` requires the retired objects to be safe to drop from any thread
+unsafe impl Send for Deferred {}
+
+unsafe fn drop_box<T>(ptr: *mut ()) {
+    drop(Box::from_raw(ptr as *mut T));
+}
+
+pub struct Collector {
+    id: usize,
+    epoch: AtomicUsize,
+    participants: Mutex<Vec<Arc<Participant>>>,
+    garbage: Mutex<Vec<Deferred>>,
+    pending: AtomicUsize, // Number of objects in `garbage`, read without taking the lock
+}
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Collector {
+    pub fn new() -> Self {
+        Collector {
+            id: NEXT_COLLECTOR_ID.fetch_add(1, Relaxed),
+            epoch: AtomicUsize::new(0),
+            participants: Mutex::new(vec![]),
+            garbage: Mutex::new(vec![]),
+            pending: AtomicUsize::new(0),
+        }
+    }
+    /**
+    Pins the current thread, objects retired from now on are not freed before the guard is dropped.
+    Pins can be nested, the thread is unpinned when its outermost guard is dropped.
+    */
+    pub fn pin(&self) -> Guard<'_> {
+        LOCALS.with(|locals| {
+            let mut locals = locals.borrow_mut();
+            let index = match locals.iter().position(|l| l.collector_id == self.id) {
+                Some(index) => index,
+                None => {
+                    // Entries of dropped collectors are only cleaned up here
+                    locals.retain(|l| !l.participant.orphaned.load(Relaxed));
+                    locals.push(Local {
+                        collector_id: self.id,
+                        participant: self.register(),
+                        pins: 0,
+                    });
+                    locals.len() - 1
+                }
+            };
+            let local = &mut locals[index];
+            if local.pins == 0 {
+                let epoch = self.epoch.load(Relaxed);
+                local
+                    .participant
+                    .epoch
+                    .store((epoch << 1) | PINNED, Relaxed);
+                // The local epoch has to be visible before any shared object is loaded
+                fence(SeqCst);
+            }
+            local.pins += 1;
+        });
+        Guard {
+            collector: self,
+            _not_send: PhantomData,
+        }
+    }
+    /**
+    Claims a participant left by an exited thread, or registers a new one.
+    */
+    fn register(&self) -> Arc<Participant> {
+        let mut participants = self
+            .participants
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for participant in participants.iter() {
+            if participant
+                .in_use
+                .compare_exchange(false, true, SeqCst, Relaxed)
+                .is_ok()
+            {
+                return Arc::clone(participant);
+            }
+        }
+        let participant = Arc::new(Participant::default());
+        participant.in_use.store(true, SeqCst);
+        participants.push(Arc::clone(&participant));
+        participant
+    }
+    fn unpin(&self) {
+        let unpinned = LOCALS.with(|locals| {
+            let mut locals = locals.borrow_mut();
+            let local = locals
+                .iter_mut()
+                .find(|l| l.collector_id == self.id)
+                .expect("A guard is always dropped on the thread that pinned it");
+            local.pins -= 1;
+            if local.pins == 0 {
+                local.participant.epoch.store(0, SeqCst);
+                return true;
+            }
+            false
+        });
+        if unpinned && self.pending.load(Relaxed) >= COLLECT_THRESHOLD {
+            self.collect();
+        }
+    }
+    /**
+    Advances the global epoch if every pinned participant observed the current one.
+    */
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(SeqCst);
+        fence(SeqCst);
+        let Ok(participants) = self.participants.try_lock() else {
+            return epoch;
+        };
+        for participant in participants.iter() {
+            let local = participant.epoch.load(SeqCst);
+            if local & PINNED != 0 && local >> 1 != epoch {
+                return epoch;
+            }
+        }
+        drop(participants);
+        match self
+            .epoch
+            .compare_exchange(epoch, epoch + 1, SeqCst, SeqCst)
+        {
+            Ok(_) => epoch + 1,
+            Err(current) => current,
+        }
+    }
+    /**
+    Frees the retired objects whose retire epoch the global epoch moved two steps past.
+    */
+    pub fn collect(&self) {
+        let epoch = self.try_advance();
+        let Ok(mut garbage) = self.garbage.try_lock() else {
+            return;
+        };
+        let (ready, waiting): (Vec<Deferred>, Vec<Deferred>) =
+            garbage.drain(..).partition(|d| d.epoch + 2 <= epoch);
+        *garbage = waiting;
+        self.pending.store(garbage.len(), Relaxed);
+        drop(garbage);
+        for deferred in ready {
+            // SAFETY: No pinned participant can still reference the object, see the module documentation
+            unsafe { (deferred.drop)(deferred.ptr) };
+        }
+    }
+    /**
+    Returns the number of retired objects that are not freed yet.
+    */
+    pub fn pending(&self) -> usize {
+        self.pending.load(Relaxed)
+    }
+}
+impl Drop for Collector {
+    fn drop(&mut self) {
+        // `&mut self` guarantees no guard is alive, everything retired can be freed
+        let garbage = self
+            .garbage
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for deferred in garbage.drain(..) {
+            // SAFETY: The objects were retired through `Guard::retire` and nothing can reference them anymore
+            unsafe { (deferred.drop)(deferred.ptr) };
+        }
+        let participants = self
+            .participants
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        for participant in participants.iter() {
+            participant.orphaned.store(true, Relaxed);
+        }
+    }
+}
+
+/**
+Keeps the current thread pinned, see `Collector::pin`. A guard cannot be sent to another thread.
+*/
+pub struct Guard<'a> {
+    collector: &'a Collector,
+    _not_send: PhantomData<*const ()>,
+}
+impl Guard<'_> {
+    /**
+    Retires the boxed object, it is dropped once every thread pinned at this point has unpinned.
+    # Safety
+    `ptr` must come from `Box::into_raw`, be retired only once and be unreachable for operations pinning
+    after this call. `T` must be safe to drop from any thread.
+    */
+    pub unsafe fn retire<T>(&self, ptr: *mut T) {
+        let collector = self.collector;
+        let mut garbage = collector
+            .garbage
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        garbage.push(Deferred {
+            ptr: ptr as *mut (),
+            drop: drop_box::<T>,
+            epoch: collector.epoch.load(SeqCst),
+        });
+        collector.pending.store(garbage.len(), Relaxed);
+    }
+}
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.collector.unpin();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc;
+    use std::thread;
+
+    struct CountDrop<'a>(&'a AtomicUsize);
+    impl Drop for CountDrop<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_nested_pins() {
+        let collector = Collector::new();
+        let outer = collector.pin();
+        let inner = collector.pin();
+        drop(inner);
+        let participants = collector.participants.lock().unwrap();
+        assert_eq!(participants.len(), 1);
+        assert_ne!(participants[0].epoch.load(SeqCst) & PINNED, 0);
+        drop(participants);
+        drop(outer);
+        assert_eq!(
+            collector.participants.lock().unwrap()[0].epoch.load(SeqCst),
+            0
+        );
+    }
+
+    #[test]
+    fn test_retired_objects_wait_for_pinned_threads() {
+        let dropped = AtomicUsize::new(0);
+        let collector = Collector::new();
+        thread::scope(|scope| {
+            let (pinned_tx, pinned_rx) = mpsc::channel();
+            let (release_tx, release_rx) = mpsc::channel::<()>();
+            let collector = &collector;
+            let reader = scope.spawn(move || {
+                let _guard = collector.pin();
+                pinned_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            });
+            pinned_rx.recv().unwrap();
+            {
+                let guard = collector.pin();
+                unsafe { guard.retire(Box::into_raw(Box::new(CountDrop(&dropped)))) };
+            }
+            for _ in 0..10 {
+                collector.collect();
+            }
+            // The reader pinned before the object was retired, so it may still reference it
+            assert_eq!(dropped.load(SeqCst), 0);
+            assert_eq!(collector.pending(), 1);
+            release_tx.send(()).unwrap();
+            reader.join().unwrap();
+        });
+        for _ in 0..3 {
+            collector.collect();
+        }
+        assert_eq!(dropped.load(SeqCst), 1);
+        assert_eq!(collector.pending(), 0);
+    }
+
+    #[test]
+    fn test_drop_frees_pending_objects() {
+        let dropped = AtomicUsize::new(0);
+        let collector = Collector::new();
+        let guard = collector.pin();
+        for _ in 0..3 {
+            unsafe { guard.retire(Box::into_raw(Box::new(CountDrop(&dropped)))) };
+        }
+        drop(guard);
+        drop(collector);
+        assert_eq!(dropped.load(SeqCst), 3);
+    }
+
+    #[test]
+    fn test_participants_are_reused() {
+        let collector = Collector::new();
+        let participant = collector.register();
+        // Dropping the thread local entry releases the participant, as on thread exit
+        drop(Local {
+            collector_id: collector.id,
+            participant: Arc::clone(&participant),
+            pins: 0,
+        });
+        assert!(Arc::ptr_eq(&collector.register(), &participant));
+        assert_eq!(collector.participants.lock().unwrap().len(), 1);
+    }
+}
@@ -1,6 +1,137 @@
-use std::hash::{DefaultHasher, Hash, Hasher};
+use crate::pm::{layout_tag, LayoutTag};
+use std::hash::{BuildHasher, Hash, Hasher};
+
+// The multiplier of FxHash, an odd constant spreading every input bit over the high bits
+const SEED: u64 = 0x517c_c1b7_2722_0a95;
+// The first digits of pi, so the zero key does not hash to zero
+const INITIAL_STATE: u64 = 0x243f_6a88_85a3_08d3;
+
+/**
+The default hash of the maps, implemented in the crate so its output never changes with the Rust release,
+which is mandatory for the tables persisted in a pool. It is not seeded, so it offers no protection
+against keys chosen to collide.
+
+Each word written is folded in with a multiply and rotate, and `finish` applies the 64-bit finalizer of MurmurHash3,
+so the most significant bits routing the keys to the segments and the fingerprint in the least significant byte
+both depend on the whole key. Integer keys are hashed in a single step.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct DashHasher {
+    hash: u64,
+}
+impl Default for DashHasher {
+    fn default() -> Self {
+        DashHasher {
+            hash: INITIAL_STATE,
+        }
+    }
+}
+impl DashHasher {
+    fn add_word(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+impl Hasher for DashHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add_word(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let remainder = chunks.remainder();
+        if !remainder.is_empty() {
+            // The length in the last byte tells `[1]` from `[1, 0]`
+            let mut word = [0; 8];
+            word[..remainder.len()].copy_from_slice(remainder);
+            word[7] = remainder.len() as u8;
+            self.add_word(u64::from_le_bytes(word));
+        }
+    }
+    fn write_u8(&mut self, i: u8) {
+        self.add_word(i as u64);
+    }
+    fn write_u16(&mut self, i: u16) {
+        self.add_word(i as u64);
+    }
+    fn write_u32(&mut self, i: u32) {
+        self.add_word(i as u64);
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.add_word(i);
+    }
+    fn write_u128(&mut self, i: u128) {
+        self.add_word(i as u64);
+        self.add_word((i >> 64) as u64);
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.add_word(i as u64);
+    }
+    fn finish(&self) -> u64 {
+        let mut hash = self.hash;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ (hash >> 33)
+    }
+}
+/**
+Builds the `DashHasher`, the default `BuildHasher` of `ExtendableHashing`.
+*/
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DashBuildHasher;
+impl BuildHasher for DashBuildHasher {
+    type Hasher = DashHasher;
+    fn build_hasher(&self) -> DashHasher {
+        DashHasher::default()
+    }
+}
+impl LayoutTag for DashBuildHasher {
+    const TAG: u64 = layout_tag("DashHasher");
+}
+/**
+Hashes a value with the default hash of the maps.
+*/
 pub fn calculate_hash<T: Hash>(t: &T) -> usize {
-    let mut hasher = DefaultHasher::new(); // Create a new DefaultHasher
-    t.hash(&mut hasher); // Hash the value
-    hasher.finish() as usize // Get the resulting hash
+    DashBuildHasher.hash_one(t) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_hash_is_stable() {
+        // Pools written by earlier builds are routed with these hashes
+        let mut hasher = DashHasher::default();
+        hasher.write(b"dash");
+        assert_eq!(hasher.finish(), 0x19a8_9ac6_dfa2_7d25);
+        assert_eq!(calculate_hash(&0u64), 0x0f84_4373_0f99_d79a);
+        assert_eq!(calculate_hash(&1u64), 0x4d7c_5f98_406e_c4f0);
+    }
+
+    #[test]
+    pub fn test_hash_spreads_integer_keys() {
+        // Consecutive keys have to reach every segment and every fingerprint
+        let mut patterns = [0; 16];
+        let mut fingerprints = [false; 256];
+        for i in 0..4096u64 {
+            let hash = calculate_hash(&i);
+            patterns[hash >> (usize::BITS - 4)] += 1;
+            fingerprints[hash & 0xff] = true;
+        }
+        assert!(patterns.iter().all(|count| (128..384).contains(count)));
+        assert!(fingerprints.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    pub fn test_write_pads_the_remainder() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = DashHasher::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_ne!(hash(&[1]), hash(&[1, 0]));
+        assert_ne!(hash(&[1; 8]), hash(&[1; 9]));
+        assert_eq!(hash(b"segment"), hash(b"segment"));
+    }
 }
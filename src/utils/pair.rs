@@ -1,39 +1,247 @@
-use crate::hash::ValueT;
-#[derive(Debug, Clone, PartialEq)]
-pub struct Key<T: PartialEq + Clone> {
-    pub key: T,
-    pub is_pointer: bool,
-    pub length: u32,
-    pub pointed_key: Vec<u8>,
-}
-impl<T: PartialEq + Clone> Key<T> {
-    pub fn new(key: &T) -> Self {
-        Key {
-            key: key.clone(),
+use crate::utils::var_compare;
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::ptr::{slice_from_raw_parts_mut, NonNull};
+
+pub const INLINE_KEY_SIZE: usize = 16;
+
+/**
+A variable-length byte-string key, like a string or a UUID blob. Keys of up to `INLINE_KEY_SIZE` bytes are
+stored inline in the bucket slot and compared without leaving it. Longer keys are allocated out of line
+and the slot only keeps their address and length, so the slots of a bucket keep a fixed size.
+
+A key is compared and hashed by its bytes only, it hashes like the `[u8]` it was built from.
+The inserts, the lookups and the splits and merges rehashing the stored keys all agree on the segment
+and the bucket of a key.
+*/
+pub struct Key {
+    is_pointer: bool, // Set for the keys longer than `INLINE_KEY_SIZE`, the variant of `data` in use
+    length: u32,
+    data: KeyData,
+}
+union KeyData {
+    // Zero padded, so equal inline keys have equal arrays
+    inline: [u8; INLINE_KEY_SIZE],
+    pointer: NonNull<u8>,
+}
+impl Key {
+    /**
+    Stores `bytes` inline, or copies them to a new allocation when they do not fit.
+    # Panics
+    If the key is 4 GiB or longer.
+    */
+    pub fn new(bytes: &[u8]) -> Self {
+        match Self::inline(bytes) {
+            Some(key) => key,
+            None => Self::from_boxed(bytes.into()),
+        }
+    }
+    fn inline(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > INLINE_KEY_SIZE {
+            return None;
+        }
+        let mut inline = [0; INLINE_KEY_SIZE];
+        inline[..bytes.len()].copy_from_slice(bytes);
+        Some(Key {
             is_pointer: false,
-            length: 0,
-            pointed_key: vec![],
+            length: bytes.len() as u32,
+            data: KeyData { inline },
+        })
+    }
+    fn from_boxed(bytes: Box<[u8]>) -> Self {
+        if let Some(key) = Self::inline(&bytes) {
+            return key;
+        }
+        let length = u32::try_from(bytes.len()).expect("a key has to be shorter than 4 GiB");
+        Key {
+            is_pointer: true,
+            length,
+            data: KeyData {
+                // SAFETY: `Box::into_raw` never returns null
+                pointer: unsafe { NonNull::new_unchecked(Box::into_raw(bytes) as *mut u8) },
+            },
         }
     }
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: `is_pointer` tells the variant in use, an out-of-line key is valid for `length` bytes
+        unsafe {
+            if self.is_pointer {
+                std::slice::from_raw_parts(self.data.pointer.as_ptr(), self.length as usize)
+            } else {
+                &self.data.inline[..self.length as usize]
+            }
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.length as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    /**
+    Whether the bytes are stored out of line, reading them costs one more cache miss than reading the slot.
+    */
+    pub fn is_pointer(&self) -> bool {
+        self.is_pointer
+    }
 }
-#[derive(Debug)]
-pub struct Pair<T: PartialEq> {
-    pub key: Key<T>,
-    pub value: ValueT,
+impl Drop for Key {
+    fn drop(&mut self) {
+        if self.is_pointer {
+            // SAFETY: The bytes were allocated by `from_boxed` as a boxed slice of `length` bytes
+            drop(unsafe {
+                Box::from_raw(slice_from_raw_parts_mut(
+                    self.data.pointer.as_ptr(),
+                    self.length as usize,
+                ))
+            });
+        }
+    }
 }
-impl<T: Clone + PartialEq> Clone for Pair<T> {
+impl Clone for Key {
     fn clone(&self) -> Self {
-        // Manually handle the data if necessary; for example, create a new buffer
-        let new_buffer = self.value.to_vec(); // or other logic
-        Pair {
-            key: self.key.clone(),
-            value: new_buffer,
+        if self.is_pointer {
+            Self::from_boxed(self.as_bytes().into())
+        } else {
+            Key {
+                is_pointer: false,
+                length: self.length,
+                // SAFETY: The key is inline
+                data: KeyData {
+                    inline: unsafe { self.data.inline },
+                },
+            }
+        }
+    }
+}
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        if !self.is_pointer && !other.is_pointer {
+            // SAFETY: Both keys are inline. The lengths have to be compared, a key may end with zero bytes
+            return self.length == other.length && unsafe { self.data.inline == other.data.inline };
+        }
+        var_compare(self.as_bytes(), self.length, other.as_bytes(), other.length)
+    }
+}
+impl Eq for Key {}
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Has to stay the hash of the byte slice for `Borrow<[u8]>`
+        self.as_bytes().hash(state)
+    }
+}
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match std::str::from_utf8(self.as_bytes()) {
+            Ok(key) => f.debug_tuple("Key").field(&key).finish(),
+            Err(_) => f.debug_tuple("Key").field(&self.as_bytes()).finish(),
         }
     }
 }
+impl Borrow<[u8]> for Key {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+impl From<&[u8]> for Key {
+    fn from(bytes: &[u8]) -> Self {
+        Key::new(bytes)
+    }
+}
+impl From<&str> for Key {
+    fn from(key: &str) -> Self {
+        Key::new(key.as_bytes())
+    }
+}
+impl From<Vec<u8>> for Key {
+    fn from(bytes: Vec<u8>) -> Self {
+        Key::from_boxed(bytes.into_boxed_slice())
+    }
+}
+impl From<String> for Key {
+    fn from(key: String) -> Self {
+        Key::from(key.into_bytes())
+    }
+}
+// SAFETY: A key owns its bytes and never mutates them
+unsafe impl Send for Key {}
+unsafe impl Sync for Key {}
+/**
+The content of a bucket slot.
+*/
+#[derive(Debug, Clone)]
+pub struct Pair<K: PartialEq + Clone, V> {
+    pub key: K,
+    pub value: V,
+}
 
-impl<T: PartialEq> Pair<T> {
-    pub fn new(key: Key<T>, value: ValueT) -> Self {
+impl<K: PartialEq + Clone, V> Pair<K, V> {
+    pub fn new(key: K, value: V) -> Self {
         Pair { key, value }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::hashing::calculate_hash;
+    use std::mem::size_of;
+
+    #[test]
+    pub fn test_key_compares_and_hashes_its_bytes() {
+        let key = Key::from("a1b2c3d4-e5f6-4711-8899-aabbccddeeff");
+        assert_eq!(key, Key::new(key.as_bytes()));
+        assert_ne!(key, Key::from("a1b2c3d4"));
+        assert_ne!(Key::new(&[1, 0]), Key::new(&[1]));
+        assert_ne!(Key::from("ab"), Key::from("ba"));
+        assert_eq!(calculate_hash(&key), calculate_hash(&key.clone()));
+        assert_eq!(calculate_hash(&key), calculate_hash(&key.as_bytes()));
+        assert!(key.is_pointer());
+        assert_eq!(key.len(), 36);
+    }
+
+    #[test]
+    pub fn test_short_keys_are_inline() {
+        let short = Key::from("0123456789abcdef");
+        let long = Key::from("0123456789abcdefg");
+        assert!(!short.is_pointer());
+        assert!(long.is_pointer());
+        assert_ne!(short, long);
+        assert_eq!(long.as_bytes()[..INLINE_KEY_SIZE], *short.as_bytes());
+        // The conversions pick the representation from the length only
+        assert!(!Key::from(b"0123".to_vec()).is_pointer());
+        assert_eq!(Key::from(b"0123".to_vec()), Key::new(b"0123"));
+        assert_eq!(
+            calculate_hash(&Key::from("0123")),
+            calculate_hash(&Key::from(b"0123".to_vec()))
+        );
+        let clone = long.clone();
+        drop(long);
+        assert_eq!(clone.as_bytes(), b"0123456789abcdefg");
+        assert_eq!(size_of::<Key>(), 24);
+    }
+
+    #[test]
+    pub fn test_empty_key() {
+        let key = Key::from(Vec::new());
+        assert!(key.is_empty());
+        assert!(!key.is_pointer());
+        assert_eq!(key.clone(), Key::new(&[]));
+        assert_eq!(format!("{:?}", key), "Key(\"\")");
+    }
+
+    #[test]
+    pub fn test_var_compare() {
+        assert!(var_compare(b"dash", 4, b"dash", 4));
+        assert!(!var_compare(b"dash", 4, b"cash", 4));
+        assert!(!var_compare(b"dash", 4, b"dashes", 6));
+        // Only the first `len` bytes belong to the key
+        assert!(var_compare(b"dash", 2, b"da", 2));
+    }
+}
@@ -0,0 +1,147 @@
+//! Vectorized fingerprint matching, the hot loop of every bucket probe.
+//!
+//! A bucket keeps 18 one-byte fingerprints, 14 for its slots and 4 for the entries it overflowed to the stash.
+//! `match_fingerprints` compares all of them to the fingerprint of a key at once. On x86_64 the AVX2 path is
+//! selected at runtime and SSE2, part of the baseline, is used otherwise. The other targets use `std::simd`
+//! when the nightly-only `portable_simd` feature is enabled, and a scalar loop otherwise.
+pub const K_NUM_FINGERPRINTS: usize = 18;
+const MATCH_MASK: u32 = (1 << K_NUM_FINGERPRINTS) - 1;
+
+/**
+Returns a bitmask with bit `i` set when `fingers[i] == meta_hash`.
+*/
+#[inline]
+pub fn match_fingerprints(fingers: &[u8; K_NUM_FINGERPRINTS], meta_hash: u8) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // The detection is cached by std, it costs an atomic load
+        if std::arch::is_x86_feature_detected!("avx2") {
+            // SAFETY: AVX2 is supported by the running CPU
+            unsafe { x86::match_avx2(fingers, meta_hash) }
+        } else {
+            x86::match_sse2(fingers, meta_hash)
+        }
+    }
+    #[cfg(all(not(target_arch = "x86_64"), feature = "portable_simd"))]
+    {
+        portable::match_fingerprints(fingers, meta_hash)
+    }
+    #[cfg(all(not(target_arch = "x86_64"), not(feature = "portable_simd")))]
+    {
+        match_scalar(fingers, meta_hash)
+    }
+}
+/**
+The reference implementation of `match_fingerprints`.
+*/
+pub fn match_scalar(fingers: &[u8; K_NUM_FINGERPRINTS], meta_hash: u8) -> u32 {
+    fingers
+        .iter()
+        .enumerate()
+        .filter(|(_, &finger)| finger == meta_hash)
+        .fold(0, |mask, (i, _)| mask | (1 << i))
+}
+/**
+Copies the fingerprints to a zeroed 32-byte vector, the padding matches a zero fingerprint and is masked out.
+*/
+#[cfg(any(target_arch = "x86_64", feature = "portable_simd"))]
+#[inline]
+fn padded(fingers: &[u8; K_NUM_FINGERPRINTS]) -> [u8; 32] {
+    let mut padded = [0; 32];
+    padded[..K_NUM_FINGERPRINTS].copy_from_slice(fingers);
+    padded
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::{padded, K_NUM_FINGERPRINTS, MATCH_MASK};
+    use std::arch::x86_64::{
+        __m128i, __m256i, _mm256_cmpeq_epi8, _mm256_loadu_si256, _mm256_movemask_epi8,
+        _mm256_set1_epi8, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8,
+    };
+
+    /**
+    Compares the 18 fingerprints with a single 32-byte comparison.
+    # Safety
+    The CPU must support AVX2.
+    */
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn match_avx2(fingers: &[u8; K_NUM_FINGERPRINTS], meta_hash: u8) -> u32 {
+        let fingers = padded(fingers);
+        // SAFETY: The array is 32 bytes long, `loadu` has no alignment requirement
+        let data = unsafe { _mm256_loadu_si256(fingers.as_ptr() as *const __m256i) };
+        let matched = _mm256_cmpeq_epi8(data, _mm256_set1_epi8(meta_hash as i8));
+        _mm256_movemask_epi8(matched) as u32 & MATCH_MASK
+    }
+    /**
+    Compares the first 16 fingerprints in one SSE2 comparison and the last 2 one by one.
+    */
+    pub fn match_sse2(fingers: &[u8; K_NUM_FINGERPRINTS], meta_hash: u8) -> u32 {
+        // SAFETY: SSE2 is part of the x86_64 baseline, and the load reads the first 16 of the 18 bytes
+        let mut mask = unsafe {
+            let data = _mm_loadu_si128(fingers.as_ptr() as *const __m128i);
+            let matched = _mm_cmpeq_epi8(data, _mm_set1_epi8(meta_hash as i8));
+            _mm_movemask_epi8(matched) as u32
+        };
+        for (i, &finger) in fingers.iter().enumerate().skip(16) {
+            if finger == meta_hash {
+                mask |= 1 << i;
+            }
+        }
+        mask
+    }
+}
+
+#[cfg(feature = "portable_simd")]
+mod portable {
+    use super::{padded, K_NUM_FINGERPRINTS, MATCH_MASK};
+    use std::simd::cmp::SimdPartialEq;
+    use std::simd::Simd;
+
+    #[allow(dead_code)]
+    pub fn match_fingerprints(fingers: &[u8; K_NUM_FINGERPRINTS], meta_hash: u8) -> u32 {
+        let data = Simd::<u8, 32>::from_array(padded(fingers));
+        data.simd_eq(Simd::splat(meta_hash)).to_bitmask() as u32 & MATCH_MASK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprints(seed: u8) -> [u8; K_NUM_FINGERPRINTS] {
+        std::array::from_fn(|i| (i as u8).wrapping_mul(seed) % 7)
+    }
+
+    #[test]
+    pub fn test_match_fingerprints() {
+        let fingers = [0, 3, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 3];
+        assert_eq!(
+            match_fingerprints(&fingers, 3),
+            (1 << 1) | (1 << 4) | (1 << 15) | (1 << 17)
+        );
+        assert_eq!(match_fingerprints(&fingers, 1), 0);
+        // The padding of the vector paths never matches
+        assert_eq!(match_fingerprints(&[0; K_NUM_FINGERPRINTS], 0), MATCH_MASK);
+    }
+
+    #[test]
+    pub fn test_vector_paths_match_scalar() {
+        for seed in 0..=255u8 {
+            let fingers = fingerprints(seed);
+            for meta_hash in 0..8u8 {
+                let expected = match_scalar(&fingers, meta_hash);
+                assert_eq!(match_fingerprints(&fingers, meta_hash), expected);
+                #[cfg(target_arch = "x86_64")]
+                {
+                    assert_eq!(x86::match_sse2(&fingers, meta_hash), expected);
+                    if std::arch::is_x86_feature_detected!("avx2") {
+                        assert_eq!(unsafe { x86::match_avx2(&fingers, meta_hash) }, expected);
+                    }
+                }
+                #[cfg(feature = "portable_simd")]
+                assert_eq!(portable::match_fingerprints(&fingers, meta_hash), expected);
+            }
+        }
+    }
+}